@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rocket::serde::{Serialize, Deserialize};
+use rocket::serde::json;
+
+use crate::cryptography;
+use crate::files;
+use crate::laws;
+use crate::store;
+use crate::utils;
+
+// Portable archive of some Books, a gzip compressed tar with:
+//   manifest.toml                          model, dimension and catalogues of each book, sha256 of every other entry
+//   laws/<pais>.<instrumento>.config.toml  the config of a book, and its text of law
+//   books/<pais>.<instrumento>/...         reference, embedding and text files of every catalogue, as in reference_folder
+const MANIFEST_ENTRY: &str = "manifest.toml";
+const ARCHIVE_VERSION: u16 = 1;
+// Catalogues of an archived Book and the texts of its articles split in parts
+type BookUnit = (Vec<store::StoredCatalogue>, Vec<(laws::LawIndex,String)>);
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchivedBook {
+  pub pais: String,
+  pub instrumento: String,
+  // model id and dimension of the embeddings, the local model must match them
+  pub model: String,
+  pub dims: usize,
+  pub catalogues: usize
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveManifest {
+  pub version: u16,
+  pub created: u64,
+  pub books: Vec<ArchivedBook>,
+  // sha256 of every entry but the manifest, by its path in the archive
+  pub files: BTreeMap<String, String>
+}
+
+fn book_entry(book: &laws::LawBook, filename: &str) -> String {
+  format!("books/{}.{}/{}",book.pais,book.instrumento,filename)
+}
+fn config_entry(book: &laws::LawBook) -> String {
+  format!("laws/{}.{}{}",book.pais,book.instrumento,utils::config_law_config_extension())
+}
+fn text_of_law_entry(book: &laws::LawBook) -> String {
+  format!("laws/{}.{}{}",book.pais,book.instrumento,utils::config_law_extension())
+}
+// Names of books end up in paths, an archive cannot write outside of the folders of the book
+fn valid_name(name: &str) -> bool {
+  !name.is_empty() && !name.contains('/') && !name.contains('\\') && !name.contains("..")
+}
+
+// Entries of a Book, its catalogues read from the store
+fn book_entries(book: &laws::LawBook, entries: &mut BTreeMap<String, Vec<u8>>) -> Result<ArchivedBook, String> {
+  let stored = store::store().get_book(book)?;
+  let (model, dims) = match stored.first() {
+    Some(first) => (first.model.clone(), first.vector.len()),
+    None => return Err(format!("{}.{}: no catalogues to export",book.pais,book.instrumento))
+  };
+  if let Some(other) = stored.iter().find(|x| x.model != model || x.vector.len() != dims) {
+    return Err(format!("{}.{}: <{}> was embedded by {} in {} dimensions, not by {} in {}, ingest it again or see the fsck command",
+      book.pais,book.instrumento,files::law_index_to_filename(&other.dindex),other.model,other.vector.len(),model,dims));
+  }
+  for dindex in store::store().list_texts(book)? {
+    if let Some(text) = store::store().get_text(&dindex)? {
+      entries.insert(book_entry(book, &format!("{}{}",files::law_index_to_filename(&dindex),utils::config_law_extension())), text.into_bytes());
+    }
+  }
+  for catalogue in stored.iter() {
+    let doc = catalogue.to_catalogue();
+    let stem = files::law_index_to_filename(&catalogue.dindex);
+    entries.insert(book_entry(book, &format!("{}{}",stem,utils::config_reference_extension())), files::reference_file_content(&doc).into_bytes());
+    entries.insert(book_entry(book, &format!("{}{}",stem,utils::config_embeddings_extension())), files::embeddings_file_content(&doc).into_bytes());
+    // the text of an article is kept over the one of its single window
+    entries.entry(book_entry(book, &format!("{}{}",stem,utils::config_law_extension()))).or_insert(catalogue.text.clone().into_bytes());
+  }
+  for (entry, filename) in [(config_entry(book), files::law_config_filename(book)), (text_of_law_entry(book), files::book_of_law_filename(book))] {
+    if let Ok(content) = fs::read(&filename) {
+      entries.insert(entry, content);
+    }
+  }
+  Ok(ArchivedBook {
+    pais: book.pais.clone(),
+    instrumento: book.instrumento.clone(),
+    model,
+    dims,
+    catalogues: stored.len()
+  })
+}
+fn append_entry<W: io::Write>(builder: &mut tar::Builder<W>, path: &str, content: &[u8], mtime: u64) -> io::Result<()> {
+  let mut header = tar::Header::new_gnu();
+  header.set_size(content.len() as u64);
+  header.set_mode(0o644);
+  header.set_mtime(mtime);
+  builder.append_data(&mut header, path, content)
+}
+// The archive is written whole or not at all, the manifest goes first
+fn write_archive(archive: &str, manifest: &ArchiveManifest, entries: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+  let content = toml::to_string(manifest).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+  if let Some(folder) = Path::new(archive).parent().filter(|x| !x.as_os_str().is_empty()) {
+    fs::create_dir_all(folder)?;
+  }
+  let file = fs::File::create(files::partial_filename(archive))?;
+  let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+  append_entry(&mut builder, MANIFEST_ENTRY, content.as_bytes(), manifest.created)?;
+  for (path, content) in entries.iter() {
+    append_entry(&mut builder, path, content, manifest.created)?;
+  }
+  let file = builder.into_inner()?.finish()?;
+  file.sync_all()?;
+  files::promote_partial(archive)
+}
+
+// Packs some Books (every Book of the store if none is given) into one archive, returns the catalogues packed
+pub fn export(archive: &str, books: &[laws::LawBook]) -> Result<usize, String> {
+  let books = if books.is_empty() { store::store().books()? } else { books.to_owned() };
+  let mut entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+  let mut archived: Vec<ArchivedBook> = Vec::new();
+  for book in books.iter() {
+    let packed = book_entries(book, &mut entries)?;
+    println!("Packing {}.{}: {} catalogues embedded by {} in {} dimensions",book.pais,book.instrumento,packed.catalogues,packed.model,packed.dims);
+    archived.push(packed);
+  }
+  let manifest = ArchiveManifest {
+    version: ARCHIVE_VERSION,
+    created: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+    books: archived,
+    files: entries.iter().map(|(path, content)| (path.clone(), cryptography::sha256_bytes(content))).collect()
+  };
+  write_archive(archive, &manifest, &entries)
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0019"),archive,x))?;
+  Ok(manifest.books.iter().map(|x| x.catalogues).sum())
+}
+
+fn read_archive(archive: &str) -> io::Result<BTreeMap<String, Vec<u8>>> {
+  let mut reader = tar::Archive::new(GzDecoder::new(fs::File::open(archive)?));
+  let mut entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+  for entry in reader.entries()? {
+    let mut entry = entry?;
+    if !entry.header().entry_type().is_file() {
+      continue;
+    }
+    let path = entry.path()?.to_string_lossy().to_string();
+    let mut content: Vec<u8> = Vec::new();
+    entry.read_to_end(&mut content)?;
+    entries.insert(path, content);
+  }
+  Ok(entries)
+}
+// Every entry is listed in the manifest with its hash and every listed entry is there
+fn verify(manifest: &ArchiveManifest, entries: &BTreeMap<String, Vec<u8>>) -> Result<(), String> {
+  if manifest.version > ARCHIVE_VERSION {
+    return Err(format!("version {} is newer than the supported {}",manifest.version,ARCHIVE_VERSION));
+  }
+  if let Some(book) = manifest.books.iter().find(|x| !valid_name(&x.pais) || !valid_name(&x.instrumento)) {
+    return Err(format!("invalid book name {}.{}",book.pais,book.instrumento));
+  }
+  for (path, digest) in manifest.files.iter() {
+    match entries.get(path) {
+      None => return Err(format!("{} is missing",path)),
+      Some(content) if cryptography::sha256_bytes(content) != *digest => return Err(format!("{} does not match its sha256",path)),
+      Some(_) => ()
+    }
+  }
+  if let Some(path) = entries.keys().find(|x| !manifest.files.contains_key(*x)) {
+    return Err(format!("{} is not in the manifest",path));
+  }
+  Ok(())
+}
+// Model the catalogues of a Book are searched with here, from the language of its archived config
+fn local_model(book: &laws::LawBook, entries: &BTreeMap<String, Vec<u8>>) -> Result<String, String> {
+  let language = entries.get(&config_entry(book))
+    .and_then(|x| String::from_utf8_lossy(x).parse::<toml::Value>().ok())
+    .and_then(|x| x.get("language").and_then(|y| y.as_str()).map(|y| y.to_string()));
+  match language {
+    None => Ok(utils::config_model()),
+    Some(language) if utils::config_languages().contains(&language) => Ok(utils::config_model_for_language(&language)),
+    Some(language) => Err(format!("{}.{}: the language {} is not served here",book.pais,book.instrumento,language))
+  }
+}
+// hidden_size of the config.json of a model, the size of its embeddings
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ModelConfig {
+  hidden_size: usize
+}
+// Dimension of the embeddings of a local model: from its config.json, else from a catalogue it embedded
+fn local_dims(model: &str) -> Result<usize, String> {
+  let config = format!("{}config.json",utils::config_model_path_for(model));
+  if let Some(config) = fs::read_to_string(&config).ok().and_then(|x| json::from_str::<ModelConfig>(&x).ok()) {
+    return Ok(config.hidden_size);
+  }
+  for book in store::store().books()? {
+    let first = store::store().list(&book)?.into_iter().next();
+    if let Some(stored) = first.map(|x| store::store().get(&x)).transpose()?.flatten().filter(|x| x.model == model) {
+      return Ok(stored.vector.len());
+    }
+  }
+  Err(format!("the dimension of {} is unknown, it has no <{}> and embedded no local catalogue",model,config))
+}
+// The archived embeddings are only comparable with the queries of the same model
+fn check_model(archived: &ArchivedBook, book: &laws::LawBook, entries: &BTreeMap<String, Vec<u8>>) -> Result<(), String> {
+  let model = local_model(book, entries)?;
+  if model != archived.model {
+    return Err(format!("{}.{} was embedded by {}, it would be searched with {} here",book.pais,book.instrumento,archived.model,model));
+  }
+  let dims = local_dims(&model)?;
+  if dims != archived.dims {
+    return Err(format!("{}.{} has {} dimensions, the local {} has {}",book.pais,book.instrumento,archived.dims,model,dims));
+  }
+  Ok(())
+}
+// Catalogues and texts of the articles split in parts of an archived Book
+fn book_catalogues(archived: &ArchivedBook, book: &laws::LawBook, entries: &BTreeMap<String, Vec<u8>>) -> Result<BookUnit, String> {
+  let entry_of = |dindex: &laws::LawIndex, extension: String| entries.get(&book_entry(book, &format!("{}{}",files::law_index_to_filename(dindex),extension)))
+    .and_then(|x| String::from_utf8(x.clone()).ok());
+  let prefix = book_entry(book, "");
+  let mut catalogues: Vec<store::StoredCatalogue> = Vec::new();
+  for (path, content) in entries.range(prefix.clone()..).take_while(|x| x.0.starts_with(&prefix)) {
+    if !path.ends_with(&utils::config_reference_extension()) {
+      continue;
+    }
+    let reference = files::ReferenceFile::parse(&String::from_utf8_lossy(content)).map_err(|x| format!("{}: {}",path,x))?;
+    let dindex = reference.law_index();
+    if dindex.book != *book || reference.model != archived.model {
+      return Err(format!("{}: a catalogue of {}.{} embedded by {}",path,dindex.book.pais,dindex.book.instrumento,reference.model));
+    }
+    let vector = entry_of(&dindex, utils::config_embeddings_extension())
+      .and_then(|x| x.lines().map(|y| y.parse::<f32>().ok()).collect::<Option<Vec<f32>>>())
+      .filter(|x| x.len() == archived.dims && x.iter().all(|y| y.is_finite()))
+      .ok_or(format!("{}: missing or invalid embedding",path))?;
+    let text = entry_of(&dindex, utils::config_law_extension()).ok_or(format!("{}: missing text",path))?;
+    catalogues.push(store::StoredCatalogue {
+      dindex,
+      etype: reference.etype.clone(),
+      pooling: reference.pooling.clone(),
+      model: reference.model.clone(),
+      template: reference.template.clone(),
+      metric: reference.metric()?,
+      text,
+      vector
+    });
+  }
+  if catalogues.len() != archived.catalogues {
+    return Err(format!("{}.{}: {} catalogues instead of {}",book.pais,book.instrumento,catalogues.len(),archived.catalogues));
+  }
+  let mut texts: Vec<(laws::LawIndex,String)> = Vec::new();
+  for dindex in catalogues.iter().filter(|x| x.dindex.parte.is_some()).map(|x| laws::LawIndex { parte: None, ..x.dindex.clone() }) {
+    if texts.iter().any(|x| x.0 == dindex) || catalogues.iter().any(|x| x.dindex == dindex) {
+      continue;
+    }
+    if let Some(text) = entry_of(&dindex, utils::config_law_extension()) {
+      texts.push((dindex, text));
+    }
+  }
+  Ok((catalogues, texts))
+}
+
+// Unpacks the Books of an archive into the store, nothing is written unless the whole archive is verified
+// and every Book matches the local model; a Book already stored is only overwritten when replacing
+pub fn import(archive: &str, replace: bool) -> Result<usize, String> {
+  let mut entries = read_archive(archive)
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0020"),archive,x))?;
+  let manifest = entries.remove(MANIFEST_ENTRY)
+    .ok_or(format!("{}: {}: no manifest",utils::error_message("E0020"),archive))?;
+  let manifest = toml::from_str::<ArchiveManifest>(&String::from_utf8_lossy(&manifest))
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0020"),archive,x))?;
+  verify(&manifest, &entries)
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0020"),archive,x))?;
+  let mut units: Vec<(laws::LawBook, BookUnit)> = Vec::new();
+  for archived in manifest.books.iter() {
+    let book = laws::LawBook {
+      pais: archived.pais.clone(),
+      instrumento: archived.instrumento.clone()
+    };
+    check_model(archived, &book, &entries)?;
+    if !replace && !store::store().list(&book)?.is_empty() {
+      return Err(format!("{}.{} is already stored, import it with --replace to overwrite it",book.pais,book.instrumento));
+    }
+    let (catalogues, texts) = book_catalogues(archived, &book, &entries)?;
+    units.push((book, (catalogues, texts)));
+  }
+  let mut count = 0;
+  for (book, (catalogues, texts)) in units {
+    // a replaced Book keeps its catalogues until the archived ones are all written
+    store::store().replace_book(&book, &catalogues, &texts)?;
+    for (entry, filename) in [(config_entry(&book), files::law_config_filename(&book)), (text_of_law_entry(&book), files::book_of_law_filename(&book))] {
+      if let Some(content) = entries.get(&entry) {
+        fs::create_dir_all(files::book_of_law_foldername()).map_err(|x| format!("{}: {}",filename,x))?;
+        files::write_atomic(&filename, content).map_err(|x| format!("{}: {}",filename,x))?;
+      }
+    }
+    println!("Imported {}.{}: {} catalogues and {} article texts",book.pais,book.instrumento,catalogues.len(),texts.len());
+    count += catalogues.len();
+  }
+  Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn archived() -> (ArchiveManifest, BTreeMap<String, Vec<u8>>) {
+    let book = laws::LawBook { pais: "mx".to_string(), instrumento: "cpeum".to_string() };
+    let stem = files::law_index_to_filename(&laws::LawIndex { book: book.clone(), titulo: None, capitulo: None, articulo: Some(1), parte: None });
+    let entries = BTreeMap::from([
+      (book_entry(&book, &format!("{}{}",stem,utils::config_reference_extension())), b"reference".to_vec()),
+      (book_entry(&book, &format!("{}{}",stem,utils::config_embeddings_extension())), b"0.5\n0.25".to_vec()),
+      (book_entry(&book, &format!("{}{}",stem,utils::config_law_extension())), b"articulo 1".to_vec())]);
+    let manifest = ArchiveManifest {
+      version: ARCHIVE_VERSION,
+      created: 0,
+      books: Vec::from([ArchivedBook { pais: book.pais, instrumento: book.instrumento, model: "test".to_string(), dims: 2, catalogues: 1 }]),
+      files: entries.iter().map(|(path, content)| (path.clone(), cryptography::sha256_bytes(content))).collect()
+    };
+    (manifest, entries)
+  }
+
+  #[test]
+  fn a_whole_archive_verifies() {
+    let (manifest, entries) = archived();
+    assert_eq!(verify(&manifest, &entries), Ok(()));
+  }
+
+  #[test]
+  fn changed_missing_or_extra_entries_are_refused() {
+    let (manifest, entries) = archived();
+    let path = entries.keys().next().unwrap().clone();
+    let mut changed = entries.clone();
+    changed.insert(path.clone(), b"another reference".to_vec());
+    assert!(verify(&manifest, &changed).unwrap_err().contains("sha256"));
+    let mut missing = entries.clone();
+    missing.remove(&path);
+    assert!(verify(&manifest, &missing).unwrap_err().contains("missing"));
+    let mut extra = entries.clone();
+    extra.insert("books/mx.cpeum/extra.txt".to_string(), Vec::new());
+    assert!(verify(&manifest, &extra).unwrap_err().contains("not in the manifest"));
+  }
+
+  #[test]
+  fn newer_versions_and_escaping_names_are_refused() {
+    let (mut manifest, entries) = archived();
+    manifest.version = ARCHIVE_VERSION + 1;
+    assert!(verify(&manifest, &entries).is_err());
+    for name in ["..", "../etc", "a/b", ""] {
+      let (mut manifest, entries) = archived();
+      manifest.books[0].instrumento = name.to_string();
+      assert!(verify(&manifest, &entries).unwrap_err().contains("invalid book name"), "{} accepted",name);
+    }
+  }
+}
@@ -0,0 +1,155 @@
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use rocket::tokio::sync::oneshot;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::transformer;
+use crate::utils;
+
+struct BatchRequest {
+  model: String,
+  texts: Vec<String>,
+  queued: Instant,
+  reply: oneshot::Sender<Option<Vec<Vec<f32>>>>
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchStats {
+  pub batches: u64,
+  pub requests: u64,
+  pub texts: u64,
+  pub max_batch_size: u64,
+  pub mean_batch_size: f32,
+  pub mean_latency_ms: f32,
+  pub mean_encode_ms: f32
+}
+
+lazy_static! {
+  static ref BATCH_QUEUE: Mutex<Sender<BatchRequest>> = Mutex::new(
+    spawn_worker(utils::config_batch_max_size(), utils::config_batch_max_wait())
+  );
+}
+static BATCHES: AtomicU64 = AtomicU64::new(0);
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static TEXTS: AtomicU64 = AtomicU64::new(0);
+static MAX_BATCH_SIZE: AtomicU64 = AtomicU64::new(0);
+static LATENCY_MICROS: AtomicU64 = AtomicU64::new(0);
+static ENCODE_MICROS: AtomicU64 = AtomicU64::new(0);
+
+// The worker is a plain thread, encoding never runs on the executor threads of the server
+fn spawn_worker(max_size: usize, max_wait: Duration) -> Sender<BatchRequest> {
+  let (sender, receiver) = channel::<BatchRequest>();
+  thread::Builder::new().name("tsahdu-batching".to_string())
+    .spawn(move || worker(receiver, max_size, max_wait))
+    .expect("unable to spawn the batching worker");
+  sender
+}
+
+// Collects requests until max_size texts are queued or max_wait has passed since the first one,
+// encodes all of them in one call per model and fans the embeddings back out
+fn worker(receiver: Receiver<BatchRequest>, max_size: usize, max_wait: Duration) {
+  while let Ok(first) = receiver.recv() {
+    let deadline = first.queued.max(Instant::now()) + max_wait;
+    let mut size = first.texts.len();
+    let mut batch = Vec::from([first]);
+    while size < max_size {
+      match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(request) => {
+          size += request.texts.len();
+          batch.push(request);
+        }
+        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break
+      }
+    }
+    // one encode call per model present in the batch
+    let mut models = batch.iter().map(|x| x.model.clone()).collect::<Vec<String>>();
+    models.sort();
+    models.dedup();
+    for model in models {
+      let (requests, rest): (Vec<BatchRequest>, Vec<BatchRequest>) = batch.into_iter().partition(|x| x.model == model);
+      batch = rest;
+      encode_batch(&model, requests);
+    }
+  }
+}
+fn encode_batch(model: &str, batch: Vec<BatchRequest>) {
+  let texts = batch.iter().flat_map(|x| x.texts.iter().cloned()).collect::<Vec<String>>();
+  let now = Instant::now();
+  let encds = transformer::model_encode(model, &texts);
+  ENCODE_MICROS.fetch_add(now.elapsed().as_micros() as u64, Ordering::Relaxed);
+  BATCHES.fetch_add(1, Ordering::Relaxed);
+  TEXTS.fetch_add(texts.len() as u64, Ordering::Relaxed);
+  MAX_BATCH_SIZE.fetch_max(texts.len() as u64, Ordering::Relaxed);
+  let mut offset : usize = 0;
+  for request in batch {
+    let count = request.texts.len();
+    let reply = encds.as_ref().ok().map(|x| x[offset..offset+count].to_vec());
+    offset += count;
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    LATENCY_MICROS.fetch_add(request.queued.elapsed().as_micros() as u64, Ordering::Relaxed);
+    // the requester may be gone, nothing to answer then
+    let _ = request.reply.send(reply);
+  }
+  if let Err(error) = encds {
+    println!("[Warning]: batching worker, {} : {:?}",utils::error_message("E0007"),error);
+  }
+}
+
+// Queues the texts for the next batch, a worker that died (a panic while encoding) is replaced by a new one
+fn enqueue(model: &str, texts: &[String]) -> oneshot::Receiver<Option<Vec<Vec<f32>>>> {
+  let (reply, response) = oneshot::channel();
+  let request = BatchRequest {
+    model: model.to_string(),
+    texts: texts.to_owned(),
+    queued: Instant::now(),
+    reply
+  };
+  let mut queue = BATCH_QUEUE.lock().unwrap();
+  if let Err(unsent) = queue.send(request) {
+    println!("[Warning]: batching worker is gone, spawning a new one");
+    *queue = spawn_worker(utils::config_batch_max_size(), utils::config_batch_max_wait());
+    // the new worker holds the receiver, this send cannot fail
+    let _ = queue.send(unsent.0);
+  }
+  response
+}
+fn reply_embeddings(reply: Result<Option<Vec<Vec<f32>>>,oneshot::error::RecvError>) -> Result<Vec<Vec<f32>>, String> {
+  reply.ok().flatten().ok_or(utils::error_message("E0007"))
+}
+
+// Queues the texts for the next batch and waits for their embeddings, blocks the calling thread
+// Never call it from an async handler, use submit_async there
+pub fn submit(model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  if texts.is_empty() {
+    return Ok(Vec::new());
+  }
+  reply_embeddings(enqueue(model, texts).blocking_recv())
+}
+// Queues the texts for the next batch, the handler awaits the embeddings without holding an executor thread
+pub async fn submit_async(model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  if texts.is_empty() {
+    return Ok(Vec::new());
+  }
+  reply_embeddings(enqueue(model, texts).await)
+}
+
+pub fn stats() -> BatchStats {
+  let batches = BATCHES.load(Ordering::Relaxed);
+  let requests = REQUESTS.load(Ordering::Relaxed);
+  let texts = TEXTS.load(Ordering::Relaxed);
+  BatchStats {
+    batches,
+    requests,
+    texts,
+    max_batch_size: MAX_BATCH_SIZE.load(Ordering::Relaxed),
+    mean_batch_size: if batches == 0 { 0.0 } else { texts as f32 / batches as f32 },
+    mean_latency_ms: if requests == 0 { 0.0 } else { LATENCY_MICROS.load(Ordering::Relaxed) as f32 / requests as f32 / 1000.0 },
+    mean_encode_ms: if batches == 0 { 0.0 } else { ENCODE_MICROS.load(Ordering::Relaxed) as f32 / batches as f32 / 1000.0 }
+  }
+}
@@ -0,0 +1,151 @@
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::create_dir_all;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use rocket::serde::{Serialize, Deserialize};
+use walkdir::WalkDir;
+
+use crate::cryptography;
+use crate::files;
+use crate::utils;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheStats {
+  pub memory_hits: u64,
+  pub disk_hits: u64,
+  pub misses: u64,
+  pub entries: usize,
+  pub capacity: usize
+}
+
+// Least recently used embeddings, the tick of each key orders the evictions
+pub struct EmbeddingCache {
+  pub capacity: usize,
+  entries: HashMap<String,(Vec<f32>,u64)>,
+  recency: BTreeMap<u64,String>,
+  tick: u64
+}
+
+lazy_static! {
+  static ref EMBEDDINGS_CACHE: Mutex<EmbeddingCache> = Mutex::new(
+    EmbeddingCache::new(utils::config_embedding_cache_capacity())
+  );
+}
+static MEMORY_HITS: AtomicU64 = AtomicU64::new(0);
+static DISK_HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+impl EmbeddingCache {
+  pub fn new(capacity: usize) -> EmbeddingCache {
+    EmbeddingCache {
+      capacity,
+      entries: HashMap::new(),
+      recency: BTreeMap::new(),
+      tick: 0
+    }
+  }
+  pub fn get(&mut self, key: &str) -> Option<Vec<f32>> {
+    self.tick += 1;
+    let tick = self.tick;
+    let (vector, last) = self.entries.get_mut(key)?;
+    self.recency.remove(last);
+    *last = tick;
+    self.recency.insert(tick, key.to_string());
+    Some(vector.clone())
+  }
+  pub fn put(&mut self, key: &str, vector: Vec<f32>) {
+    if self.capacity == 0 {
+      return;
+    }
+    self.tick += 1;
+    if let Some((_, last)) = self.entries.insert(key.to_string(), (vector, self.tick)) {
+      self.recency.remove(&last);
+    }
+    self.recency.insert(self.tick, key.to_string());
+    while self.entries.len() > self.capacity {
+      let (&oldest, _) = self.recency.iter().next().unwrap();
+      let evicted = self.recency.remove(&oldest).unwrap();
+      self.entries.remove(&evicted);
+    }
+  }
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+}
+
+// Embeddings are only reused for the same text, model and normalization
+pub fn embedding_key(text: &str, model: &str, normalization: &str) -> String {
+  format!("{}.{}.{}",model,normalization,cryptography::sha256_digest(text))
+}
+pub fn embedding_cache_filename(key: &str) -> String {
+  format!("{}{}{}",utils::config_embedding_cache_folder(),key,utils::config_embeddings_extension())
+}
+
+// Looks a key up in memory, then on disk (if enabled), counting hits and misses
+pub fn lookup(key: &str, disk: bool) -> Option<Vec<f32>> {
+  if let Some(vector) = EMBEDDINGS_CACHE.lock().unwrap().get(key) {
+    MEMORY_HITS.fetch_add(1, Ordering::Relaxed);
+    return Some(vector);
+  }
+  if disk {
+    if let Ok(lines) = utils::lines_from_file(embedding_cache_filename(key)) {
+      let vector = lines.iter().map(|x| x.parse::<f32>()).collect::<Result<Vec<f32>,_>>();
+      // an empty file was left by a writer that predates the atomic writes
+      if let Some(vector) = vector.ok().filter(|x| !x.is_empty()) {
+        DISK_HITS.fetch_add(1, Ordering::Relaxed);
+        EMBEDDINGS_CACHE.lock().unwrap().put(key, vector.clone());
+        return Some(vector);
+      }
+    }
+  }
+  MISSES.fetch_add(1, Ordering::Relaxed);
+  None
+}
+pub fn store(key: &str, vector: &[f32], disk: bool) {
+  EMBEDDINGS_CACHE.lock().unwrap().put(key, vector.to_owned());
+  if disk {
+    create_dir_all(utils::config_embedding_cache_folder()).unwrap();
+    // a reader never sees a half written vector, it would be served as a shorter embedding
+    if files::write_atomic(&embedding_cache_filename(key),
+      vector.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join("\n").as_bytes()).is_err() {
+      println!("[Warning]: unable to write embedding cache file: <{}>",embedding_cache_filename(key));
+    }
+  }
+}
+
+pub fn stats() -> CacheStats {
+  let cache = EMBEDDINGS_CACHE.lock().unwrap();
+  CacheStats {
+    memory_hits: MEMORY_HITS.load(Ordering::Relaxed),
+    disk_hits: DISK_HITS.load(Ordering::Relaxed),
+    misses: MISSES.load(Ordering::Relaxed),
+    entries: cache.len(),
+    capacity: cache.capacity
+  }
+}
+
+// Removes the disk entries older than max_age and then the oldest ones above max_files, returns the removed count
+pub fn prune_disk(max_age: Option<Duration>, max_files: Option<usize>) -> usize {
+  let mut files = WalkDir::new(utils::config_embedding_cache_folder()).into_iter().filter_map(|e| e.ok())
+    .filter(|x| x.file_type().is_file())
+    .map(|x| (x.path().to_path_buf(), x.metadata().ok().and_then(|y| y.modified().ok()).unwrap_or(SystemTime::UNIX_EPOCH)))
+    .collect::<Vec<(std::path::PathBuf,SystemTime)>>();
+  // newest first
+  files.sort_by_key(|x| std::cmp::Reverse(x.1));
+  let now = SystemTime::now();
+  let mut removed : usize = 0;
+  for (idx, (path, modified)) in files.iter().enumerate() {
+    let too_old = max_age.map(|x| now.duration_since(*modified).unwrap_or_default() > x).unwrap_or(false);
+    let too_many = max_files.map(|x| idx >= x).unwrap_or(false);
+    if (too_old || too_many) && fs::remove_file(path).is_ok() {
+      removed += 1;
+    }
+  }
+  removed
+}
@@ -0,0 +1,136 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::catalogue;
+use crate::laws;
+use crate::matrix;
+use crate::transformer;
+
+// Rows scored together against the whole book while computing the nearest neighbours
+const NEIGHBOUR_CHUNK: usize = 256;
+
+// Units of a relevance threshold:
+// Distance (raw distance, lower is closer), ZScore (standard deviations closer than the typical
+// nearest neighbour of the book), Percentile (share of the book nearest neighbours farther away),
+// Confidence (logistic of the z-score, 0.5 is as close as a typical nearest neighbour)
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ScoreUnit {
+  Distance,
+  ZScore,
+  Percentile,
+  Confidence
+}
+// Distribution of the distance of each catalogue of a book to its nearest neighbour (in another article)
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BookStatistics {
+  pub count: usize,
+  pub mean: f32,
+  pub deviation: f32,
+  pub neighbours: Vec<f32>
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Calibrated {
+  pub zscore: f32,
+  pub percentile: f32,
+  pub confidence: f32
+}
+
+lazy_static! {
+  // by Book and name of the metric, a request may search with a metric other than the indexed one
+  static ref STATISTICS_MEMORY: Mutex<HashMap<(laws::LawBook,String),BookStatistics>> = Mutex::new(
+    HashMap::new()
+  );
+}
+
+impl ScoreUnit {
+  pub fn from_name(name: &str) -> Option<ScoreUnit> {
+    match name.to_lowercase().as_str() {
+      "distance"   => Some(ScoreUnit::Distance),
+      "zscore"     => Some(ScoreUnit::ZScore),
+      "percentile" => Some(ScoreUnit::Percentile),
+      "confidence" => Some(ScoreUnit::Confidence),
+      _ => None
+    }
+  }
+}
+
+impl BookStatistics {
+  pub fn calibrate(&self, distance: f32) -> Calibrated {
+    let zscore = if self.deviation > 0.0 { (self.mean - distance) / self.deviation } else { 0.0 };
+    // neighbours is sorted, closest first
+    let closer = self.neighbours.partition_point(|&x| x < distance);
+    Calibrated {
+      zscore,
+      percentile: if self.count == 0 { 0.0 } else { 100.0 * (self.count - closer) as f32 / self.count as f32 },
+      confidence: 1.0 / (1.0 + (-zscore).exp())
+    }
+  }
+}
+
+// Same article, parts overlap and would make every neighbour look close
+fn same_article(a: &laws::LawIndex, b: &laws::LawIndex) -> bool {
+  a.titulo == b.titulo && a.capitulo == b.capitulo && a.articulo == b.articulo
+}
+
+// Statistics of the nearest neighbour distances of a Book under a metric, computed once per load
+pub fn book_statistics(book: &laws::LawBook, metric: &transformer::DistanceMetric) -> Result<BookStatistics, String> {
+  let key = (book.clone(), metric.name());
+  if let Some(statistics) = STATISTICS_MEMORY.lock().unwrap().get(&key) {
+    return Ok(statistics.clone());
+  }
+  let mut neighbours: Vec<f32> = Vec::new();
+  if let Some(model) = catalogue::book_model(book) {
+    // not kept in memory, a quantized book never holds the f32 matrix
+    let dmatrix = matrix::BookMatrix::fabric(book, &model)?;
+    let rows = (0..dmatrix.len()).map(|x| dmatrix.vectors.row(x).to_vec()).collect::<Vec<Vec<f32>>>();
+    // every row against every other, a chunk of rows at a time
+    for (chunk, queries) in rows.chunks(NEIGHBOUR_CHUNK).enumerate() {
+      for (offset, distances) in dmatrix.score_batch(metric, queries).iter().enumerate() {
+        let dindex = &dmatrix.indexes[chunk * NEIGHBOUR_CHUNK + offset];
+        if let Some(nearest) = distances.iter().zip(dmatrix.indexes.iter())
+          .filter(|(_,other)| !same_article(dindex, other))
+          .map(|(&x,_)| x).filter(|x| !x.is_nan()).min_by(|a,b| a.total_cmp(b)) {
+          neighbours.push(nearest);
+        }
+      }
+    }
+  }
+  neighbours.sort_by(|a,b| a.total_cmp(b));
+  let count = neighbours.len();
+  let mean = if count == 0 { 0.0 } else { neighbours.iter().sum::<f32>() / count as f32 };
+  let deviation = if count == 0 { 0.0 } else { (neighbours.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / count as f32).sqrt() };
+  let statistics = BookStatistics {
+    count,
+    mean,
+    deviation,
+    neighbours
+  };
+  STATISTICS_MEMORY.lock().unwrap().insert(key, statistics.clone());
+  Ok(statistics)
+}
+// Drops the statistics of a Book, they are recomputed on the next use
+pub fn forget_statistics(book: &laws::LawBook) {
+  STATISTICS_MEMORY.lock().unwrap().retain(|(dbook,_),_| dbook != book);
+}
+
+// Whether a distance clears a threshold given in the configured unit
+pub fn passes(statistics: &BookStatistics, distance: f32, unit: &ScoreUnit, threshold: f32) -> bool {
+  let calibrated = statistics.calibrate(distance);
+  match unit {
+    ScoreUnit::Distance   => distance < threshold,
+    ScoreUnit::ZScore     => calibrated.zscore >= threshold,
+    ScoreUnit::Percentile => calibrated.percentile >= threshold,
+    ScoreUnit::Confidence => calibrated.confidence >= threshold
+  }
+}
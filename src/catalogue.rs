@@ -1,7 +1,9 @@
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::fs::create_dir_all;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::path::Path;
 use rocket::serde::{Serialize, Deserialize};
 use walkdir::WalkDir;
 
@@ -9,9 +11,16 @@ use crate::transformer::EmbeddingType;
 use crate::utils;
 use crate::language;
 use crate::transformer;
-use crate::mathematics;
 use crate::files;
+use crate::explain;
 use crate::laws;
+use crate::whitening;
+use crate::calibration;
+use crate::matrix;
+use crate::quantization;
+use crate::snapshot;
+use crate::journal;
+use crate::store;
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -22,44 +31,269 @@ pub struct Catalogue {
   pub dmeaning: transformer::Meaning
 }
 
+// Catalogues of one Book, never modified once published: a new shard replaces it
+pub type BookShard = Arc<HashMap<laws::LawIndex,Catalogue>>;
+// Catalogues of a Book read from the store, with the snapshot their vectors are read from if any
+pub type BookRead = (HashMap<laws::LawIndex,Catalogue>, Option<Arc<snapshot::MappedSnapshot>>);
+
 lazy_static! {
-  pub static ref CATALOGUES_MEMORY: Mutex<HashMap<laws::LawIndex,Catalogue>> = Mutex::new(
+  // Readers clone the shard of a Book and release the lock at once, so searches never wait on each other
+  static ref CATALOGUES_MEMORY: RwLock<HashMap<laws::LawBook,BookShard>> = RwLock::new(
+    HashMap::new()
+  );
+  // Catalogues of the Books being ingested, published as a whole when the ingestion ends
+  static ref STAGING_MEMORY: Mutex<HashMap<laws::LawBook,HashMap<laws::LawIndex,Catalogue>>> = Mutex::new(
+    HashMap::new()
+  );
+  // Catalogues replaced during the ingestion of a Book, dropped from its shard when it is published
+  static ref STAGING_RETIRED: Mutex<HashMap<laws::LawBook,HashSet<laws::LawIndex>>> = Mutex::new(
+    HashMap::new()
+  );
+  // Snapshot the shard of a Book was published from, its catalogues hold no vector: they are read from the map.
+  // Only written under the write lock of CATALOGUES_MEMORY
+  static ref SNAPSHOTS_MEMORY: RwLock<HashMap<laws::LawBook,Arc<snapshot::MappedSnapshot>>> = RwLock::new(
+    HashMap::new()
+  );
+  // Generation of the shard of each Book, whatever is built from a shard is tagged with it
+  static ref BOOK_GENERATIONS: Mutex<HashMap<laws::LawBook,u64>> = Mutex::new(
     HashMap::new()
   );
 }
+static GENERATION: AtomicU64 = AtomicU64::new(0);
 
-pub fn consult_catalogues_memory(dindex: &laws::LawIndex) -> Catalogue {
-  if !(CATALOGUES_MEMORY.lock().unwrap().contains_key(dindex)) {
-    load_catalogues_memory(false);
-  }
-  CATALOGUES_MEMORY.lock().unwrap().get(dindex)
-    .expect(utils::error_message("E0009").as_str()).to_owned()
-}
-
-pub fn compare_embedding_against_law_book(embedding: &transformer::Embedding, book: &laws::LawBook) -> Vec<(laws::LawIndex,f32)>{
-  let mut aux = CATALOGUES_MEMORY.lock().unwrap()
-    .iter().filter(|(dindex,dcatalogue)| 
-      dindex.book==*book)
-      .collect::<HashMap<&laws::LawIndex,&Catalogue>>()
-    .iter().map(|(&dindex,dcatalogue)| 
-      (dindex.clone(),transformer::embeddings_vectors_distance(
-        &embedding.vector.clone().unwrap(), 
-        &dcatalogue.dmeaning.embedding.vector.clone().unwrap())))
-      .collect::<Vec<(laws::LawIndex,f32)>>();
-  aux.sort_by(|a,b| a.1.partial_cmp(&b.1).unwrap());
-  return aux[0..utils::config_return_count()].to_vec().iter()
-    .filter(|x| x.1 < utils::config_return_min_value()).map(|x| x.to_owned())
-    .collect::<Vec<(laws::LawIndex,f32)>>();
-}
-
-pub fn load_catalogues_memory_item(law_index: &laws::LawIndex, etype: transformer::EmbeddingType) {
-  let phrase_of_law = files::read_phrase_of_law(law_index);
-  let embedding = &Some(utils::lines_from_file(&files::embeddings_filename(&law_index))
-    .expect(format!("{} : {}",utils::error_message("E0003").as_str(),&files::embeddings_filename(&law_index)).as_str())
-    .iter().map(|x| x.parse::<f32>().unwrap()).collect::<Vec<f32>>());
-  CATALOGUES_MEMORY.lock().unwrap().insert(
-    law_index.clone(),
-    catalogue_fabric(
+// Current shard of a Book, empty if the book is not in memory
+pub fn book_shard(book: &laws::LawBook) -> BookShard {
+  CATALOGUES_MEMORY.read().unwrap().get(book).cloned().unwrap_or_default()
+}
+// Generation of the published shard of a Book, read it before the shard: what is built from
+// a newer shard than its generation says is only built again once more
+pub fn book_generation(book: &laws::LawBook) -> u64 {
+  BOOK_GENERATIONS.lock().unwrap().get(book).copied().unwrap_or(0)
+}
+// A quantized Book is searched by its codes and rescored from the store, its catalogues hold no f32 vector
+fn resident(mut catalogue: Catalogue, quantized: bool) -> Catalogue {
+  if quantized {
+    catalogue.dmeaning.embedding.vector = None;
+  }
+  catalogue
+}
+fn resident_catalogues(catalogues: HashMap<laws::LawIndex,Catalogue>) -> HashMap<laws::LawIndex,Catalogue> {
+  let quantized = utils::config_quantization() != quantization::Quantization::None;
+  if !quantized {
+    return catalogues;
+  }
+  catalogues.into_iter().map(|(dindex, dcatalogue)| (dindex, resident(dcatalogue, quantized))).collect()
+}
+pub fn memory_books() -> Vec<laws::LawBook> {
+  CATALOGUES_MEMORY.read().unwrap().keys().cloned().collect()
+}
+pub fn memory_catalogue(dindex: &laws::LawIndex) -> Option<Catalogue> {
+  book_shard(&dindex.book).get(dindex).cloned()
+}
+// Snapshot the current shard of a Book was published from
+pub fn book_snapshot(book: &laws::LawBook) -> Option<Arc<snapshot::MappedSnapshot>> {
+  SNAPSHOTS_MEMORY.read().unwrap().get(book).cloned()
+}
+// Replaces every catalogue of a Book at once
+pub fn publish_shard(book: &laws::LawBook, catalogues: HashMap<laws::LawIndex,Catalogue>) {
+  publish_shard_mapped(book, catalogues, None);
+}
+// Replaces every catalogue of a Book at once, along with the snapshot the vectors are read from
+pub fn publish_shard_mapped(book: &laws::LawBook, catalogues: HashMap<laws::LawIndex,Catalogue>, mapped: Option<Arc<snapshot::MappedSnapshot>>) {
+  publish_shards(Vec::from([(book.clone(), Some((catalogues, mapped)))]));
+}
+// Replaces (or removes, on None) the shards of several Books at once, readers see all the changes or none
+pub fn publish_shards(shards: Vec<(laws::LawBook,Option<BookRead>)>) {
+  let books = shards.iter().map(|x| x.0.clone()).collect::<Vec<laws::LawBook>>();
+  let shards = shards.into_iter().map(|(book, read)| (book, read.map(|(catalogues, mapped)| (resident_catalogues(catalogues), mapped))))
+    .collect::<Vec<(laws::LawBook,Option<BookRead>)>>();
+  {
+    let mut memory = CATALOGUES_MEMORY.write().unwrap();
+    let mut snapshots = SNAPSHOTS_MEMORY.write().unwrap();
+    for (book, read) in shards {
+      match read {
+        Some((catalogues, mapped)) => {
+          memory.insert(book.clone(), Arc::new(catalogues));
+          match mapped {
+            Some(mapped) => snapshots.insert(book, mapped),
+            None => snapshots.remove(&book)
+          };
+        }
+        None => {
+          memory.remove(&book);
+          snapshots.remove(&book);
+        }
+      };
+    }
+  }
+  for book in books {
+    forget_book(&book);
+  }
+}
+// Adds a catalogue to a copy of the shard of its Book, readers keep the shard they already hold
+pub fn insert_catalogue(catalogue: Catalogue) {
+  let book = catalogue.dindex.book.clone();
+  insert_catalogues(&book, Vec::from([catalogue]));
+}
+// Adds catalogues of a Book at once to a copy of its shard, made outside the lock: searches and other
+// writers only wait for the swap. The copy is made again if the shard was replaced meanwhile
+pub fn insert_catalogues(book: &laws::LawBook, catalogues: Vec<Catalogue>) {
+  let quantized = utils::config_quantization() != quantization::Quantization::None;
+  let catalogues = catalogues.into_iter().map(|x| resident(x, quantized)).collect::<Vec<Catalogue>>();
+  let inserted = catalogues.iter().map(|x| x.dindex.clone()).collect::<HashSet<laws::LawIndex>>();
+  loop {
+    let current = CATALOGUES_MEMORY.read().unwrap().get(book).cloned();
+    let mut shard = current.as_deref().cloned().unwrap_or_default();
+    shard.extend(catalogues.iter().map(|x| (x.dindex.clone(), x.clone())));
+    let mut memory = CATALOGUES_MEMORY.write().unwrap();
+    if memory.get(book).map(Arc::as_ptr) != current.as_ref().map(Arc::as_ptr) {
+      continue;
+    }
+    // the snapshot must not serve the vector of a catalogue replaced in memory
+    let mut snapshots = SNAPSHOTS_MEMORY.write().unwrap();
+    if let Some(mapped) = snapshots.get(book).filter(|x| inserted.iter().any(|y| x.contains(y))) {
+      let mapped = Arc::new(mapped.without(&inserted));
+      snapshots.insert(book.clone(), mapped);
+    }
+    memory.insert(book.clone(), Arc::new(shard));
+    break;
+  }
+  forget_book(book);
+}
+// Keeps a freshly ingested catalogue out of the searches until its Book is published
+pub fn stage_catalogue(catalogue: Catalogue) {
+  let catalogue = resident(catalogue, utils::config_quantization() != quantization::Quantization::None);
+  STAGING_MEMORY.lock().unwrap().entry(catalogue.dindex.book.clone()).or_default()
+    .insert(catalogue.dindex.clone(), catalogue);
+}
+// Keeps a catalogue replaced by the ingestion in the searches until its Book is published
+pub fn retire_catalogue(dindex: &laws::LawIndex) {
+  STAGING_RETIRED.lock().unwrap().entry(dindex.book.clone()).or_default()
+    .insert(dindex.clone());
+}
+// Whether a Book is being ingested by this process
+pub fn is_staging(book: &laws::LawBook) -> bool {
+  STAGING_MEMORY.lock().unwrap().contains_key(book)
+}
+// Drops the staged catalogues of a Book whose ingestion failed, searches keep its current shard
+pub fn discard_staged(book: &laws::LawBook) {
+  STAGING_MEMORY.lock().unwrap().remove(book);
+  STAGING_RETIRED.lock().unwrap().remove(book);
+}
+// Publishes the staged catalogues of a Book over its current shard
+pub fn publish_staged(book: &laws::LawBook) {
+  let staged = STAGING_MEMORY.lock().unwrap().remove(book).unwrap_or_default();
+  let retired = STAGING_RETIRED.lock().unwrap().remove(book).unwrap_or_default();
+  let mut catalogues = (*book_shard(book)).clone();
+  catalogues.retain(|dindex, _| !retired.contains(dindex));
+  // the catalogues kept from a snapshot still read their vectors from it, the replaced ones never do
+  let replaced = staged.keys().cloned().chain(retired).collect::<HashSet<laws::LawIndex>>();
+  let mapped = book_snapshot(book).map(|x| Arc::new(x.without(&replaced)));
+  catalogues.extend(staged);
+  publish_shard_mapped(book, catalogues, mapped);
+}
+
+// A miss only reads that catalogue from the store, never the whole book
+#[allow(dead_code)]
+pub fn consult_catalogues_memory(dindex: &laws::LawIndex) -> Result<Catalogue, String> {
+  if let Some(catalogue) = memory_catalogue(dindex) {
+    return Ok(catalogue);
+  }
+  let stored = store::store().get(dindex)?
+    .ok_or(format!("{}: {}",utils::error_message("E0009"),files::law_index_to_filename(dindex)))?;
+  insert_catalogue(catalogue_of_stored(&stored));
+  memory_catalogue(dindex)
+    .ok_or(format!("{}: {}",utils::error_message("E0009"),files::law_index_to_filename(dindex)))
+}
+// Vectors of the catalogues of a Book for a model, whitened as they are searched: the resident ones
+// or the ones of its snapshot, else the stored ones for a quantized Book, whose catalogues hold none
+pub fn book_vectors(book: &laws::LawBook, model: &str) -> Result<Vec<(laws::LawIndex,Vec<f32>)>, String> {
+  let shard = book_shard(book);
+  let mapped = book_snapshot(book);
+  let vectors = shard.iter().filter(|(_,dcatalogue)| dcatalogue.dmeaning.embedding.model==model)
+    .map(|(dindex,dcatalogue)| dcatalogue.dmeaning.embedding.vector.clone()
+      .or_else(|| mapped.as_ref()?.vector(dindex).map(|x| whitening::whiten(book, model, &x)))
+      .map(|x| (dindex.clone(), x)))
+    .collect::<Option<Vec<(laws::LawIndex,Vec<f32>)>>>();
+  if let Some(vectors) = vectors {
+    return Ok(vectors);
+  }
+  Ok(store::store().get_book(book)?.into_iter()
+    .filter(|x| x.model==model && shard.contains_key(&x.dindex) && !x.vector.is_empty())
+    .map(|x| {
+      let vector = whitening::whiten(book, model, &x.vector);
+      (x.dindex, vector)
+    }).collect())
+}
+// Vector of a catalogue read from the snapshot of its shard, whitened
+pub fn mapped_vector(dindex: &laws::LawIndex, model: &str) -> Option<Vec<f32>> {
+  book_snapshot(&dindex.book)?.vector(dindex).map(|x| whitening::whiten(&dindex.book, model, &x))
+}
+// Vector of a catalogue in memory, read from its snapshot or the store if it is not resident
+pub fn catalogue_vector(dindex: &laws::LawIndex) -> Option<Vec<f32>> {
+  let shard = book_shard(&dindex.book);
+  let dcatalogue = shard.get(dindex)?;
+  dcatalogue.dmeaning.embedding.vector.clone()
+    .or_else(|| quantization::full_precision(dindex, &dcatalogue.dmeaning.embedding.model))
+}
+// Catalogue in memory of a stored one, its vector whitened
+pub fn catalogue_of_stored(stored: &store::StoredCatalogue) -> Catalogue {
+  let mut catalogue = stored.to_catalogue();
+  catalogue.dmeaning.embedding.vector = Some(whitening::whiten(&stored.dindex.book, &stored.model, &stored.vector));
+  catalogue
+}
+
+// Ranks every catalogue of a book against the embedding, closest first
+pub fn rank_embedding_against_law_book(embedding: &transformer::Embedding, book: &laws::LawBook) -> Result<Vec<(laws::LawIndex,f32)>, String> {
+  rank_embedding_against_law_book_within(embedding, book, |_| true)
+}
+// Ranks the catalogues of a book accepted by member, a quantized book only returns its rescored candidates
+pub fn rank_embedding_against_law_book_within(embedding: &transformer::Embedding, book: &laws::LawBook, member: impl Fn(&laws::LawIndex) -> bool) -> Result<Vec<(laws::LawIndex,f32)>, String> {
+  let query = embedding.vector.as_ref().unwrap();
+  Ok(match utils::config_quantization() {
+    quantization::Quantization::None => matrix::book_matrix(book, &embedding.model)?.rank(&embedding.metric, query)
+      .into_iter().filter(|(dindex,_)| member(dindex)).collect(),
+    dquantization => quantization::rescore(
+      quantization::book_quantized(book, &embedding.model, &dquantization)?.rank(&embedding.metric, query)
+        .into_iter().filter(|(dindex,_)| member(dindex)).collect(),
+      &embedding.metric,
+      query,
+      &embedding.model,
+      utils::config_rescore_candidates())
+  })
+}
+
+// Metric the catalogues of a book were indexed with, None if the book is not in memory
+pub fn book_metric(book: &laws::LawBook) -> Option<transformer::DistanceMetric> {
+  book_shard(book).values().next()
+    .map(|dcatalogue| dcatalogue.dmeaning.embedding.metric.clone())
+}
+// Model of the catalogues of a book, None if the book is not in memory
+pub fn book_model(book: &laws::LawBook) -> Option<String> {
+  book_shard(book).values().next()
+    .map(|dcatalogue| dcatalogue.dmeaning.embedding.model.clone())
+}
+
+pub fn compare_embedding_against_law_book(embedding: &transformer::Embedding, book: &laws::LawBook) -> Result<Vec<(laws::LawIndex,f32)>, String> {
+  let statistics = calibration::book_statistics(book, &embedding.metric)?;
+  Ok(rank_embedding_against_law_book(embedding, book)?.iter()
+    .take(utils::config_return_count())
+    .filter(|x| calibration::passes(&statistics, x.1, &utils::config_return_min_unit(), utils::config_return_min_value()))
+    .map(|x| x.to_owned())
+    .collect::<Vec<(laws::LawIndex,f32)>>())
+}
+
+// Catalogue of a reference, with its text and (whitened) embedding read from disk,
+// an unreadable text or embedding file is reported rather than stopping the load
+pub fn read_catalogue(law_index: &laws::LawIndex, etype: transformer::EmbeddingType, pooling: transformer::PoolingStrategy, model: String, template: String, metric: transformer::DistanceMetric) -> Result<Catalogue, String> {
+  let phrase_of_law = fs::read_to_string(files::file_of_law_filename(law_index))
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0002"),files::file_of_law_filename(law_index),x))?;
+  let vector = utils::lines_from_file(files::embeddings_filename(law_index))
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0003"),files::embeddings_filename(law_index),x))?
+    .iter().map(|x| x.parse::<f32>()).collect::<Result<Vec<f32>,_>>()
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0003"),files::embeddings_filename(law_index),x))?;
+  let embedding = &Some(whitening::whiten(&law_index.book, &model, &vector));
+  Ok(catalogue_fabric(
       law_index.book.pais.clone(),
       law_index.book.instrumento.clone(),
       law_index.titulo,
@@ -68,66 +302,234 @@ pub fn load_catalogues_memory_item(law_index: &laws::LawIndex, etype: transforme
       law_index.parte,
       &language::phrase_fabric(phrase_of_law),
       etype,
+      pooling,
+      model,
+      template,
+      metric,
       embedding
-    )
-  );
+    ))
+}
+// Drops everything derived from the catalogues of a book, it is computed again on the next use
+pub fn forget_book(book: &laws::LawBook) {
+  BOOK_GENERATIONS.lock().unwrap().insert(book.clone(), GENERATION.fetch_add(1, Ordering::SeqCst) + 1);
+  laws::forget_centroids(book);
+  calibration::forget_statistics(book);
+  matrix::forget_matrix(book);
+  quantization::forget_quantized(book);
+}
+// Books with a folder in reference_folder, named pais.instrumento
+pub fn reference_books() -> Vec<laws::LawBook> {
+  WalkDir::new(utils::config_reference_folder()).min_depth(1).max_depth(1).into_iter().filter_map(|e| e.ok())
+    .filter(|x| x.file_type().is_dir())
+    .filter_map(|x| utils::name_from_dir_entry(&x).split_once('.')
+      .map(|(pais, instrumento)| laws::LawBook {
+        pais: pais.to_string(),
+        instrumento: instrumento.to_string()
+      }))
+    .collect()
+}
+// Catalogues of a Book as they are in the store (for the folder, its snapshot while it is valid),
+// None if the store has no such Book
+pub fn read_book(book: &laws::LawBook) -> Option<BookRead> {
+  if utils::config_store() == store::StoreKind::Folder {
+    if !Path::new(&files::book_foldername(book)).is_dir() {
+      return None;
+    }
+    return Some(snapshot::read_snapshot(book).map(|(catalogues, mapped)| (catalogues, Some(mapped)))
+      .unwrap_or_else(|| (read_book_files(book), None)));
+  }
+  let stored = store::store().get_book(book).unwrap_or_else(|x| panic!("{}",x));
+  if stored.is_empty() {
+    return None;
+  }
+  Some((stored.iter().map(|x| (x.dindex.clone(), catalogue_of_stored(x))).collect(), None))
+}
+// Catalogues of a Book read from its reference files
+pub fn read_book_files(book: &laws::LawBook) -> HashMap<laws::LawIndex,Catalogue> {
+  let mut catalogues: HashMap<laws::LawIndex,Catalogue> = HashMap::new();
+  for dpath in WalkDir::new(files::book_foldername(book)).into_iter().filter_map(|e| e.ok()) {
+    if !(utils::name_from_dir_entry(&dpath).ends_with(&utils::config_reference_extension())) {
+      continue;
+    }
+    let (law_index, etype, pooling, model, template, metric) = match files::read_reference_file(&dpath) {
+      Ok(reference) => reference,
+      Err(reason) => {
+        println!("[Warning]: {}, it is not loaded, see the fsck command",reason);
+        continue;
+      }
+    };
+    match read_catalogue(&law_index, etype, pooling, model, template, metric) {
+      Ok(catalogue) => catalogues.insert(law_index, catalogue),
+      Err(reason) => {
+        println!("[Warning]: {}, it is not loaded, see the fsck command",reason);
+        continue;
+      }
+    };
+  }
+  catalogues
+}
+// Loads the Books of a store other than the folder, each one published as a whole
+fn load_store_memory(force_load: bool) -> Vec<laws::LawBook> {
+  let loaded = memory_books();
+  let mut books: Vec<laws::LawBook> = Vec::new();
+  for book in store::store().books().unwrap_or_else(|x| panic!("{}",x)) {
+    if !force_load && loaded.contains(&book) {
+      continue;
+    }
+    if let Some((catalogues, _)) = read_book(&book) {
+      println!("Loading {} catalogues of {}.{} from the {} store to CATALOGUES_MEMORY",catalogues.len(),book.pais,book.instrumento,store::store().name());
+      publish_shard(&book, catalogues);
+      books.push(book);
+    }
+  }
+  books
 }
 pub fn load_catalogues_memory(force_load: bool) {
-  // for dpath in fs::read_dir(utils::config_reference_folder()).expect("Catalogues folder not found") {
-  for dpath in WalkDir::new(utils::config_reference_folder()).into_iter().filter_map(|e| e.ok()) {
+  if utils::config_store() != store::StoreKind::Folder {
+    let books = load_store_memory(force_load);
+    return log_statistics(books);
+  }
+  let mut books: Vec<laws::LawBook> = Vec::new();
+  // Books with a valid snapshot are mapped at once, their files are not read
+  let mut snapshots: Vec<laws::LawBook> = Vec::new();
+  let loaded = memory_books();
+  for book in reference_books() {
+    // writes interrupted by a crash are completed (or dropped) before anything is read
+    journal::recover(&book);
+    if (force_load || !loaded.contains(&book)) && snapshot::load_snapshot(&book) {
+      println!("Loading snapshot to CATALOGUES_MEMORY: [{}]",files::snapshot_filename(&book));
+      snapshots.push(book.clone());
+      books.push(book);
+    }
+  }
+  // Books read file by file are staged and published once complete, the folders of the mapped ones are not walked
+  let mut staging: HashMap<laws::LawBook,HashMap<laws::LawIndex,Catalogue>> = HashMap::new();
+  let mapped = snapshots.iter().map(|x| format!("{}.{}",x.pais,x.instrumento)).collect::<HashSet<String>>();
+  for dpath in WalkDir::new(utils::config_reference_folder()).into_iter()
+    .filter_entry(|x| !(x.depth() == 1 && x.file_type().is_dir() && mapped.contains(&utils::name_from_dir_entry(x))))
+    .filter_map(|e| e.ok()) {
     let filename = utils::name_from_dir_entry(&dpath);
     if !(filename.ends_with(&utils::config_reference_extension())) {
       continue;
     }
-    let (law_index, etype) = files::read_reference_file(&dpath);
-    if !(force_load || !(CATALOGUES_MEMORY.lock().unwrap().contains_key(&law_index))) {
+    let (law_index, etype, pooling, model, template, metric) = match files::read_reference_file(&dpath) {
+      Ok(reference) => reference,
+      Err(reason) => {
+        println!("[Warning]: {}, it is not loaded, see the fsck command",reason);
+        continue;
+      }
+    };
+    let shard = staging.entry(law_index.book.clone()).or_insert_with(|| (*book_shard(&law_index.book)).clone());
+    if !force_load && shard.contains_key(&law_index) {
       continue;
     }
+    // an interrupted ingestion may leave a reference without its embedding or text
+    if !Path::new(&files::embeddings_filename(&law_index)).exists() || !Path::new(&files::file_of_law_filename(&law_index)).exists() {
+      println!("[Warning]: [{}] is missing its embedding or text, it is not loaded, see the fsck command",filename);
+      continue;
+    }
+    if template != utils::config_template(&model, &transformer::EncodingRole::Document) {
+      println!("[Warning]: [{}] was embedded with the template <{}>, not the configured one, it must be ingested again",filename,template);
+    }
     println!("Loading file to CATALOGUES_MEMORY: [{}]",filename);
-    load_catalogues_memory_item(&law_index, etype);
+    let catalogue = match read_catalogue(&law_index, etype, pooling, model, template, metric) {
+      Ok(catalogue) => catalogue,
+      Err(reason) => {
+        println!("[Warning]: {}, it is not loaded, see the fsck command",reason);
+        continue;
+      }
+    };
+    if !books.contains(&law_index.book) {
+      books.push(law_index.book.clone());
+    }
+    shard.insert(law_index.clone(), catalogue);
+  }
+  for (book, catalogues) in staging {
+    if books.contains(&book) {
+      publish_shard(&book, catalogues);
+    }
+  }
+  log_statistics(books);
+}
+// Score statistics of the loaded books, thresholds are calibrated against them
+fn log_statistics(books: Vec<laws::LawBook>) {
+  for book in books {
+    match calibration::book_statistics(&book, &utils::config_law_metric(&book)) {
+      Ok(statistics) => println!("Nearest neighbour {} distances of {}.{}: mean {:.4}, deviation {:.4} over {} catalogues",
+        utils::config_law_metric(&book).name(),book.pais,book.instrumento,statistics.mean,statistics.deviation,statistics.count),
+      Err(error) => println!("[Warning]: no statistics for {}.{}, {}",book.pais,book.instrumento,error)
+    }
   }
 }
 
+// Reference and embedding files of a catalogue with their content
+pub fn catalogue_writes(doc: &Catalogue) -> Vec<(String, String)> {
+  Vec::from([
+    (files::reference_filename(&doc.dindex), files::reference_file_content(doc)),
+    (files::embeddings_filename(&doc.dindex), files::embeddings_file_content(doc))])
+}
+// Saves the reference, embedding and text of a catalogue as one unit of the store
 pub fn save_catalogue(doc: &Catalogue) {
-  create_dir_all(files::reference_foldername(&doc.dindex)).unwrap();
-  create_dir_all(files::embeddings_foldername(&doc.dindex)).unwrap();
-  files::write_reference_file(doc);
-  files::write_embeddings_file(doc);
+  store::store().put(&store::StoredCatalogue::from_catalogue(doc))
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),files::law_index_to_filename(&doc.dindex)));
+}
+// Stages the catalogues of a unit committed by an interrupted ingestion, from its files
+pub fn stage_references(filenames: &[String]) {
+  for filename in filenames.iter().filter(|x| x.ends_with(&utils::config_reference_extension())) {
+    let (law_index, etype, pooling, model, template, metric) = match files::read_reference_path(filename) {
+      Ok(reference) => reference,
+      Err(reason) => {
+        println!("[Warning]: {}, it is ingested again",reason);
+        continue;
+      }
+    };
+    match read_catalogue(&law_index, etype, pooling, model, template, metric) {
+      Ok(catalogue) => stage_catalogue(catalogue),
+      Err(reason) => println!("[Warning]: {}, it is ingested again",reason)
+    }
+  }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn catalogue_fabric(
   pais: String, instrumento: String, titulo: Option<u16>,
   capitulo: Option<u16>, articulo: Option<u16>,
   parte: Option<u16>, phrase_of_law: &language::Phrase, 
-  etype: transformer::EmbeddingType, embedding: &Option<Vec<f32>>) -> Catalogue {
-  return Catalogue {
+  etype: transformer::EmbeddingType, pooling: transformer::PoolingStrategy, 
+  model: String, template: String, metric: transformer::DistanceMetric, 
+  embedding: &Option<Vec<f32>>) -> Catalogue {
+  Catalogue {
     dindex: laws::LawIndex {
       book:laws::LawBook {
         pais:pais.to_lowercase(),
         instrumento:instrumento.to_lowercase(),
       },
-      titulo:titulo,
-      capitulo:capitulo,
-      articulo:articulo,
-      parte:parte
+      titulo,
+      capitulo,
+      articulo,
+      parte
     },
     dmeaning: transformer::meaning_fabric(
       phrase_of_law,
       embedding,
-      etype
+      etype,
+      pooling,
+      model,
+      template,
+      metric
     )
   }
 }
 
-pub fn embedd_sentence(phrase_of_law: &language::Phrase, law_index: &laws::LawIndex) -> (Option<Vec<f32>>, EmbeddingType) {
+pub fn embedd_sentence(model: &str, template: &str, phrase_of_law: &language::Phrase, law_index: &laws::LawIndex) -> Result<(Option<Vec<f32>>, EmbeddingType), String> {
   let mut embedding: Option<Vec<f32>>= None;
-  let segments = language::segment_phrase_with_index(phrase_of_law, law_index);
+  let segments = language::segment_phrase_with_index(model, template, phrase_of_law, law_index);
   let mut etype = transformer::EmbeddingType::Total;
   if !segments.is_empty() {
-    let texts: Vec<String> = segments.iter().map(|x| x.1.text.clone()).collect::<Vec<String>>();
-    let encds = transformer::transform_sentences(&texts);
-    let encds_arr = mathematics::vec2d_axis_average::<f32>(&encds,0);
-    embedding = Some(encds_arr);
+    let windows: Vec<language::Window> = segments.iter().map(|x| x.1.clone()).collect::<Vec<language::Window>>();
+    let texts: Vec<String> = windows.iter().map(|x| x.phrase.text.clone()).collect::<Vec<String>>();
+    let encds = transformer::transform_templated(model, template, &texts)?;
+    embedding = Some(transformer::pool_windows(&windows, &encds, &utils::config_pooling()));
     if segments.len() != 1 {
       etype = transformer::EmbeddingType::Average;
     }
@@ -136,26 +538,155 @@ pub fn embedd_sentence(phrase_of_law: &language::Phrase, law_index: &laws::LawIn
       println!("[Warning]: catalogue_mech, phrase_of_law is found too short : <{}>",phrase_of_law.text);
     }
   }
-  return (embedding,etype);
+  Ok((embedding,etype))
+}
+// Embeddings of each window of a phrase of law, indexed by parte (parte None if it fits in one window)
+pub fn embedd_parts(model: &str, template: &str, phrase_of_law: &language::Phrase, law_index: &laws::LawIndex) -> Result<Vec<(laws::LawIndex, language::Phrase, Vec<f32>)>, String> {
+  let segments = language::segment_phrase_with_index(model, template, phrase_of_law, law_index);
+  if segments.is_empty() {
+    if !phrase_of_law.text.is_empty() {
+      println!("[Warning]: catalogue_mech, phrase_of_law is found too short : <{}>",phrase_of_law.text);
+    }
+    return Ok(Vec::new());
+  }
+  let texts: Vec<String> = segments.iter().map(|x| x.1.phrase.text.clone()).collect::<Vec<String>>();
+  let encds = transformer::transform_templated(model, template, &texts)?;
+  Ok(segments.into_iter().zip(encds)
+    .map(|((dindex, window), encd)| (dindex, window.phrase, encd)).collect())
 }
 // Generate catalogue for phrase of law
 pub fn catalogue_mech(phrase_of_law: &language::Phrase, law_index: &laws::LawIndex) {
-  let (embd, etype) = embedd_sentence(phrase_of_law, law_index);
+  let pooling = utils::config_pooling();
+  if pooling == transformer::PoolingStrategy::Parts {
+    return catalogue_mech_parts(phrase_of_law, law_index);
+  }
+  let model = utils::config_law_model(&law_index.book);
+  let template = utils::config_template(&model, &transformer::EncodingRole::Document);
+  let metric = utils::config_law_metric(&law_index.book);
+  // the ingestion of the book fails as a whole, its staged catalogues are discarded
+  let (embd, etype) = embedd_sentence(&model, &template, phrase_of_law, law_index)
+    .unwrap_or_else(|x| panic!("{}: {}",x,files::law_index_to_filename(law_index)));
   if embd.is_some() {
-    // Save catalgue
-    save_catalogue(&catalogue_fabric(
+    // Save catalgue and document of law
+    let catalogue = catalogue_fabric(
       law_index.book.pais.clone().to_lowercase(), 
       law_index.book.instrumento.clone().to_lowercase(), 
-      law_index.titulo.clone(),
-      law_index.capitulo.clone(), 
-      law_index.articulo.clone(),
+      law_index.titulo,
+      law_index.capitulo, 
+      law_index.articulo,
       None,
       &phrase_of_law.clone(), 
       etype.clone(),
-      &embd));
-    // Save document of law
-    files::write_file_of_law(&phrase_of_law.clone(), law_index);
-    // Load catalogue
-    load_catalogues_memory_item(law_index, etype.clone());
+      pooling.clone(),
+      model.clone(),
+      template.clone(),
+      metric.clone(),
+      &embd);
+    save_catalogue(&catalogue);
+    explain::forget_article(law_index);
+    // Stage catalogue, it is searched once the whole book is published
+    stage_catalogue(catalogue_of_stored(&store::StoredCatalogue::from_catalogue(&catalogue)));
+  }
+}
+// Generate one catalogue per window of the phrase of law, no pooling
+pub fn catalogue_mech_parts(phrase_of_law: &language::Phrase, law_index: &laws::LawIndex) {
+  let model = utils::config_law_model(&law_index.book);
+  let template = utils::config_template(&model, &transformer::EncodingRole::Document);
+  let metric = utils::config_law_metric(&law_index.book);
+  let parts = embedd_parts(&model, &template, phrase_of_law, law_index)
+    .unwrap_or_else(|x| panic!("{}: {}",x,files::law_index_to_filename(law_index)));
+  if parts.is_empty() {
+    return;
   }
-}
\ No newline at end of file
+  let mut catalogues: Vec<store::StoredCatalogue> = Vec::new();
+  for (dindex, dphrase, encd) in parts {
+    // Catalgue
+    let catalogue = catalogue_fabric(
+      dindex.book.pais.clone().to_lowercase(), 
+      dindex.book.instrumento.clone().to_lowercase(), 
+      dindex.titulo,
+      dindex.capitulo, 
+      dindex.articulo,
+      dindex.parte,
+      &dphrase, 
+      transformer::EmbeddingType::Total,
+      transformer::PoolingStrategy::Parts,
+      model.clone(),
+      template.clone(),
+      metric.clone(),
+      &Some(encd));
+    catalogues.push(store::StoredCatalogue::from_catalogue(&catalogue));
+  }
+  // The article embedded whole under another pooling is replaced by its parts, its text is saved again below
+  let whole = laws::LawIndex { parte: None, ..law_index.clone() };
+  store::store().delete(&whole)
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),files::law_index_to_filename(&whole)));
+  retire_catalogue(&whole);
+  // Save the article, the full text is kept for offsets and explanations, and all of its parts as one unit
+  store::store().put_unit(&catalogues, &Vec::from([(law_index.clone(), phrase_of_law.text.clone())]))
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),files::law_index_to_filename(law_index)));
+  explain::forget_article(law_index);
+  for stored in catalogues.iter() {
+    // Stage catalogue, it is searched once the whole book is published
+    stage_catalogue(catalogue_of_stored(stored));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, AtomicUsize};
+  use std::thread;
+  use std::time::Duration;
+
+  fn test_catalogue(book: &laws::LawBook, articulo: u16) -> Catalogue {
+    catalogue_fabric(book.pais.clone(), book.instrumento.clone(), Some(1), Some(1), Some(articulo), None,
+      &language::phrase_fabric(format!("articulo {}",articulo)),
+      EmbeddingType::Total, transformer::PoolingStrategy::Mean,
+      "test".to_string(), String::new(), transformer::DistanceMetric::Euclidean,
+      &Some((0..8).map(|x| ((articulo as usize * 7 + x) % 5) as f32).collect()))
+  }
+
+  // searches running while a book is staged and published see its old shard or the whole new one, nothing between
+  #[test]
+  fn parallel_searches_never_see_a_partial_shard() {
+    let book = laws::LawBook { pais: "test".to_string(), instrumento: "stress".to_string() };
+    let (before, after) = (40usize, 100usize);
+    publish_shard(&book, (0..before as u16).map(|x| test_catalogue(&book, x)).map(|x| (x.dindex.clone(), x)).collect());
+    let stop = Arc::new(AtomicBool::new(false));
+    let searches = Arc::new(AtomicUsize::new(0));
+    let readers = (0..4).map(|_| {
+      let (book, stop, searches) = (book.clone(), stop.clone(), searches.clone());
+      thread::spawn(move || {
+        let query = test_catalogue(&book, 0).dmeaning.embedding;
+        let mut published = false;
+        while !stop.load(Ordering::Relaxed) {
+          let count = rank_embedding_against_law_book(&query, &book).unwrap().len();
+          assert!(count == before || count == after, "a partial shard of {} catalogues",count);
+          assert!(!(published && count == before), "the old shard after the published one");
+          published |= count == after;
+          searches.fetch_add(1, Ordering::Relaxed);
+        }
+      })
+    }).collect::<Vec<thread::JoinHandle<()>>>();
+    for articulo in before as u16..after as u16 {
+      stage_catalogue(test_catalogue(&book, articulo));
+      thread::yield_now();
+    }
+    // the readers search the old shard with the whole book staged before it is published
+    let staged = searches.load(Ordering::Relaxed);
+    while searches.load(Ordering::Relaxed) < staged + 100 {
+      thread::sleep(Duration::from_millis(1));
+    }
+    publish_staged(&book);
+    let published = searches.load(Ordering::Relaxed);
+    while searches.load(Ordering::Relaxed) < published + 100 {
+      thread::sleep(Duration::from_millis(1));
+    }
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+      reader.join().unwrap();
+    }
+    assert_eq!(book_shard(&book).len(), after);
+  }
+}
@@ -0,0 +1,498 @@
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::archive;
+use crate::cache;
+use crate::catalogue;
+use crate::files;
+use crate::fsck;
+use crate::laws;
+use crate::language;
+use crate::matrix;
+use crate::quantization;
+use crate::snapshot;
+use crate::store;
+use crate::whitening;
+use crate::transformer;
+use crate::utils;
+
+const USAGE: &str = r#"usage: tsahdu_rs [command]
+  (no command)                                   launch the server
+  cache stats                                    print the embedding cache counters
+  cache prune [--older-than-days D] [--max-files N]
+                                                 remove embedding cache files from disk
+  parity [fixtures file]                         compare the torch and onnx embeddings of the fixture sentences
+  whiten <pais> <instrumento>                    fit and save the whitening of a book, used on the next load
+  bench [pais] [instrumento] [--queries N] [--metric M]
+                                                 time the per-item scoring loop against the matrix kernels
+  quantization [pais] [instrumento] [--k K] [--queries file] [--stored]
+                                                 recall of the int8 and product quantization against the f32 search
+  ingest <pais> <instrumento>                    read the text of law of a book, embed and store its catalogues
+  snapshot [pais instrumento]                    build the snapshot of a book (of every book if none is given)
+  stress [pais] [instrumento] [--threads N] [--rounds R]
+                                                 parallel searches while the book is loaded again R times
+  fsck [pais instrumento] [--repair reembed|delete|quarantine]
+                                                 check the reference, embedding and text files of a book (of every book)
+  migrate <folder|sqlite> <folder|sqlite> [pais instrumento]
+                                                 copy the catalogues and texts of a book (of every book) between stores
+  export <archive> [pais instrumento ...]        pack books (every book) with their model and a sha256 manifest into a .tar.gz
+  import <archive> [--replace]                   verify an archive against the local model and store its books"#;
+const PARITY_FIXTURES: &str = "resources/fixtures/parity.txt";
+const EVALUATION_FIXTURES: &str = "resources/fixtures/evaluation.txt";
+
+// Returns the value following a flag, --max-files 100 -> Some("100")
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+  args.iter().position(|x| x == flag).and_then(|x| args.get(x+1))
+}
+
+fn cache_command(args: &[String]) {
+  match args.first().map(|x| x.as_str()) {
+    Some("stats") => {
+      println!("{:?}",cache::stats());
+    }
+    Some("prune") => {
+      let max_age = flag_value(args, "--older-than-days")
+        .map(|x| Duration::from_secs(86400 * x.parse::<u64>().expect("--older-than-days must be a number")));
+      let max_files = flag_value(args, "--max-files")
+        .map(|x| x.parse::<usize>().expect("--max-files must be a number"));
+      if max_age.is_none() && max_files.is_none() {
+        println!("{}",USAGE);
+        return;
+      }
+      println!("Removed {} embedding cache files",cache::prune_disk(max_age, max_files));
+    }
+    _ => println!("{}",USAGE)
+  }
+}
+
+// Both backends must produce compatible vectors, the .enc files are shared
+fn parity_command(args: &[String]) {
+  let fixtures = args.first().map(|x| x.as_str()).unwrap_or(PARITY_FIXTURES);
+  let sentences = utils::lines_from_file(fixtures).unwrap_or_else(|_| panic!("Unable to read fixtures: {}",fixtures))
+    .into_iter().filter(|x| !x.trim().is_empty()).collect::<Vec<String>>();
+  let deviations = match transformer::backend_parity(&utils::config_model(), &sentences) {
+    Ok(deviations) => deviations,
+    Err(error) => {
+      println!("[Error]: parity requires both backends: {:?}",error);
+      return;
+    }
+  };
+  let tolerance = utils::config_onnx_parity_tolerance();
+  let mut failures : usize = 0;
+  for (sentence, deviation) in sentences.iter().zip(deviations.iter()) {
+    let passed = *deviation <= tolerance;
+    if !passed {
+      failures += 1;
+    }
+    println!("[{}] max deviation: {:.6} : <{}>",if passed {"ok"} else {"FAILED"},deviation,sentence);
+  }
+  println!("{} of {} sentences within tolerance {}",sentences.len()-failures,sentences.len(),tolerance);
+}
+
+// Fitted offline, the eigen decomposition of the covariance takes a while on large models
+fn whiten_command(args: &[String]) {
+  let book = match (args.first(), args.get(1)) {
+    (Some(pais), Some(instrumento)) => laws::LawBook {
+      pais: pais.to_lowercase(),
+      instrumento: instrumento.to_lowercase()
+    },
+    _ => {
+      println!("{}",USAGE);
+      return;
+    }
+  };
+  match whitening::fit_book(&book) {
+    Ok(Some(dwhitening)) => println!("Whitening of {}.{}: {} components over {} dimensions, saved to <{}>",
+      book.pais,book.instrumento,dwhitening.components.len(),dwhitening.mean.len(),files::whitening_filename(&book)),
+    Ok(None) => println!("[Error]: no embeddings found for {}.{}",book.pais,book.instrumento),
+    Err(error) => println!("[Error]: {}",error)
+  }
+}
+
+// The stored vectors of the book serve as queries, every path must agree with the per-item loop
+fn bench_command(args: &[String]) {
+  let book = laws::LawBook {
+    pais: args.first().filter(|x| !x.starts_with("--")).cloned().unwrap_or("colombia".to_string()),
+    instrumento: args.get(1).filter(|x| !x.starts_with("--")).cloned().unwrap_or("constitucion".to_string())
+  };
+  let count = flag_value(args, "--queries").map(|x| x.parse::<usize>().expect("--queries must be a number")).unwrap_or(32);
+  let metric = flag_value(args, "--metric").map(|x| transformer::DistanceMetric::from_name(x).expect("unknown --metric"))
+    .unwrap_or(utils::config_law_metric(&book));
+  catalogue::load_catalogues_memory(false);
+  let model = match catalogue::book_model(&book) {
+    Some(model) => model,
+    None => {
+      println!("[Error]: no catalogues loaded for {}.{}",book.pais,book.instrumento);
+      return;
+    }
+  };
+  let vectors = match catalogue::book_vectors(&book, &model) {
+    Ok(vectors) => vectors,
+    Err(error) => {
+      println!("[Error]: {}",error);
+      return;
+    }
+  };
+  let queries = vectors.iter().map(|x| x.1.clone()).take(count).collect::<Vec<Vec<f32>>>();
+  // per-item loop, as compare_embedding_against_law_book used to score
+  let now = Instant::now();
+  let baseline = queries.iter().map(|query| {
+    let mut scores = vectors.iter()
+      .map(|(dindex,vector)| (dindex.clone(), transformer::embeddings_vectors_distance(&metric, query, vector)))
+      .collect::<Vec<(laws::LawIndex,f32)>>();
+    scores.sort_by(|a,b| a.1.total_cmp(&b.1));
+    scores
+  }).collect::<Vec<Vec<(laws::LawIndex,f32)>>>();
+  let loop_time = now.elapsed();
+  let now = Instant::now();
+  let dmatrix = match matrix::book_matrix(&book, &model) {
+    Ok(dmatrix) => dmatrix,
+    Err(error) => {
+      println!("[Error]: {}",error);
+      return;
+    }
+  };
+  let build_time = now.elapsed();
+  let now = Instant::now();
+  let single = queries.iter().map(|x| dmatrix.rank(&metric, x)).collect::<Vec<Vec<(laws::LawIndex,f32)>>>();
+  let single_time = now.elapsed();
+  let now = Instant::now();
+  let batch = dmatrix.score_batch(&metric, &queries);
+  let batch_time = now.elapsed();
+  println!("{} queries against {} catalogues of {} dimensions, metric {}",
+    queries.len(),dmatrix.len(),dmatrix.vectors.ncols(),metric.name());
+  println!("per-item loop  : {:?}, {} ranks",loop_time,baseline.len());
+  println!("matrix build   : {:?}",build_time);
+  // the kernels are checked against the per-item loop by the tests of matrix.rs, here they are only timed
+  println!("matrix single  : {:?} ({:.1}x), {} ranks",single_time,
+    loop_time.as_secs_f64() / single_time.as_secs_f64().max(1e-9),single.len());
+  println!("matrix batch   : {:?} ({:.1}x), {} ranks",batch_time,
+    loop_time.as_secs_f64() / batch_time.as_secs_f64().max(1e-9),batch.len());
+}
+
+// Share of the f32 top k found in a top k
+fn recall_at(baseline: &[(laws::LawIndex,f32)], ranked: &[(laws::LawIndex,f32)], k: usize) -> f32 {
+  let expected = baseline.iter().take(k).map(|x| &x.0).collect::<Vec<&laws::LawIndex>>();
+  if expected.is_empty() {
+    return 1.0;
+  }
+  ranked.iter().take(k).filter(|x| expected.contains(&&x.0)).count() as f32 / expected.len() as f32
+}
+// Queries of the evaluation set (or the stored vectors of the book) searched with and without quantization
+fn quantization_command(args: &[String]) {
+  let book = laws::LawBook {
+    pais: args.first().filter(|x| !x.starts_with("--")).cloned().unwrap_or("colombia".to_string()),
+    instrumento: args.get(1).filter(|x| !x.starts_with("--")).cloned().unwrap_or("constitucion".to_string())
+  };
+  let k = flag_value(args, "--k").map(|x| x.parse::<usize>().expect("--k must be a number")).unwrap_or(utils::config_return_count());
+  let metric = utils::config_law_metric(&book);
+  catalogue::load_catalogues_memory(false);
+  let model = match catalogue::book_model(&book) {
+    Some(model) => model,
+    None => {
+      println!("[Error]: no catalogues loaded for {}.{}",book.pais,book.instrumento);
+      return;
+    }
+  };
+  let queries = if args.iter().any(|x| x == "--stored") {
+    match catalogue::book_vectors(&book, &model) {
+      Ok(vectors) => vectors.into_iter().map(|x| x.1).collect::<Vec<Vec<f32>>>(),
+      Err(error) => {
+        println!("[Error]: {}",error);
+        return;
+      }
+    }
+  } else {
+    let fixtures = flag_value(args, "--queries").map(|x| x.as_str()).unwrap_or(EVALUATION_FIXTURES);
+    utils::lines_from_file(fixtures).unwrap_or_else(|_| panic!("Unable to read evaluation set: {}",fixtures))
+      .into_iter().filter(|x| !x.trim().is_empty())
+      .filter_map(|x| transformer::transform_phrase(&model, &transformer::EncodingRole::Query, &language::phrase_fabric(x))
+        .unwrap_or_else(|_| panic!("{}", utils::error_message("E0007"))))
+      .map(|x| whitening::whiten(&book, &model, &x)).collect::<Vec<Vec<f32>>>()
+  };
+  let dmatrix = match matrix::BookMatrix::fabric(&book, &model) {
+    Ok(dmatrix) => dmatrix,
+    Err(error) => {
+      println!("[Error]: {}",error);
+      return;
+    }
+  };
+  let baseline = queries.iter().map(|x| dmatrix.rank(&metric, x)).collect::<Vec<Vec<(laws::LawIndex,f32)>>>();
+  println!("{} queries against {} catalogues, metric {}, recall at {}",queries.len(),dmatrix.len(),metric.name(),k);
+  println!("f32     : {} bytes per vector",dmatrix.vectors.ncols() * std::mem::size_of::<f32>());
+  for dquantization in [quantization::Quantization::Int8, quantization::Quantization::Product] {
+    let now = Instant::now();
+    let quantized = match quantization::QuantizedBook::fabric(&book, &model, &dquantization) {
+      Ok(quantized) => quantized,
+      Err(error) => {
+        println!("[Error]: {}",error);
+        return;
+      }
+    };
+    let build_time = now.elapsed();
+    let mut approximate: f32 = 0.0;
+    let mut rescored: f32 = 0.0;
+    for (query, expected) in queries.iter().zip(baseline.iter()) {
+      let ranked = quantized.rank(&metric, query);
+      approximate += recall_at(expected, &ranked, k);
+      rescored += recall_at(expected, &quantization::rescore(ranked, &metric, query, &model, utils::config_rescore_candidates()), k);
+    }
+    let n = queries.len().max(1) as f32;
+    println!("{:<8}: {} bytes per vector, built in {:?}, recall {:.3}, rescored ({} candidates) {:.3}",
+      format!("{:?}",dquantization).to_lowercase(),quantized.bytes_per_vector(),build_time,
+      approximate / n,utils::config_rescore_candidates(),rescored / n);
+  }
+}
+
+fn ingest_command(args: &[String]) {
+  let book = match (args.first(), args.get(1)) {
+    (Some(pais), Some(instrumento)) => laws::LawBook {
+      pais: pais.to_lowercase(),
+      instrumento: instrumento.to_lowercase()
+    },
+    _ => {
+      println!("{}",USAGE);
+      return;
+    }
+  };
+  let now = Instant::now();
+  laws::ingest(&book);
+  println!("Ingested {}.{}: {} catalogues in {:?}",book.pais,book.instrumento,catalogue::book_shard(&book).len(),now.elapsed());
+}
+
+fn snapshot_command(args: &[String]) {
+  if utils::config_store() != store::StoreKind::Folder {
+    println!("Snapshots are only built for the folder store, the {} store is read whole",store::store().name());
+    return;
+  }
+  let books = match (args.first(), args.get(1)) {
+    (Some(pais), Some(instrumento)) => Vec::from([laws::LawBook {
+      pais: pais.to_lowercase(),
+      instrumento: instrumento.to_lowercase()
+    }]),
+    _ => catalogue::reference_books()
+  };
+  for book in books {
+    let now = Instant::now();
+    let count = snapshot::build_snapshot(&book);
+    println!("Snapshot of {}.{}: {} catalogues in {:?}, <{}>",book.pais,book.instrumento,count,now.elapsed(),files::snapshot_filename(&book));
+  }
+}
+
+// Searches of the stored vectors of a book, each thread until stop is set, returns the latencies
+// and the count of searches that saw a partially loaded book
+fn stress_searches(book: &laws::LawBook, model: &str, queries: Arc<Vec<Vec<f32>>>, expected: usize, threads: usize, stop: Arc<AtomicBool>) -> Vec<thread::JoinHandle<(Vec<Duration>,usize)>> {
+  (0..threads).map(|offset| {
+    let (book, model, queries, stop) = (book.clone(), model.to_string(), queries.clone(), stop.clone());
+    thread::spawn(move || {
+      let mut latencies: Vec<Duration> = Vec::new();
+      let mut torn = 0;
+      let mut query = offset;
+      while !stop.load(Ordering::Relaxed) {
+        let embedding = transformer::Embedding {
+          vector: Some(queries[query % queries.len()].clone()),
+          etype: transformer::EmbeddingType::Total,
+          pooling: utils::config_pooling(),
+          model: model.clone(),
+          template: utils::config_template(&model, &transformer::EncodingRole::Query),
+          metric: utils::config_law_metric(&book)
+        };
+        let now = Instant::now();
+        let ranked = catalogue::rank_embedding_against_law_book(&embedding, &book);
+        latencies.push(now.elapsed());
+        // a failed search counts as a partial book
+        if ranked.map(|x| x.len()).unwrap_or(0) != expected {
+          torn += 1;
+        }
+        query += threads;
+      }
+      (latencies, torn)
+    })
+  }).collect()
+}
+fn percentile(latencies: &mut [Duration], p: f32) -> Duration {
+  latencies.sort();
+  latencies.get(((latencies.len() as f32 * p) as usize).min(latencies.len().max(1) - 1)).copied().unwrap_or_default()
+}
+// Latency of parallel searches alone, then while the book is loaded again (from its snapshot or its files)
+fn stress_command(args: &[String]) {
+  let book = laws::LawBook {
+    pais: args.first().filter(|x| !x.starts_with("--")).cloned().unwrap_or("colombia".to_string()),
+    instrumento: args.get(1).filter(|x| !x.starts_with("--")).cloned().unwrap_or("constitucion".to_string())
+  };
+  let threads = flag_value(args, "--threads").map(|x| x.parse::<usize>().expect("--threads must be a number")).unwrap_or(8);
+  let rounds = flag_value(args, "--rounds").map(|x| x.parse::<usize>().expect("--rounds must be a number")).unwrap_or(5);
+  catalogue::load_catalogues_memory(false);
+  let model = match catalogue::book_model(&book) {
+    Some(model) => model,
+    None => {
+      println!("[Error]: no catalogues loaded for {}.{}",book.pais,book.instrumento);
+      return;
+    }
+  };
+  let queries = match catalogue::book_vectors(&book, &model) {
+    Ok(vectors) => Arc::new(vectors.into_iter().map(|x| x.1).take(256).collect::<Vec<Vec<f32>>>()),
+    Err(error) => {
+      println!("[Error]: {}",error);
+      return;
+    }
+  };
+  if queries.is_empty() {
+    println!("[Error]: {}.{} has no stored vectors to search with",book.pais,book.instrumento);
+    return;
+  }
+  // the first search builds the matrix, it is not part of either run, and gives the size of the whole book
+  let expected = match catalogue::rank_embedding_against_law_book(&transformer::Embedding {
+    vector: queries.first().cloned(),
+    etype: transformer::EmbeddingType::Total,
+    pooling: utils::config_pooling(),
+    model: model.clone(),
+    template: utils::config_template(&model, &transformer::EncodingRole::Query),
+    metric: utils::config_law_metric(&book)
+  }, &book) {
+    Ok(ranked) => ranked.len(),
+    Err(error) => {
+      println!("[Error]: {}",error);
+      return;
+    }
+  };
+  let report = |name: &str, handles: Vec<thread::JoinHandle<(Vec<Duration>,usize)>>, elapsed: Duration| {
+    let (mut latencies, torn) = handles.into_iter().map(|x| x.join().unwrap())
+      .fold((Vec::new(), 0), |(mut a, b), (x, y)| { a.extend(x); (a, b + y) });
+    println!("{:<16}: {} searches in {:?} ({:.0}/s), p50 {:?}, p99 {:?}, max {:?}, partial books seen {}",
+      name,latencies.len(),elapsed,latencies.len() as f64 / elapsed.as_secs_f64().max(1e-9),
+      percentile(&mut latencies, 0.5),percentile(&mut latencies, 0.99),percentile(&mut latencies, 1.0),torn);
+  };
+  let stop = Arc::new(AtomicBool::new(false));
+  let now = Instant::now();
+  let handles = stress_searches(&book, &model, queries.clone(), expected, threads, stop.clone());
+  thread::sleep(Duration::from_secs(2));
+  stop.store(true, Ordering::Relaxed);
+  report("searches alone", handles, now.elapsed());
+  let stop = Arc::new(AtomicBool::new(false));
+  let now = Instant::now();
+  let handles = stress_searches(&book, &model, queries.clone(), expected, threads, stop.clone());
+  let mut loads: Vec<Duration> = Vec::new();
+  for _ in 0..rounds {
+    let load = Instant::now();
+    catalogue::load_catalogues_memory(true);
+    loads.push(load.elapsed());
+  }
+  stop.store(true, Ordering::Relaxed);
+  report("during loading", handles, now.elapsed());
+  println!("{} loads of {}.{} with {} threads searching, slowest {:?}",
+    rounds,book.pais,book.instrumento,threads,percentile(&mut loads, 1.0));
+}
+
+fn fsck_command(args: &[String]) {
+  let books = match (args.first().filter(|x| !x.starts_with("--")), args.get(1).filter(|x| !x.starts_with("--"))) {
+    (Some(pais), Some(instrumento)) => Vec::from([laws::LawBook {
+      pais: pais.to_lowercase(),
+      instrumento: instrumento.to_lowercase()
+    }]),
+    _ => catalogue::reference_books()
+  };
+  let repair = flag_value(args, "--repair")
+    .map(|x| fsck::Repair::from_name(x).expect("--repair must be one of reembed, delete, quarantine"));
+  let (mut found, mut repaired) = (0, 0);
+  for book in books {
+    let findings = fsck::check_book(&book);
+    println!("{}.{}: {} issues",book.pais,book.instrumento,findings.len());
+    for finding in findings.iter() {
+      found += 1;
+      match &repair {
+        None => println!("  {:?} <{}>",finding.issue,finding.path),
+        Some(repair) => match fsck::repair(finding, repair) {
+          Ok(()) => {
+            repaired += 1;
+            println!("  {:?} <{}>: {:?} done",finding.issue,finding.path,repair);
+          }
+          Err(reason) => println!("  {:?} <{}>: {:?} failed, {}",finding.issue,finding.path,repair,reason)
+        }
+      }
+    }
+  }
+  match repair {
+    None => println!("{} issues found, run again with --repair to fix them",found),
+    Some(_) => println!("{} issues found, {} repaired",found,repaired)
+  }
+}
+
+fn migrate_command(args: &[String]) {
+  let (from, to) = match (args.first().and_then(|x| store::StoreKind::from_name(x)), args.get(1).and_then(|x| store::StoreKind::from_name(x))) {
+    (Some(from), Some(to)) if from != to => (from, to),
+    _ => {
+      println!("{}",USAGE);
+      return;
+    }
+  };
+  let books = match (args.get(2), args.get(3)) {
+    (Some(pais), Some(instrumento)) => Vec::from([laws::LawBook {
+      pais: pais.to_lowercase(),
+      instrumento: instrumento.to_lowercase()
+    }]),
+    _ => Vec::new()
+  };
+  match store::migrate(&*store::open_store(&from), &*store::open_store(&to), &books) {
+    Ok(count) => println!("{} catalogues copied from the {:?} store to the {:?} store",count,from,to),
+    Err(reason) => println!("Migration from the {:?} store to the {:?} store failed: {}",from,to,reason)
+  }
+}
+
+fn export_command(args: &[String]) {
+  let archive = match args.first() {
+    Some(archive) => archive,
+    None => {
+      println!("{}",USAGE);
+      return;
+    }
+  };
+  let books = args[1..].chunks_exact(2).map(|x| laws::LawBook {
+    pais: x[0].to_lowercase(),
+    instrumento: x[1].to_lowercase()
+  }).collect::<Vec<laws::LawBook>>();
+  match archive::export(archive, &books) {
+    Ok(count) => println!("{} catalogues exported to <{}>",count,archive),
+    Err(reason) => println!("Export to <{}> failed: {}",archive,reason)
+  }
+}
+
+fn import_command(args: &[String]) {
+  let archive = match args.first().filter(|x| !x.starts_with("--")) {
+    Some(archive) => archive,
+    None => {
+      println!("{}",USAGE);
+      return;
+    }
+  };
+  match archive::import(archive, args.iter().any(|x| x == "--replace")) {
+    Ok(count) => println!("{} catalogues imported from <{}>, they are served after the next reload",count,archive),
+    Err(reason) => println!("Import of <{}> refused: {}",archive,reason)
+  }
+}
+
+// Runs a command line tool if one was requested, returns false to launch the server
+pub fn dispatch() -> bool {
+  let args = std::env::args().skip(1).collect::<Vec<String>>();
+  if args.is_empty() {
+    return false;
+  }
+  match args[0].as_str() {
+    "cache" => cache_command(&args[1..]),
+    "parity" => parity_command(&args[1..]),
+    "whiten" => whiten_command(&args[1..]),
+    "bench" => bench_command(&args[1..]),
+    "quantization" => quantization_command(&args[1..]),
+    "ingest" => ingest_command(&args[1..]),
+    "snapshot" => snapshot_command(&args[1..]),
+    "stress" => stress_command(&args[1..]),
+    "fsck" => fsck_command(&args[1..]),
+    "migrate" => migrate_command(&args[1..]),
+    "export" => export_command(&args[1..]),
+    "import" => import_command(&args[1..]),
+    _ => println!("{}",USAGE)
+  }
+  true
+}
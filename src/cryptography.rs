@@ -2,4 +2,7 @@ use sha2::{Digest};
 
 pub fn sha256_digest<S: Into<String>>(text: S) -> String {
   hex::encode(sha2::Sha256::digest(text.into().as_bytes()))
-}
\ No newline at end of file
+}
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+  hex::encode(sha2::Sha256::digest(bytes))
+}
@@ -0,0 +1,91 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::language;
+use crate::laws;
+use crate::mathematics;
+use crate::store;
+use crate::transformer;
+use crate::utils;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Highlight {
+  pub span: (usize,usize),
+  pub similarity: f32,
+  pub text: String
+}
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SentenceOfLaw {
+  pub span: (usize,usize),
+  pub text: String,
+  pub vector: Vec<f32>
+}
+
+lazy_static! {
+  static ref EXPLANATIONS_MEMORY: Mutex<HashMap<laws::LawIndex,Vec<SentenceOfLaw>>> = Mutex::new(
+    HashMap::new()
+  );
+}
+
+// Sentences of an article with their embeddings (by the model of its book, as documents), cached per article (parte None)
+pub fn article_sentences(model: &str, dindex: &laws::LawIndex) -> Vec<SentenceOfLaw> {
+  let mut article_index = dindex.clone();
+  article_index.parte = None;
+  if let Some(sentences) = EXPLANATIONS_MEMORY.lock().unwrap().get(&article_index) {
+    return sentences.clone();
+  }
+  let article_text = match store::store().get_text(&article_index).ok().flatten() {
+    Some(text) => text,
+    None => return Vec::new()
+  };
+  let ranges = language::split_sentences(&article_text);
+  if ranges.is_empty() {
+    return Vec::new();
+  }
+  let texts = ranges.iter().map(|x| article_text[x.clone()].to_string()).collect::<Vec<String>>();
+  // without embeddings the hit is returned without highlights, and nothing is remembered
+  let vectors = match transformer::transform_templated(model, &utils::config_template(model, &transformer::EncodingRole::Document), &texts) {
+    Ok(vectors) => vectors,
+    Err(error) => {
+      println!("[Warning]: explain, {}",error);
+      return Vec::new();
+    }
+  };
+  let sentences = ranges.iter().zip(texts).zip(vectors)
+    .map(|((range, text), vector)| SentenceOfLaw {
+      span: (article_text[..range.start].chars().count(), article_text[..range.end].chars().count()),
+      text,
+      vector
+    }).collect::<Vec<SentenceOfLaw>>();
+  EXPLANATIONS_MEMORY.lock().unwrap().insert(article_index, sentences.clone());
+  sentences
+}
+
+// The sentences of an article closest to the query, with their character spans in the article
+pub fn explain_hit(model: &str, query: &[f32], dindex: &laws::LawIndex, count: usize) -> Vec<Highlight> {
+  let mut highlights = article_sentences(model, dindex).into_iter()
+    .map(|x| Highlight {
+      span: x.span,
+      similarity: mathematics::vector_cosine_distance::<f32>(query, &x.vector),
+      text: x.text
+    })
+    // an empty or zero vector sentence has no similarity to the query
+    .filter(|x| !x.similarity.is_nan())
+    .collect::<Vec<Highlight>>();
+  highlights.sort_by(|a,b| b.similarity.total_cmp(&a.similarity));
+  highlights.truncate(count);
+  highlights
+}
+
+// Drops the cached sentences of an article, they are embedded again on the next explanation
+pub fn forget_article(dindex: &laws::LawIndex) {
+  let mut article_index = dindex.clone();
+  article_index.parte = None;
+  EXPLANATIONS_MEMORY.lock().unwrap().remove(&article_index);
+}
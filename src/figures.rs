@@ -2,11 +2,14 @@ use plotly::{common::{color::Rgb, Title}, Plot, Layout};
 
 use crate::transformer;
 use crate::mathematics;
+use crate::utils;
 
+// Kept to draw embeddings by hand, nothing calls it
+#[allow(dead_code)]
 pub fn plot_example() {
   // let data_y = Vec::from([1,2,3,4,5]);
-  let data_y_1 = transformer::transform_sentence(&"love".to_string());
-  let data_y_2 = transformer::transform_sentence(&"fear".to_string());
+  let data_y_1 = transformer::transform_sentence(&utils::config_model(), "love");
+  let data_y_2 = transformer::transform_sentence(&utils::config_model(), "fear");
 
   println!("data_y_1.len(): {:?}",data_y_1.len());
   println!("data_y_2.len(): {:?}",data_y_2.len());
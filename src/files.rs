@@ -1,35 +1,195 @@
 use std::fs;
-use std::fs::create_dir_all;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir::DirEntry;
+use rocket::serde::{Serialize, Deserialize};
 
-use crate::language;
 use crate::transformer;
 use crate::catalogue;
 use crate::utils;
 use crate::laws;
+use crate::cryptography;
+
+// Version of the reference files written; files without one are of the first, hand written,
+// format (every value a string, "-1" for the indices a catalogue lacks) and are read as version 1
+pub const REFERENCE_VERSION: u16 = 2;
+
+// LawIndex, embedding type, pooling, model, template and metric of a catalogue
+pub type Reference = (laws::LawIndex, transformer::EmbeddingType, transformer::PoolingStrategy, String, String, transformer::DistanceMetric);
+
+// Schema of a reference file: the catalogue it describes and how its embedding was made
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReferenceFile {
+  pub version: u16,
+  pub pais: String,
+  pub instrumento: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub titulo: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub capitulo: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub articulo: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub parte: Option<u16>,
+  pub etype: transformer::EmbeddingType,
+  pub pooling: transformer::PoolingStrategy,
+  // model id the embedding was made with
+  pub model: String,
+  pub template: String,
+  // by name, minkowski-3 is the Minkowski distance of order 3
+  pub metric: String,
+  // hash of the text embedded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<String>,
+  // seconds since the epoch of the first and of the last write
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub created: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub updated: Option<u64>
+}
+// Reference files of version 1
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LegacyReferenceFile {
+  pais: String,
+  instrumento: String,
+  titulo: String,
+  capitulo: String,
+  articulo: String,
+  parte: String,
+  etype: String,
+  pooling: Option<String>,
+  model: Option<String>,
+  template: Option<String>,
+  metric: Option<String>,
+  sha256: Option<String>
+}
+
+fn legacy_index(name: &str, value: &str) -> Result<Option<u16>, String> {
+  let index = value.parse::<i16>().map_err(|_| format!("{} is not an index: {}",name,value))?;
+  if index < 0 {
+    return Ok(None);
+  }
+  Ok(Some(index as u16))
+}
+
+impl LegacyReferenceFile {
+  fn upgrade(self) -> Result<ReferenceFile, String> {
+    let etype = match self.etype.as_str() {
+      "Total" => transformer::EmbeddingType::Total,
+      "Average" => transformer::EmbeddingType::Average,
+      x => return Err(format!("unknown etype: {}",x))
+    };
+    // references written before pooling strategies were averaged
+    let pooling = match self.pooling {
+      None => transformer::PoolingStrategy::Mean,
+      Some(x) => transformer::PoolingStrategy::from_name(&x).ok_or(format!("unknown pooling: {}",x))?
+    };
+    Ok(ReferenceFile {
+      version: 1,
+      titulo: legacy_index("titulo", &self.titulo)?,
+      capitulo: legacy_index("capitulo", &self.capitulo)?,
+      articulo: legacy_index("articulo", &self.articulo)?,
+      parte: legacy_index("parte", &self.parte)?,
+      pais: self.pais,
+      instrumento: self.instrumento,
+      etype,
+      pooling,
+      // references written before serving several models were embedded by the configured one
+      model: self.model.unwrap_or(utils::config_model()),
+      // and without a template
+      template: self.template.unwrap_or("{}".to_string()),
+      // and searched by euclidean distance
+      metric: self.metric.unwrap_or(transformer::DistanceMetric::Euclidean.name()),
+      sha256: self.sha256,
+      created: None,
+      updated: None
+    })
+  }
+}
+
+impl ReferenceFile {
+  pub fn from_catalogue(doc: &catalogue::Catalogue) -> ReferenceFile {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).ok();
+    ReferenceFile {
+      version: REFERENCE_VERSION,
+      pais: doc.dindex.book.pais.clone(),
+      instrumento: doc.dindex.book.instrumento.clone(),
+      titulo: doc.dindex.titulo,
+      capitulo: doc.dindex.capitulo,
+      articulo: doc.dindex.articulo,
+      parte: doc.dindex.parte,
+      etype: doc.dmeaning.embedding.etype.clone(),
+      pooling: doc.dmeaning.embedding.pooling.clone(),
+      model: doc.dmeaning.embedding.model.clone(),
+      template: doc.dmeaning.embedding.template.clone(),
+      metric: doc.dmeaning.embedding.metric.name(),
+      sha256: Some(cryptography::sha256_digest(doc.dmeaning.phrase.text.as_str())),
+      created: now,
+      updated: now
+    }
+  }
+  // Reads the content of a reference file of any version up to REFERENCE_VERSION
+  pub fn parse(content: &str) -> Result<ReferenceFile, String> {
+    let value = content.parse::<toml::Value>().map_err(|x| x.to_string())?;
+    let reference = match value.get("version") {
+      None => value.try_into::<LegacyReferenceFile>().map_err(|x| x.to_string())?.upgrade()?,
+      Some(_) => value.try_into::<ReferenceFile>().map_err(|x| x.to_string())?
+    };
+    if reference.version > REFERENCE_VERSION {
+      return Err(format!("version {} is newer than the supported {}",reference.version,REFERENCE_VERSION));
+    }
+    reference.metric()?;
+    Ok(reference)
+  }
+  pub fn law_index(&self) -> laws::LawIndex {
+    laws::LawIndex {
+      book: laws::LawBook {
+        pais: self.pais.clone(),
+        instrumento: self.instrumento.clone()
+      },
+      titulo: self.titulo,
+      capitulo: self.capitulo,
+      articulo: self.articulo,
+      parte: self.parte
+    }
+  }
+  pub fn metric(&self) -> Result<transformer::DistanceMetric, String> {
+    transformer::DistanceMetric::from_name(&self.metric).ok_or(format!("unknown metric: {}",self.metric))
+  }
+}
 
 // Folders paths
+// Every file of a Book lives in the same folder
+pub fn book_foldername(book: &laws::LawBook) -> String {
+  format!("{}{}.{}/",utils::config_reference_folder(),book.pais,book.instrumento)
+}
 pub fn reference_foldername(dindex: &laws::LawIndex) -> String {
-  format!("{}{}.{}/",utils::config_reference_folder(),dindex.book.pais,dindex.book.instrumento)
+  book_foldername(&dindex.book)
 }
 pub fn embeddings_foldername(dindex: &laws::LawIndex) -> String {
-  format!("{}{}.{}/",utils::config_reference_folder(),dindex.book.pais,dindex.book.instrumento)
+  book_foldername(&dindex.book)
 }
 pub fn file_of_law_foldername(dindex: &laws::LawIndex) -> String {
-  format!("{}{}.{}/",utils::config_reference_folder(),dindex.book.pais,dindex.book.instrumento)
+  book_foldername(&dindex.book)
 }
 pub fn book_of_law_foldername() -> String {
-  format!("{}",utils::config_laws_folder())
+  utils::config_laws_folder().to_string()
 }
 
 // File names
 pub fn law_index_to_filename(dindex: &laws::LawIndex) -> String {
   format!("{}.{}{}{}{}{}",
     dindex.book.pais,dindex.book.instrumento,
-    if dindex.titulo.is_none() {"".to_string()}  else {format!(".titulo-{}",dindex.titulo.unwrap()).to_string()},
-    if dindex.capitulo.is_none() {"".to_string()}  else {format!(".capitulo-{}",dindex.capitulo.unwrap()).to_string()},
-    if dindex.articulo.is_none() {"".to_string()}  else {format!(".articulo-{}",dindex.articulo.unwrap()).to_string()},
-    if dindex.parte.is_none() {"".to_string()}  else {format!(".parte-{}",dindex.parte.unwrap()).to_string()})
+    dindex.titulo.map(|x| format!(".titulo-{}",x)).unwrap_or_default(),
+    dindex.capitulo.map(|x| format!(".capitulo-{}",x)).unwrap_or_default(),
+    dindex.articulo.map(|x| format!(".articulo-{}",x)).unwrap_or_default(),
+    dindex.parte.map(|x| format!(".parte-{}",x)).unwrap_or_default())
 }
 pub fn reference_filename(dindex: &laws::LawIndex) -> String {
   format!("{}{}{}",reference_foldername(dindex),&law_index_to_filename(dindex),utils::config_reference_extension())
@@ -40,6 +200,30 @@ pub fn embeddings_filename(dindex: &laws::LawIndex) -> String {
 pub fn file_of_law_filename(dindex: &laws::LawIndex) -> String {
   format!("{}{}{}",file_of_law_foldername(dindex),law_index_to_filename(dindex),utils::config_law_extension())
 }
+pub fn whitening_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",book_foldername(book),book.pais,book.instrumento,utils::config_whitening_extension())
+}
+// Next to the folder of the Book, writing them does not change the folder they describe
+pub fn snapshot_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",utils::config_reference_folder(),book.pais,book.instrumento,utils::config_snapshot_extension())
+}
+pub fn manifest_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",utils::config_reference_folder(),book.pais,book.instrumento,utils::config_manifest_extension())
+}
+pub fn journal_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",book_foldername(book),book.pais,book.instrumento,utils::config_journal_extension())
+}
+// Where a Book is written whole before it replaces its folder, beside reference_folder so no walk of it finds the Book twice
+pub fn staging_foldername(book: &laws::LawBook) -> String {
+  format!("{}.staging/{}.{}/",utils::config_reference_folder().trim_end_matches('/'),book.pais,book.instrumento)
+}
+// Next to the folder of the Book, it may be locked before the folder exists
+pub fn lock_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",utils::config_reference_folder(),book.pais,book.instrumento,utils::config_lock_extension())
+}
+pub fn partial_filename(filename: &str) -> String {
+  format!("{}{}",filename,utils::config_partial_extension())
+}
 pub fn book_of_law_filename(book: &laws::LawBook) -> String {
   format!("{}{}.{}{}",
     book_of_law_foldername(),
@@ -47,64 +231,115 @@ pub fn book_of_law_filename(book: &laws::LawBook) -> String {
     book.instrumento,
     utils::config_law_extension())
 }
+pub fn law_config_filename(book: &laws::LawBook) -> String {
+  format!("{}{}.{}{}",book_of_law_foldername(),book.pais,book.instrumento,utils::config_law_config_extension())
+}
 
 
 // Files Readings
-pub fn read_reference_file(filepath: &DirEntry) -> (laws::LawIndex, transformer::EmbeddingType) {
-  let filecontent = utils::read_config_file(filepath.path().as_os_str().to_str().unwrap());
-  let titulo = filecontent["titulo"].parse::<i16>().unwrap();
-  let capitulo = filecontent["capitulo"].parse::<i16>().unwrap();
-  let articulo = filecontent["articulo"].parse::<i16>().unwrap();
-  let parte = filecontent["parte"].parse::<i16>().unwrap();
-  let law_index = laws::LawIndex { 
-    book: laws::LawBook {
-    pais:filecontent["pais"].clone(),
-    instrumento:filecontent["instrumento"].clone()
-    }, 
-    titulo: if titulo<0 { None } else { Some(titulo.try_into().unwrap()) }, 
-    capitulo: if capitulo<0 { None } else { Some(capitulo.try_into().unwrap()) }, 
-    articulo: if articulo<0 { None } else { Some(articulo.try_into().unwrap()) }, 
-    parte: if parte<0 { None } else { Some(parte.try_into().unwrap()) }
-  };
-  assert!(filecontent["etype"].as_str() == "Total" || filecontent["etype"].as_str() == "Average");
-  let etype = if filecontent["etype"].as_str() == "Total" 
-    {transformer::EmbeddingType::Total} else {transformer::EmbeddingType::Average};
-  return (law_index, etype);
+pub fn read_reference_file(filepath: &DirEntry) -> Result<Reference, String> {
+  read_reference_path(filepath.path().as_os_str().to_str().unwrap_or_default())
+}
+pub fn read_reference_path(filepath: &str) -> Result<Reference, String> {
+  let reference = read_reference_schema(filepath)?;
+  let metric = reference.metric().map_err(|x| format!("{}: {}: {}",utils::error_message("E0018"),filepath,x))?;
+  let law_index = reference.law_index();
+  Ok((law_index, reference.etype, reference.pooling, reference.model, reference.template, metric))
+}
+// Reference file as written, of any version
+pub fn read_reference_schema(filepath: &str) -> Result<ReferenceFile, String> {
+  let filecontent = fs::read_to_string(filepath)
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0018"),filepath,x))?;
+  ReferenceFile::parse(&filecontent)
+    .map_err(|x| format!("{}: {}: {}",utils::error_message("E0018"),filepath,x))
 }
 pub fn read_law_book(book: &laws::LawBook) -> String {
   fs::read_to_string(book_of_law_filename(book))
-    .expect(format!("{} : {}",utils::error_message("E0011").as_str(),book_of_law_filename(book)).as_str())
+    .unwrap_or_else(|_| panic!("{} : {}",utils::error_message("E0011").as_str(),book_of_law_filename(book)))
 }
 pub fn read_phrase_of_law(dindex: &laws::LawIndex) -> String {
-  fs::read_to_string(&file_of_law_filename(dindex))
-    .expect(format!("{} : {}",utils::error_message("E0002").as_str(),&file_of_law_filename(dindex)).as_str())
+  fs::read_to_string(file_of_law_filename(dindex))
+    .unwrap_or_else(|_| panic!("{} : {}",utils::error_message("E0002").as_str(),&file_of_law_filename(dindex)))
 }
 // Files Writing
-pub fn write_file_of_law(phrase_of_law: &language::Phrase, dindex: &laws::LawIndex) {
-  create_dir_all(file_of_law_foldername(dindex)).unwrap();
-  fs::write(file_of_law_filename(dindex), phrase_of_law.text.clone())
-    .expect(format!("{}{}",utils::error_message("E0012").as_str(),file_of_law_filename(dindex)).as_str());
-}
-pub fn write_reference_file(doc: &catalogue::Catalogue) {
-  fs::write(reference_filename(&doc.dindex),format!(
-r#"pais = "{}"
-instrumento = "{}"
-titulo = "{}"
-capitulo = "{}"
-articulo = "{}"
-parte = "{}"
-etype = "{:?}""#,
-  doc.dindex.book.pais,
-  doc.dindex.book.instrumento,
-  if doc.dindex.titulo.is_none() {"-1".to_string()}  else {format!("{}",doc.dindex.titulo.unwrap()).to_string()},
-  if doc.dindex.capitulo.is_none() {"-1".to_string()}  else {format!("{}",doc.dindex.capitulo.unwrap()).to_string()},
-  if doc.dindex.articulo.is_none() {"-1".to_string()}  else {format!("{}",doc.dindex.articulo.unwrap()).to_string()},
-  if doc.dindex.parte.is_none() {"-1".to_string()}  else {format!("{}",doc.dindex.parte.unwrap()).to_string()},
-  doc.dmeaning.embedding.etype))
-  .expect(format!("{}: {}",utils::error_message("E0008"),reference_filename(&doc.dindex)).as_str());
-}
-pub fn write_embeddings_file(doc: &catalogue::Catalogue) {
-  fs::write(embeddings_filename(&doc.dindex),&doc.dmeaning.embedding.vector.clone().unwrap()
-    .iter().map(|&x| x.to_string()).collect::<Vec<String>>().join("\n"))
-    .expect(format!("{}: {}",utils::error_message("E0005"),embeddings_filename(&doc.dindex)).as_str());
-}
\ No newline at end of file
+// Written whole or not at all: the content goes to a partial file, synced, then renamed over the target
+pub fn write_partial(filename: &str, content: &[u8]) -> io::Result<()> {
+  let mut file = fs::File::create(partial_filename(filename))?;
+  file.write_all(content)?;
+  file.sync_all()
+}
+pub fn promote_partial(filename: &str) -> io::Result<()> {
+  fs::rename(partial_filename(filename), filename)
+}
+// Makes the renames inside a folder durable
+pub fn sync_folder(foldername: &str) -> io::Result<()> {
+  fs::File::open(foldername)?.sync_all()
+}
+pub fn write_atomic(filename: &str, content: &[u8]) -> io::Result<()> {
+  write_partial(filename, content)?;
+  promote_partial(filename)?;
+  sync_folder(Path::new(filename).parent().and_then(|x| x.to_str()).filter(|x| !x.is_empty()).unwrap_or("."))
+}
+pub fn reference_file_content(doc: &catalogue::Catalogue) -> String {
+  let mut reference = ReferenceFile::from_catalogue(doc);
+  // a catalogue embedded again keeps the time its reference was first written
+  if let Ok(previous) = read_reference_schema(&reference_filename(&doc.dindex)) {
+    reference.created = previous.created.or(reference.created);
+  }
+  toml::to_string(&reference)
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0008"),reference_filename(&doc.dindex)))
+}
+pub fn embeddings_file_content(doc: &catalogue::Catalogue) -> String {
+  doc.dmeaning.embedding.vector.clone().unwrap()
+    .iter().map(|&x| x.to_string()).collect::<Vec<String>>().join("\n")
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LEGACY: &str = r#"
+pais = "mx"
+instrumento = "cpeum"
+titulo = "2"
+capitulo = "-1"
+articulo = "15"
+parte = "-1"
+etype = "Total"
+"#;
+
+  #[test]
+  fn legacy_references_are_upgraded() {
+    let reference = ReferenceFile::parse(LEGACY).unwrap();
+    assert_eq!(reference.version, 1);
+    assert_eq!((reference.titulo, reference.capitulo, reference.articulo, reference.parte), (Some(2), None, Some(15), None));
+    assert!(matches!(reference.etype, transformer::EmbeddingType::Total));
+    // what the first format did not write is what it was made with
+    assert!(matches!(reference.pooling, transformer::PoolingStrategy::Mean));
+    assert_eq!(reference.model, utils::config_model());
+    assert_eq!(reference.template, "{}");
+    assert_eq!(reference.metric, transformer::DistanceMetric::Euclidean.name());
+    assert_eq!(reference.sha256, None);
+    assert_eq!(reference.law_index().book.instrumento, "cpeum");
+  }
+
+  #[test]
+  fn legacy_references_with_wrong_values_are_refused() {
+    assert!(ReferenceFile::parse(&LEGACY.replace("\"Total\"", "\"Sum\"")).is_err());
+    assert!(ReferenceFile::parse(&LEGACY.replace("\"15\"", "\"quince\"")).is_err());
+    assert!(ReferenceFile::parse(&format!("{}pooling = \"median\"\n",LEGACY)).is_err());
+    assert!(ReferenceFile::parse(&format!("{}metric = \"hamming\"\n",LEGACY)).is_err());
+  }
+
+  #[test]
+  fn current_references_round_trip() {
+    let mut reference = ReferenceFile::parse(LEGACY).unwrap();
+    reference.version = REFERENCE_VERSION;
+    reference.metric = transformer::DistanceMetric::Minkowski(3.0).name();
+    let parsed = ReferenceFile::parse(&toml::to_string(&reference).unwrap()).unwrap();
+    assert_eq!(parsed.version, REFERENCE_VERSION);
+    assert_eq!(parsed.law_index(), reference.law_index());
+    assert_eq!(parsed.metric().unwrap().name(), "minkowski-3");
+    reference.version = REFERENCE_VERSION + 1;
+    assert!(ReferenceFile::parse(&toml::to_string(&reference).unwrap()).is_err());
+  }
+}
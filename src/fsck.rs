@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::catalogue;
+use crate::cryptography;
+use crate::files;
+use crate::language;
+use crate::laws;
+use crate::store;
+use crate::transformer;
+use crate::utils;
+
+// Inconsistencies between the reference, embedding and text files of a catalogue
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Issue {
+  // an embedding or text file without its reference
+  Orphaned,
+  // a reference that cannot be parsed
+  Unreadable,
+  MissingEmbedding,
+  MissingText,
+  DimensionMismatch(usize, usize),
+  // NaN, infinite or unparsable components
+  InvalidVector,
+  // the text changed since it was embedded
+  HashMismatch
+}
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Finding {
+  pub path: String,
+  pub dindex: Option<laws::LawIndex>,
+  pub issue: Issue
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Repair {
+  Reembed,
+  Delete,
+  Quarantine
+}
+
+impl Repair {
+  pub fn from_name(name: &str) -> Option<Repair> {
+    match name.to_lowercase().as_str() {
+      "reembed"    => Some(Repair::Reembed),
+      "delete"     => Some(Repair::Delete),
+      "quarantine" => Some(Repair::Quarantine),
+      _ => None
+    }
+  }
+}
+
+fn stem<'a>(filename: &'a str, extension: &str) -> Option<&'a str> {
+  filename.strip_suffix(extension)
+}
+fn read_vector(filepath: &str) -> Option<Vec<f32>> {
+  utils::lines_from_file(filepath).ok()?.iter().map(|x| x.parse::<f32>().ok()).collect::<Option<Vec<f32>>>()
+}
+
+// Every inconsistency of a Book: in its files for the folder store, then in its stored catalogues
+pub fn check_book(book: &laws::LawBook) -> Vec<Finding> {
+  let mut findings = if utils::config_store() == store::StoreKind::Folder { check_files(book) } else { Vec::new() };
+  let checked = findings.iter().filter_map(|x| x.dindex.clone()).collect::<HashSet<laws::LawIndex>>();
+  findings.extend(check_stored(book, &checked));
+  findings
+}
+// Where a catalogue is stored: its reference file, or its key in the sqlite store
+fn stored_path(dindex: &laws::LawIndex) -> String {
+  match utils::config_store() {
+    store::StoreKind::Folder => files::reference_filename(dindex),
+    store::StoreKind::Sqlite => files::law_index_to_filename(dindex)
+  }
+}
+// What only files can have wrong: orphans, unreadable references, missing or unparsable files, changed texts
+fn check_files(book: &laws::LawBook) -> Vec<Finding> {
+  let folder = files::book_foldername(book);
+  let filenames = WalkDir::new(&folder).into_iter().filter_map(|e| e.ok())
+    .filter(|x| x.file_type().is_file())
+    .map(|x| utils::name_from_dir_entry(&x)).collect::<Vec<String>>();
+  let references = filenames.iter()
+    .filter_map(|x| stem(x, &utils::config_reference_extension()).map(|y| y.to_string())).collect::<Vec<String>>();
+  let mut findings: Vec<Finding> = Vec::new();
+  // embeddings and texts without a reference, the text of an article split in parts belongs to them
+  for filename in filenames.iter() {
+    let dstem = match stem(filename, &utils::config_embeddings_extension()).or(stem(filename, &utils::config_law_extension())) {
+      Some(dstem) => dstem,
+      None => continue
+    };
+    let parted = format!("{}.parte-",dstem);
+    if !references.iter().any(|x| x == dstem || (filename.ends_with(&utils::config_law_extension()) && x.starts_with(&parted))) {
+      findings.push(Finding { path: format!("{}{}",folder,filename), dindex: None, issue: Issue::Orphaned });
+    }
+  }
+  for reference in references.iter() {
+    let path = format!("{}{}{}",folder,reference,utils::config_reference_extension());
+    let reference = match files::read_reference_schema(&path) {
+      Ok(reference) => reference,
+      Err(_) => {
+        findings.push(Finding { path, dindex: None, issue: Issue::Unreadable });
+        continue;
+      }
+    };
+    let dindex = reference.law_index();
+    let finding = |issue: Issue| Finding { path: path.clone(), dindex: Some(dindex.clone()), issue };
+    if !Path::new(&files::embeddings_filename(&dindex)).exists() {
+      findings.push(finding(Issue::MissingEmbedding));
+    } else if read_vector(&files::embeddings_filename(&dindex)).is_none() {
+      findings.push(finding(Issue::InvalidVector));
+    }
+    match fs::read_to_string(files::file_of_law_filename(&dindex)) {
+      Err(_) => findings.push(finding(Issue::MissingText)),
+      Ok(text) => {
+        // references written before the hash was stored cannot be checked
+        if reference.sha256.as_ref().map(|x| *x != cryptography::sha256_digest(text.as_str())).unwrap_or(false) {
+          findings.push(finding(Issue::HashMismatch));
+        }
+      }
+    }
+  }
+  findings
+}
+// What any store can have wrong, read through it: unreadable catalogues, empty or non finite vectors,
+// empty texts and dimensions apart from the rest of the book. Catalogues already found wrong are skipped
+fn check_stored(book: &laws::LawBook, checked: &HashSet<laws::LawIndex>) -> Vec<Finding> {
+  let mut findings: Vec<Finding> = Vec::new();
+  let mut vectors: Vec<(laws::LawIndex, usize)> = Vec::new();
+  for dindex in store::store().list(book).unwrap_or_else(|x| panic!("{}",x)).into_iter().filter(|x| !checked.contains(x)) {
+    let finding = |issue: Issue| Finding { path: stored_path(&dindex), dindex: Some(dindex.clone()), issue };
+    let stored = match store::store().get(&dindex) {
+      Ok(Some(stored)) => stored,
+      Ok(None) => continue,
+      Err(_) => {
+        findings.push(finding(Issue::Unreadable));
+        continue;
+      }
+    };
+    if stored.vector.is_empty() {
+      findings.push(finding(Issue::MissingEmbedding));
+    } else if !stored.vector.iter().all(|x| x.is_finite()) {
+      findings.push(finding(Issue::InvalidVector));
+    } else {
+      vectors.push((dindex.clone(), stored.vector.len()));
+    }
+    if stored.text.is_empty() {
+      findings.push(finding(Issue::MissingText));
+    }
+  }
+  // the dimension most vectors of the book have is taken as the right one
+  let mut dimensions: HashMap<usize,usize> = HashMap::new();
+  vectors.iter().for_each(|x| *dimensions.entry(x.1).or_insert(0) += 1);
+  if let Some((&expected, _)) = dimensions.iter().max_by_key(|x| (x.1, x.0)) {
+    for (dindex, dims) in vectors.into_iter().filter(|x| x.1 != expected) {
+      findings.push(Finding { path: stored_path(&dindex), dindex: Some(dindex), issue: Issue::DimensionMismatch(expected, dims) });
+    }
+  }
+  findings
+}
+
+// Files making up the catalogue of a finding: the reference, its embedding and its text
+fn finding_files(finding: &Finding) -> Vec<String> {
+  match &finding.dindex {
+    Some(dindex) => Vec::from([files::reference_filename(dindex), files::embeddings_filename(dindex), files::file_of_law_filename(dindex)]),
+    None => Vec::from([finding.path.clone()])
+  }
+}
+// Embeds the text of a catalogue again with the model and template it was stored with
+fn reembed(finding: &Finding) -> Result<(), String> {
+  let dindex = finding.dindex.as_ref().ok_or("no reference to embed again")?;
+  let (pooling, model, template, metric, text) = match store::store().get(dindex) {
+    Ok(Some(stored)) => (stored.pooling, stored.model, stored.template, stored.metric, stored.text),
+    // the folder store cannot read a catalogue whose embedding is missing or broken, its reference and text can
+    _ if utils::config_store() == store::StoreKind::Folder => {
+      let (_, _, pooling, model, template, metric) = files::read_reference_path(&finding.path)?;
+      let text = fs::read_to_string(files::file_of_law_filename(dindex)).map_err(|_| "no text to embed again")?;
+      (pooling, model, template, metric, text)
+    }
+    _ => return Err("no stored catalogue to embed again".to_string())
+  };
+  let phrase_of_law = language::phrase_fabric(text);
+  let (embedding, etype) = if dindex.parte.is_some() {
+    (transformer::transform_templated(&model, &template, &Vec::from([phrase_of_law.text.clone()]))?.into_iter().next(), transformer::EmbeddingType::Total)
+  } else {
+    catalogue::embedd_sentence(&model, &template, &phrase_of_law, dindex)?
+  };
+  if embedding.is_none() {
+    return Err("the text is too short to embed".to_string());
+  }
+  catalogue::save_catalogue(&catalogue::catalogue_fabric(
+    dindex.book.pais.clone(),
+    dindex.book.instrumento.clone(),
+    dindex.titulo,
+    dindex.capitulo,
+    dindex.articulo,
+    dindex.parte,
+    &phrase_of_law,
+    etype,
+    pooling,
+    model,
+    template,
+    metric,
+    &embedding));
+  Ok(())
+}
+// Applies a repair to a finding, Err tells why it could not
+pub fn repair(finding: &Finding, repair: &Repair) -> Result<(), String> {
+  match repair {
+    Repair::Reembed => reembed(finding),
+    Repair::Delete => {
+      if let Some(dindex) = &finding.dindex {
+        return store::store().delete(dindex);
+      }
+      // a file of no catalogue, only the folder store has them
+      if Path::new(&finding.path).exists() {
+        fs::remove_file(&finding.path).map_err(|x| format!("{}: {}",finding.path,x))?;
+      }
+      Ok(())
+    }
+    Repair::Quarantine if utils::config_store() != store::StoreKind::Folder => {
+      Err(format!("the {} store cannot quarantine, delete or reembed instead",store::store().name()))
+    }
+    Repair::Quarantine => {
+      for filename in finding_files(finding).iter().filter(|x| Path::new(x).exists()) {
+        let relative = Path::new(filename).strip_prefix(utils::config_reference_folder()).unwrap_or(Path::new(filename));
+        let target = Path::new(&utils::config_quarantine_folder()).join(relative);
+        fs::create_dir_all(target.parent().unwrap()).map_err(|x| format!("{}: {}",target.display(),x))?;
+        fs::rename(filename, &target).map_err(|x| format!("{}: {}",filename,x))?;
+      }
+      Ok(())
+    }
+  }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::files;
+use crate::laws;
+use crate::utils;
+
+// Write-ahead journal of the ingestion of a Book, one line per event, tab separated:
+//   ingest   <fingerprint of the text, model, template and pooling ingested>
+//   pending  <unit> <file> <file> ...   every file of the unit is fully written as a partial file
+//   commit   <unit>                     every partial file of the unit was renamed into place
+//   complete                            the whole book was ingested
+// An article is one unit: its reference, embedding and text files (and those of its parts).
+// A pending unit without its commit is rolled forward, partial files of no pending unit are removed.
+// The partial files of a unit are written before its pending line, so an ingestion holds the lock file
+// of its Book from start to end and recovery only runs under it.
+
+// Lock of the files of a Book, released when dropped (or when its process dies)
+pub struct BookLock {
+  _file: File
+}
+
+fn lock_file(book: &laws::LawBook) -> File {
+  create_dir_all(utils::config_reference_folder()).unwrap();
+  OpenOptions::new().create(true).truncate(false).write(true).open(files::lock_filename(book))
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),files::lock_filename(book)))
+}
+// Waits for another process ingesting the Book
+pub fn lock_book(book: &laws::LawBook) -> BookLock {
+  let file = lock_file(book);
+  file.lock().unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),files::lock_filename(book)));
+  BookLock { _file: file }
+}
+// None while another process (or another ingestion of this one) holds the Book
+fn try_lock_book(book: &laws::LawBook) -> Option<BookLock> {
+  let file = lock_file(book);
+  file.try_lock().ok()?;
+  Some(BookLock { _file: file })
+}
+
+fn append(book: &laws::LawBook, line: &str) {
+  let mut file = OpenOptions::new().create(true).append(true).open(files::journal_filename(book))
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),files::journal_filename(book)));
+  file.write_all(format!("{}\n",line).as_bytes())
+    .and_then(|_| file.sync_data())
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),files::journal_filename(book)));
+}
+fn journal_lines(book: &laws::LawBook) -> Vec<Vec<String>> {
+  fs::read_to_string(files::journal_filename(book)).unwrap_or_default().lines()
+    .filter(|x| !x.is_empty())
+    .map(|x| x.split('\t').map(|y| y.to_string()).collect::<Vec<String>>())
+    .collect()
+}
+// Units listed as pending, with their files, and whether each one was committed
+fn journal_units(book: &laws::LawBook) -> Vec<(String, Vec<String>, bool)> {
+  let mut units: Vec<(String, Vec<String>, bool)> = Vec::new();
+  for line in journal_lines(book).into_iter().filter(|x| x.len() > 1) {
+    if line[0] == "pending" {
+      units.push((line[1].clone(), line[2..].to_vec(), false));
+    } else if line[0] == "commit" {
+      if let Some(unit) = units.iter_mut().rev().find(|x| x.0 == line[1]) {
+        unit.2 = true;
+      }
+    }
+  }
+  units
+}
+
+// Rolls the pending units of a Book forward and removes stray partial files, returns the units rolled;
+// a Book being ingested is left to its ingestion
+pub fn recover(book: &laws::LawBook) -> usize {
+  match try_lock_book(book) {
+    Some(_lock) => recover_locked(book),
+    None => {
+      println!("[Warning]: {}.{} is being ingested, its journal is not recovered",book.pais,book.instrumento);
+      0
+    }
+  }
+}
+fn recover_locked(book: &laws::LawBook) -> usize {
+  let units = journal_units(book);
+  let mut rolled = 0;
+  for (unit, filenames, committed) in units.iter() {
+    if *committed {
+      continue;
+    }
+    for filename in filenames.iter().filter(|x| Path::new(&files::partial_filename(x)).exists()) {
+      files::promote_partial(filename)
+        .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),filename));
+    }
+    if filenames.iter().all(|x| Path::new(x).exists()) {
+      files::sync_folder(&files::book_foldername(book)).ok();
+      append(book, format!("commit\t{}",unit).as_str());
+      println!("Recovered the interrupted write of [{}]",unit);
+      rolled += 1;
+    }
+  }
+  let listed = units.iter().flat_map(|x| x.1.iter().map(|y| files::partial_filename(y))).collect::<Vec<String>>();
+  for dpath in WalkDir::new(files::book_foldername(book)).into_iter().filter_map(|e| e.ok()) {
+    let filename = dpath.path().to_str().unwrap_or_default().to_string();
+    if filename.ends_with(&utils::config_partial_extension()) && !listed.contains(&filename) {
+      println!("[Warning]: removing the partial file <{}>, its write never reached the journal",filename);
+      fs::remove_file(&filename).ok();
+    }
+  }
+  rolled
+}
+
+// Starts (or resumes) the ingestion of a Book: when the journal is of an interrupted ingestion with the
+// same fingerprint the committed units are returned with their files, they need not be written again.
+// The Book stays locked until the returned lock is dropped, once the ingestion ended
+pub fn begin_ingestion(book: &laws::LawBook, fingerprint: &str) -> (BookLock, HashMap<String, Vec<String>>) {
+  let lock = lock_book(book);
+  create_dir_all(files::book_foldername(book)).unwrap();
+  recover_locked(book);
+  let lines = journal_lines(book);
+  let resumable = lines.first().map(|x| x.len() > 1 && x[0] == "ingest" && x[1] == fingerprint).unwrap_or(false)
+    && !lines.iter().any(|x| x[0] == "complete");
+  if !resumable {
+    fs::write(files::journal_filename(book), "")
+      .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0016"),files::journal_filename(book)));
+    append(book, format!("ingest\t{}",fingerprint).as_str());
+    return (lock, HashMap::new());
+  }
+  let committed = journal_units(book).into_iter()
+    .filter(|x| x.2 && x.1.iter().all(|y| Path::new(y).exists()))
+    .map(|x| (x.0, x.1)).collect::<HashMap<String, Vec<String>>>();
+  println!("Resuming the ingestion of {}.{}, {} articles already committed",book.pais,book.instrumento,committed.len());
+  (lock, committed)
+}
+// Writes every file of a unit so that after a crash either all or none of them are in place
+pub fn commit_unit(book: &laws::LawBook, unit: &str, writes: Vec<(String, String)>) {
+  for (filename, content) in writes.iter() {
+    if let Some(folder) = Path::new(filename).parent() {
+      create_dir_all(folder).unwrap();
+    }
+    files::write_partial(filename, content.as_bytes())
+      .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),filename));
+  }
+  let filenames = writes.iter().map(|x| x.0.clone()).collect::<Vec<String>>();
+  append(book, format!("pending\t{}\t{}",unit,filenames.join("\t")).as_str());
+  for filename in filenames.iter() {
+    files::promote_partial(filename)
+      .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),filename));
+  }
+  files::sync_folder(&files::book_foldername(book))
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0004"),files::book_foldername(book)));
+  append(book, format!("commit\t{}",unit).as_str());
+}
+pub fn complete_ingestion(book: &laws::LawBook) {
+  append(book, "complete");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_book(instrumento: &str) -> laws::LawBook {
+    let book = laws::LawBook { pais: "test".to_string(), instrumento: instrumento.to_string() };
+    fs::remove_dir_all(files::book_foldername(&book)).ok();
+    create_dir_all(files::book_foldername(&book)).unwrap();
+    book
+  }
+  fn remove_book(book: &laws::LawBook) {
+    fs::remove_dir_all(files::book_foldername(book)).ok();
+    fs::remove_file(files::lock_filename(book)).ok();
+  }
+
+  #[test]
+  fn pending_units_roll_forward_and_stray_partials_go() {
+    let book = test_book("journal");
+    let folder = files::book_foldername(&book);
+    let (first, second, stray) = (format!("{}a.toml",folder), format!("{}b.txt",folder), format!("{}c.txt",folder));
+    // the first file was renamed into place before the crash, the second one was not
+    files::write_partial(&first, b"first").unwrap();
+    files::promote_partial(&first).unwrap();
+    files::write_partial(&second, b"second").unwrap();
+    files::write_partial(&stray, b"stray").unwrap();
+    fs::write(files::journal_filename(&book), format!("ingest\tx\npending\tunit\t{}\t{}\n",first,second)).unwrap();
+    assert_eq!(recover(&book), 1);
+    assert_eq!(fs::read_to_string(&second).unwrap(), "second");
+    assert!(!Path::new(&files::partial_filename(&second)).exists());
+    assert!(!Path::new(&files::partial_filename(&stray)).exists());
+    assert!(journal_units(&book).iter().all(|x| x.2));
+    // a committed unit is not rolled again
+    assert_eq!(recover(&book), 0);
+    remove_book(&book);
+  }
+
+  #[test]
+  fn units_missing_a_file_stay_pending() {
+    let book = test_book("journal-missing");
+    let folder = files::book_foldername(&book);
+    let (first, second) = (format!("{}a.toml",folder), format!("{}b.txt",folder));
+    files::write_partial(&first, b"first").unwrap();
+    fs::write(files::journal_filename(&book), format!("ingest\tx\npending\tunit\t{}\t{}\n",first,second)).unwrap();
+    assert_eq!(recover(&book), 0);
+    assert!(journal_units(&book).iter().all(|x| !x.2));
+    remove_book(&book);
+  }
+
+  #[test]
+  fn a_book_being_ingested_is_not_recovered() {
+    let book = test_book("journal-locked");
+    let stray = format!("{}c.txt",files::book_foldername(&book));
+    files::write_partial(&stray, b"being written").unwrap();
+    let lock = lock_book(&book);
+    assert_eq!(recover(&book), 0);
+    assert!(Path::new(&files::partial_filename(&stray)).exists());
+    drop(lock);
+    recover(&book);
+    assert!(!Path::new(&files::partial_filename(&stray)).exists());
+    remove_book(&book);
+  }
+}
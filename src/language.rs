@@ -1,11 +1,14 @@
 use lazy_static::lazy_static;
 use rocket::serde::{Serialize, Deserialize};
 use core::fmt::Debug;
-use std::sync::Mutex;
+use std::ops::Range;
+use std::collections::HashMap;
+use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
 
 use crate::utils;
 use crate::language;
 use crate::laws;
+use crate::transformer;
 
 #[derive(Debug,Clone,Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -21,80 +24,269 @@ pub enum TextOfLawValidation {
 }
 
 #[derive(Debug, Clone)]
-pub struct Vocab {
-  pub file: String, 
-  pub words: Vec<String>
+pub struct Window {
+  pub phrase: Phrase,
+  // byte range of the window in the text, only the tests read it
+  #[cfg_attr(not(test), allow(dead_code))]
+  pub span: Range<usize>,
+  pub tokens: usize,
+  pub overlap: usize
 }
 
 lazy_static! {
-  static ref VALIDATION_VOCAB: Mutex<Vocab> = Mutex::new(
-    Vocab {
-      file: String::from(utils::config_vocab_file()),
-      words: utils::lines_from_file(utils::config_vocab_file())
-        .expect(utils::error_message("E0001").as_str())
+  // Tokenizer of the model of each served language, by model
+  static ref TOKENIZERS: HashMap<String,BertTokenizer> = utils::config_languages().iter()
+    .map(|x| (utils::config_model_for_language(x), tokenizer_fabric(
+      &utils::config_vocab_file_for(utils::config_model_for_language(x).as_str()), 
+      utils::config_tokenizer_lowercase(x))))
+    .collect::<HashMap<String,BertTokenizer>>();
+}
+// Stopwords used to tell the language of a phrase
+const STOPWORDS: [(&str, &[&str]); 2] = [
+  ("es", &["el","la","los","las","de","del","que","y","en","un","una","por","con","para","es","se","no","al","lo","mi","me","su","estoy","fue"]),
+  ("en", &["the","of","and","to","in","is","a","that","for","it","with","as","was","on","my","i","be","are","not","this","me","was","by"])
+];
+
+// WordPiece tokenizer of a model from its vocab, uncased models lowercase and strip accents
+pub fn tokenizer_fabric(file: &str, lowercase: bool) -> BertTokenizer {
+  BertTokenizer::from_file(file, lowercase, lowercase)
+    .unwrap_or_else(|_| panic!("{} : {}",utils::error_message("E0001"),file))
+}
+fn model_tokenizer(model: &str) -> &'static BertTokenizer {
+  TOKENIZERS.get(model).unwrap_or_else(|| panic!("{} : {}",utils::error_message("E0001"),model))
+}
+
+// The served language whose stopwords appear the most in the text, the configured one on a tie
+pub fn detect_language(text: &str) -> String {
+  let words = text.split(|c: char| !c.is_alphanumeric()).filter(|x| !x.is_empty())
+    .map(|x| x.to_lowercase()).collect::<Vec<String>>();
+  let mut best = (utils::config_language(), 0usize);
+  for language in utils::config_languages() {
+    let count = match STOPWORDS.iter().find(|x| x.0 == language) {
+      Some((_, stopwords)) => words.iter().filter(|x| stopwords.contains(&x.as_str())).count(),
+      None => 0
+    };
+    if count > best.1 {
+      best = (language, count);
     }
-  );
+  }
+  best.0
 }
 
 pub fn phrase_fabric(text: String) -> Phrase {
-  return Phrase {
-    text: text
+  Phrase {
+    text
   }
 }
 
+// Tokens
+// WordPiece tokens of a text, with the tokenizer of the model
+pub fn wordpiece_tokens(model: &str, text: &str) -> Vec<String> {
+  model_tokenizer(model).tokenize(text)
+}
+// Input ids of a text for backends that tokenize on their own, [CLS] text [SEP] in at most max_length ids
+pub fn encode_ids(model: &str, text: &str, max_length: usize) -> Vec<i64> {
+  model_tokenizer(model).encode(text, None, max_length, &TruncationStrategy::LongestFirst, 0).token_ids
+}
+// Amount of model tokens of a text, without [CLS] and [SEP]
+pub fn count_tokens(model: &str, text: &str) -> usize {
+  wordpiece_tokens(model, text).len()
+}
+
+// Tokens left for the text of a window once it is placed in the template, the model reads both
+pub fn window_token_budget(model: &str, template: &str) -> usize {
+  let max_tokens = utils::atoi::<usize>(utils::config_maximum_window_tokens().as_str()).unwrap();
+  max_tokens.saturating_sub(count_tokens(model, &transformer::apply_template(template, ""))).max(1)
+}
+
 // Phrases of Law
-pub fn validate_phrase(phrase_of_law: &language::Phrase) -> TextOfLawValidation {
-  if phrase_of_law.text == "" || phrase_of_law.text.split(" ").collect::<Vec<&str>>().len() < utils::atoi::<usize>(utils::config_minimum_window_size().as_str()).unwrap() {
+pub fn validate_phrase(model: &str, template: &str, phrase_of_law: &language::Phrase) -> TextOfLawValidation {
+  if phrase_of_law.text.is_empty() || phrase_of_law.text.split(" ").collect::<Vec<&str>>().len() < utils::atoi::<usize>(utils::config_minimum_window_size().as_str()).unwrap() {
     TextOfLawValidation::Short
-  } else if phrase_of_law.text.split(" ").collect::<Vec<&str>>().len() > utils::atoi::<usize>(utils::config_maximum_window_size().as_str()).unwrap() {
+  } else if count_tokens(model, &phrase_of_law.text) > window_token_budget(model, template) {
     TextOfLawValidation::Long
   } else {
     TextOfLawValidation::Proper
   }
 }
 pub fn clean_phrase_of_law(phrase_of_law: &language::Phrase) -> language::Phrase {
-  return language::phrase_fabric(phrase_of_law.text.replace("\n"," ").replace("  "," ").trim().to_string());
+  language::phrase_fabric(phrase_of_law.text.replace("\n"," ").replace("  "," ").trim().to_string())
 }
-pub fn segment_phrase_with_index(phrase_of_law: &language::Phrase, index: &laws::LawIndex) -> Vec<(laws::LawIndex, language::Phrase)> { 
-  let mut ret : Vec<(laws::LawIndex, language::Phrase)> = Vec::new();
-  let mut c_index = index.clone();
-  match validate_phrase(phrase_of_law) {
-    TextOfLawValidation::Short => {}
-    TextOfLawValidation::Proper => {
-      c_index.parte = None;
-      ret.push((c_index.clone(),phrase_of_law.clone()));
+// Byte ranges of the words of a text
+fn split_words(text: &str, within: &Range<usize>) -> Vec<Range<usize>> {
+  let mut ret : Vec<Range<usize>> = Vec::new();
+  let mut start : Option<usize> = None;
+  for (idx, c) in text[within.clone()].char_indices() {
+    if c.is_whitespace() {
+      if let Some(x) = start {
+        ret.push(within.start + x..within.start + idx);
+        start = None;
+      }
+    } else if start.is_none() {
+      start = Some(idx);
     }
-    TextOfLawValidation::Long => {
-      let mut parte : Option<u16> = Some(0);
-      for seg in utils::overlaping_chunks::<&str>(
-        &phrase_of_law.text.split(" ").collect::<Vec<&str>>(), 
-        utils::atoi::<usize>(utils::config_maximum_window_size().as_str()).unwrap(), 
-        utils::atoi::<usize>(utils::config_window_retrocede().as_str()).unwrap())
-        .iter().map(|x| x.join(" ")).collect::<Vec<String>>() {
-        c_index.parte=parte;
-        ret.push((c_index.clone(),language::phrase_fabric(seg)));
-        parte=Some(parte.unwrap()+1);
+  }
+  if let Some(x) = start {
+    ret.push(within.start + x..within.end);
+  }
+  ret
+}
+// Overlapping windows of at most maximum_window_tokens (less the tokens of the template), boundaries snap to sentence ends 
+// (to words for sentences longer than a window), the overlap holds whole sentences of at most window_retrocede_tokens
+pub fn segment_windows(model: &str, template: &str, phrase_of_law: &language::Phrase) -> Vec<Window> {
+  let max_tokens = window_token_budget(model, template);
+  let retrocede = utils::atoi::<usize>(utils::config_window_retrocede_tokens().as_str()).unwrap();
+  segment_windows_by(&phrase_of_law.text, max_tokens, retrocede, |x| count_tokens(model, x))
+}
+// Windows of a text with the tokens of a span given by count
+fn segment_windows_by(text: &str, max_tokens: usize, retrocede: usize, count: impl Fn(&str) -> usize) -> Vec<Window> {
+  let mut units : Vec<(Range<usize>,usize)> = Vec::new();
+  for sentence in split_sentences(text) {
+    let tokens = count(&text[sentence.clone()]);
+    if tokens <= max_tokens {
+      units.push((sentence, tokens));
+    } else {
+      for word in split_words(text, &sentence) {
+        let tokens = count(&text[word.clone()]);
+        units.push((word, tokens));
       }
     }
   }
-  return ret;
-}
-pub fn segment_phrase(phrase_of_law: &language::Phrase) -> Vec<language::Phrase> { 
-  let mut ret : Vec<language::Phrase> = Vec::new();
-  match validate_phrase(phrase_of_law) {
-    TextOfLawValidation::Short => {}
-    TextOfLawValidation::Proper => {
-      ret.push(phrase_of_law.clone());
+  let mut ret : Vec<Window> = Vec::new();
+  let mut start : usize = 0;
+  let mut overlap : usize = 0;
+  while start < units.len() {
+    let mut end = start;
+    let mut tokens : usize = 0;
+    while end < units.len() && (end == start || tokens + units[end].1 <= max_tokens) {
+      tokens += units[end].1;
+      end += 1;
+    }
+    let span = units[start].0.start..units[end-1].0.end;
+    ret.push(Window {
+      phrase: phrase_fabric(text[span.clone()].to_string()),
+      span,
+      tokens,
+      overlap
+    });
+    if end == units.len() {
+      break;
+    }
+    let mut back = end;
+    overlap = 0;
+    while back > start + 1 && overlap + units[back-1].1 <= retrocede {
+      overlap += units[back-1].1;
+      back -= 1;
     }
-    TextOfLawValidation::Long => {
-      for seg in utils::overlaping_chunks::<&str>(
-        &phrase_of_law.text.split(" ").collect::<Vec<&str>>(), 
-        utils::atoi::<usize>(utils::config_maximum_window_size().as_str()).unwrap(), 
-        utils::atoi::<usize>(utils::config_window_retrocede().as_str()).unwrap())
-          .iter().map(|x| x.join(" ")).collect::<Vec<String>>() {
-        ret.push(language::phrase_fabric(seg));
+    start = back;
+  }
+  ret
+}
+// Windows of a phrase of law, none if too short, the whole phrase if it fits in one window
+pub fn segment_phrase_windows(model: &str, template: &str, phrase_of_law: &language::Phrase) -> Vec<Window> { 
+  match validate_phrase(model, template, phrase_of_law) {
+    TextOfLawValidation::Short => Vec::new(),
+    TextOfLawValidation::Proper => Vec::from([Window {
+      phrase: phrase_of_law.clone(),
+      span: 0..phrase_of_law.text.len(),
+      tokens: count_tokens(model, &phrase_of_law.text),
+      overlap: 0
+    }]),
+    TextOfLawValidation::Long => segment_windows(model, template, phrase_of_law)
+  }
+}
+pub fn segment_phrase_with_index(model: &str, template: &str, phrase_of_law: &language::Phrase, index: &laws::LawIndex) -> Vec<(laws::LawIndex, Window)> { 
+  let mut ret : Vec<(laws::LawIndex, Window)> = Vec::new();
+  let mut c_index = index.clone();
+  let windows = segment_phrase_windows(model, template, phrase_of_law);
+  if windows.len() == 1 {
+    c_index.parte = None;
+    ret.push((c_index.clone(),windows[0].clone()));
+  } else {
+    let mut parte : Option<u16> = Some(0);
+    for seg in windows {
+      c_index.parte=parte;
+      ret.push((c_index.clone(),seg));
+      parte=Some(parte.unwrap()+1);
+    }
+  }
+  ret
+}
+#[allow(dead_code)]
+pub fn segment_phrase(model: &str, template: &str, phrase_of_law: &language::Phrase) -> Vec<language::Phrase> { 
+  segment_phrase_windows(model, template, phrase_of_law).into_iter().map(|x| x.phrase).collect::<Vec<language::Phrase>>()
+}
+
+// Splits a text into sentences, returns the byte range of each sentence without surrounding spaces
+pub fn split_sentences(text: &str) -> Vec<Range<usize>> {
+  let mut ret : Vec<Range<usize>> = Vec::new();
+  let mut start : usize = 0;
+  let mut chars = text.char_indices().peekable();
+  while let Some((idx, c)) = chars.next() {
+    let at_boundary = match chars.peek() {
+      None => true,
+      Some((_, next)) => ['.', ';', ':', '?', '!'].contains(&c) && next.is_whitespace()
+    };
+    if at_boundary {
+      let end = idx + c.len_utf8();
+      let sentence = &text[start..end];
+      let trimmed_start = start + (sentence.len() - sentence.trim_start().len());
+      let trimmed_end = end - (sentence.len() - sentence.trim_end().len());
+      if trimmed_start < trimmed_end {
+        ret.push(trimmed_start..trimmed_end);
       }
+      start = end;
     }
   }
-  return ret;
+  ret
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // one token per word, the windows are checked without loading a tokenizer
+  fn words(text: &str) -> usize {
+    text.split_whitespace().count()
+  }
+
+  #[test]
+  fn windows_cover_the_text_within_the_budget() {
+    // sentences of five words, two of them per window and the last one of a window again in the next
+    let text = (1..=12).map(|x| format!("Frase {} de cinco palabras.",x)).collect::<Vec<String>>().join(" ");
+    let windows = segment_windows_by(&text, 10, 5, words);
+    assert!(windows.len() > 1);
+    assert_eq!(windows.first().unwrap().span.start, 0);
+    assert_eq!(windows.last().unwrap().span.end, text.len());
+    for window in windows.iter() {
+      assert!(window.tokens <= 10, "{} tokens",window.tokens);
+      assert_eq!(window.phrase.text, text[window.span.clone()]);
+      // boundaries snap to sentence ends
+      assert!(window.phrase.text.starts_with("Frase") && window.phrase.text.ends_with('.'));
+    }
+    for pair in windows.windows(2) {
+      assert!(pair[1].span.start > pair[0].span.start && pair[1].span.start < pair[0].span.end, "windows must overlap");
+      assert_eq!(pair[1].overlap, words(&text[pair[1].span.start..pair[0].span.end]));
+      assert_eq!(pair[1].overlap, 5);
+    }
+  }
+
+  #[test]
+  fn sentences_longer_than_a_window_split_at_words() {
+    let text = (1..=25).map(|x| format!("palabra{}",x)).collect::<Vec<String>>().join(" ") + ".";
+    let windows = segment_windows_by(&text, 10, 3, words);
+    assert!(windows.len() >= 3);
+    assert!(windows.iter().all(|x| x.tokens <= 10 && !x.phrase.text.starts_with(' ') && !x.phrase.text.ends_with(' ')));
+    assert!(windows.last().unwrap().phrase.text.ends_with("palabra25."));
+    assert!(windows.iter().skip(1).all(|x| x.overlap <= 3 && x.overlap > 0));
+  }
+
+  #[test]
+  fn a_text_within_the_budget_is_one_window() {
+    let text = "Una frase. Otra frase.".to_string();
+    let windows = segment_windows_by(&text, 10, 4, words);
+    assert_eq!(windows.len(), 1);
+    assert_eq!(windows[0].span, 0..text.len());
+    assert_eq!(windows[0].overlap, 0);
+  }
 }
@@ -4,12 +4,19 @@ use rocket::serde::{Serialize, Deserialize};
 use std::cmp::Eq;
 use regex::Regex;
 use std::ops::Range;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
 
 use crate::utils;
 use crate::mathematics;
 use crate::catalogue;
 use crate::language;
 use crate::files;
+use crate::snapshot;
+use crate::journal;
+use crate::store;
+use crate::cryptography;
+use crate::transformer;
 
 #[derive(Clone)]
 #[derive(Debug)]
@@ -33,12 +40,33 @@ pub struct LawIndex {
   pub parte: Option<u16>
 }
 #[derive(Debug)]
+#[derive(Clone)]
+pub struct Centroid {
+  // None: the articles outside of any Title
+  pub titulo: Option<u16>,
+  // None: the articles of the Title outside of any Chapter, or the whole Title
+  pub capitulo: Option<u16>,
+  // the centroid of the whole Title, not of one of its Chapters
+  pub whole: bool,
+  pub vector: Vec<f32>
+}
+#[derive(Debug)]
 pub enum LawMark {
   Capitulo,
   Titulo,
   Articulo
 }
 
+lazy_static! {
+  static ref CENTROIDS_MEMORY: Mutex<HashMap<LawBook,Vec<Centroid>>> = Mutex::new(
+    HashMap::new()
+  );
+  // Books being ingested by this process
+  static ref INGESTIONS: Mutex<HashSet<LawBook>> = Mutex::new(
+    HashSet::new()
+  );
+}
+
 // To advance a mark is to advance to the next article, to the next chapter or to the next title
 pub fn advance_mark(law_index: &mut LawIndex, mark: &(LawMark,u16,Range<usize>)) {
   match mark.0 {
@@ -47,47 +75,101 @@ pub fn advance_mark(law_index: &mut LawIndex, mark: &(LawMark,u16,Range<usize>))
     LawMark::Articulo => {law_index.articulo = Some(mark.1);}
   }
 }
-// Calculate the vector average of an entire Title
-pub fn title_average(book: &LawBook, titulo: u16) -> Vec<f32> {
-  mathematics::vec2d_axis_average::<f32>(&catalogue::CATALOGUES_MEMORY.lock().unwrap()
-    .iter().filter(|(dindex,_)| 
-      dindex.book==*book && dindex.titulo==Some(titulo))
-      .collect::<HashMap<&LawIndex,&catalogue::Catalogue>>()
-    .iter().map(|(_,dcatalogue)|
-      dcatalogue.dmeaning.embedding.vector.clone().unwrap())
-      .collect::<Vec<Vec<f32>>>(),0)
-}
-// Calculate the vector average of an entire Chapter
-pub fn chapter_average(book: &LawBook, titulo: u16, capitulo: u16) -> Vec<f32> {
-  mathematics::vec2d_axis_average::<f32>(&catalogue::CATALOGUES_MEMORY.lock().unwrap()
-    .iter().filter(|(dindex,_)| 
-      dindex.book==*book && dindex.titulo==Some(titulo) && dindex.capitulo==Some(capitulo))
-      .collect::<HashMap<&LawIndex,&catalogue::Catalogue>>()
-    .iter().map(|(_,dcatalogue)|
-      dcatalogue.dmeaning.embedding.vector.clone().unwrap())
-      .collect::<Vec<Vec<f32>>>(),0)
-}
-// Return all Titles in a Book
+// Average of the vectors of the catalogues in a subtree, normalised: the average of unit vectors is shorter
+// than them, and the more so the more spread the subtree is
+pub fn subtree_average(vectors: &[(LawIndex,Vec<f32>)], member: impl Fn(&LawIndex) -> bool) -> Vec<f32> {
+  let vectors = vectors.iter().filter(|(dindex,_)| member(dindex))
+    .map(|(_,vector)| vector.clone())
+    .collect::<Vec<Vec<f32>>>();
+  if vectors.is_empty() {
+    return Vec::new();
+  }
+  let average = mathematics::vec2d_axis_average::<f32>(&vectors,0);
+  if mathematics::euclidean_magnitude(&average) > 0.0 {
+    return mathematics::vec1d_normalize_mu3(&average);
+  }
+  average
+}
+// Centroids of every Title (whole) and of the Chapters of the Titles that have them, computed once per load.
+// Articles outside of any Title (the preámbulo) make a Title of their own, as do the articles of a
+// Title outside of any of its Chapters
+pub fn book_centroids(book: &LawBook) -> Result<Vec<Centroid>, String> {
+  if let Some(centroids) = CENTROIDS_MEMORY.lock().unwrap().get(book) {
+    return Ok(centroids.clone());
+  }
+  let mut centroids: Vec<Centroid> = Vec::new();
+  let vectors = match catalogue::book_model(book) {
+    Some(model) => catalogue::book_vectors(book, &model)?,
+    None => Vec::new()
+  };
+  for dtitle in all_titles(book) {
+    centroids.push(Centroid {
+      titulo: dtitle,
+      capitulo: None,
+      whole: true,
+      vector: subtree_average(&vectors, |x| x.titulo==dtitle)
+    });
+    let chapters = all_chapters_in_title(book, dtitle);
+    if chapters.iter().all(|x| x.is_none()) {
+      continue;
+    }
+    for dchapter in chapters {
+      centroids.push(Centroid {
+        titulo: dtitle,
+        capitulo: dchapter,
+        whole: false,
+        vector: subtree_average(&vectors, |x| x.titulo==dtitle && x.capitulo==dchapter)
+      });
+    }
+  }
+  // subtrees whose catalogues hold no vector cannot be ranked
+  centroids.retain(|x| !x.vector.is_empty());
+  CENTROIDS_MEMORY.lock().unwrap().insert(book.clone(), centroids.clone());
+  Ok(centroids)
+}
+// Drops the centroids of a Book, they are recomputed on the next use
+pub fn forget_centroids(book: &LawBook) {
+  CENTROIDS_MEMORY.lock().unwrap().remove(book);
+}
+// Return all Titles in a Book, None for the articles outside of any Title
 pub fn all_titles(book: &LawBook) -> HashSet<Option<u16>> {
-  catalogue::CATALOGUES_MEMORY.lock().unwrap()
-  .iter().filter(|(dindex,_)| 
-    dindex.book == *book && 
-    dindex.titulo.is_some())
+  catalogue::book_shard(book)
+  .iter().filter(|(dindex,_)| dindex.book == *book)
   .map(|(pindex,_)| pindex.titulo).collect::<HashSet<Option<u16>>>()
 }
-// Return all Chapters in a Book's Title
-pub fn all_chapters_in_title(book: &LawBook, title: u16) -> HashSet<Option<u16>> {
-  catalogue::CATALOGUES_MEMORY.lock().unwrap()
+// Return all Chapters in a Book's Title, None for the articles outside of any Chapter
+pub fn all_chapters_in_title(book: &LawBook, title: Option<u16>) -> HashSet<Option<u16>> {
+  catalogue::book_shard(book)
   .iter().filter(|(dindex, _)| 
     dindex.book==*book && 
-    dindex.titulo.is_some() && 
-    dindex.capitulo.is_some() && 
-    dindex.titulo==Some(title))
+    dindex.titulo==title)
   .map(|(pindex,_)| pindex.capitulo).collect::<HashSet<Option<u16>>>()
-  // all_titles(book).iter().map(|dtitle| 
-  //   .collect::<HashSet<Option<u16>>>()
 }
 
+// Articles committed before an interruption are staged from their files instead of embedded again
+fn catalogue_or_resume(phrase_of_law: &language::Phrase, law_index: &LawIndex, committed: &HashMap<String, Vec<String>>) {
+  match committed.get(&files::law_index_to_filename(law_index)) {
+    Some(filenames) => catalogue::stage_references(filenames),
+    None => catalogue::catalogue_mech(phrase_of_law, law_index)
+  }
+}
+pub fn is_ingesting(book: &LawBook) -> bool {
+  INGESTIONS.lock().unwrap().contains(book)
+}
+// Ingests a Book unless this process is ingesting it already, returns whether it did
+pub fn ingest(book: &LawBook) -> bool {
+  if !INGESTIONS.lock().unwrap().insert(book.clone()) {
+    return false;
+  }
+  let outcome = std::panic::catch_unwind(|| interpret_law(book));
+  INGESTIONS.lock().unwrap().remove(book);
+  if let Err(panic) = outcome {
+    // the committed articles stay in the journal, the next ingestion resumes from them
+    catalogue::discard_staged(book);
+    std::panic::resume_unwind(panic);
+  }
+  true
+}
 // Given a catalogue of Law this function reads, interprests and dumps a TsahduCatalogue
 pub fn interpret_law(book: &LawBook) {
   let current_law_index =  &mut LawIndex {
@@ -98,33 +180,41 @@ pub fn interpret_law(book: &LawBook) {
     parte:None
   };
   let text_of_law = &language::phrase_fabric(files::read_law_book(book));
+  // An interrupted ingestion of the same text, model and template resumes after its last committed article
+  let model = utils::config_law_model(book);
+  // (the sqlite store commits each article in a transaction of its own, it keeps no journal)
+  let journaled = utils::config_store() == store::StoreKind::Folder;
+  // the lock is held until the book is published and its snapshot written
+  let (_lock, committed) = if !journaled { (None, HashMap::new()) } else {
+    let (lock, committed) = journal::begin_ingestion(book, &cryptography::sha256_digest(format!("{}\n{}\n{}\n{:?}",
+      text_of_law.text, model, utils::config_template(&model, &transformer::EncodingRole::Document), utils::config_pooling())));
+    (Some(lock), committed)
+  };
   let marks = mark_text_of_law(text_of_law, book);
   for mark in marks.windows(2) {
-    advance_mark(current_law_index, mark.get(0).unwrap());
+    advance_mark(current_law_index, mark.first().unwrap());
     let phrase_of_law = &language::clean_phrase_of_law(
       &language::phrase_fabric(utils::substring(&text_of_law.text, 
-        mark.get(0).unwrap().2.end, 
+        mark.first().unwrap().2.end, 
         mark.get(1).unwrap().2.start)));
-    catalogue::catalogue_mech(phrase_of_law, &current_law_index);
+    catalogue_or_resume(phrase_of_law, current_law_index, &committed);
   }
   advance_mark(current_law_index, marks.last().unwrap());
-  let phrase_of_law = &language::clean_phrase_of_law(&
-    &language::phrase_fabric(utils::substring(&text_of_law.text, 
+  let phrase_of_law = &language::clean_phrase_of_law(&language::phrase_fabric(utils::substring(&text_of_law.text, 
       marks.last().unwrap().2.end, 
       text_of_law.text.len())));
-  catalogue::catalogue_mech(phrase_of_law, &current_law_index);
-  // Fabric Catalogue for all Average Titles 
-  all_titles(book).iter().for_each(|dtitle| 
-    make the catalogue for average items
-    (title_average(book, dtitle.unwrap()))
-  );
-  // Fabric Catalogue for all Average Chapters 
-  all_titles(book).iter().for_each(|dtitle| 
-    (*dtitle,all_chapters_in_title(book, dtitle.unwrap()))).collect::<Vec<(Option<u16>,HashSet<Option<u16>>)>>()
-    .iter().map(|(dtitle,dchapters)|
-      dchapters.iter().map(|pchapter|
-        chapter_average(book, dtitle.unwrap(),pchapter.unwrap())
-      ));
+  catalogue_or_resume(phrase_of_law, current_law_index, &committed);
+  if journaled {
+    journal::complete_ingestion(book);
+  }
+  // Searches see the new catalogues from here on, all of them at once
+  catalogue::publish_staged(book);
+  // Single file of the whole book, mapped on the next startup (the other stores are read whole already)
+  if utils::config_store() == store::StoreKind::Folder {
+    snapshot::build_snapshot(book);
+  }
+  // The averages of the titles and chapters are their centroids, computed again on the next search
+  forget_centroids(book);
 }
 // Efective read of laws, returns markings of all aparitions of [Articulo, Titulo, Capitulo]
 pub fn mark_text_of_law(text_of_law: &language::Phrase, book: &LawBook) -> Vec<(LawMark, u16, Range<usize>)> {
@@ -135,21 +225,21 @@ pub fn mark_text_of_law(text_of_law: &language::Phrase, book: &LawBook) -> Vec<(
   marks.append(mark_interrupt_capitulo(book, &text_of_law.text)
     .iter().map(|x| (LawMark::Capitulo,x.0,x.1.clone())).collect::<Vec<(LawMark,u16,Range<usize>)>>().as_mut());
   marks.sort_by(|a,b| a.2.end.partial_cmp(&b.2.end).unwrap());
-  return marks;
+  marks
 }
 
 // Regex
-pub fn regex_interpret_law(regex_expresion: &str, text: &String) -> Vec<(u16,Range<usize>)> {
-  Regex::new(regex_expresion).expect(format!("[{}] is not a regex expression",regex_expresion).as_str()).find_iter(text.as_str())
-    .filter_map(|x| Some((utils::atoi::<u16>(x.as_str()).expect(format!("[{}] cannot be casted to atoi",x.as_str()).as_str()), x.range())))
+pub fn regex_interpret_law(regex_expresion: &str, text: &str) -> Vec<(u16,Range<usize>)> {
+  Regex::new(regex_expresion).unwrap_or_else(|_| panic!("[{}] is not a regex expression",regex_expresion)).find_iter(text)
+    .map(|x| (utils::atoi::<u16>(x.as_str()).unwrap_or_else(|_| panic!("[{}] cannot be casted to atoi",x.as_str())), x.range()))
     .collect::<Vec<(u16,Range<usize>)>>()
 }
-pub fn mark_interrupt_articulo(book: &LawBook, text: &String) -> Vec<(u16,Range<usize>)> {
+pub fn mark_interrupt_articulo(book: &LawBook, text: &str) -> Vec<(u16,Range<usize>)> {
   regex_interpret_law(utils::config_law(book).get("regex_articulo").unwrap(), text)
 }
-pub fn mark_interrupt_titulo(book: &LawBook, text: &String) -> Vec<(u16,Range<usize>)> {
+pub fn mark_interrupt_titulo(book: &LawBook, text: &str) -> Vec<(u16,Range<usize>)> {
   regex_interpret_law(utils::config_law(book).get("regex_titulo").unwrap(), text)
 }
-pub fn mark_interrupt_capitulo(book: &LawBook, text: &String) -> Vec<(u16,Range<usize>)> {
+pub fn mark_interrupt_capitulo(book: &LawBook, text: &str) -> Vec<(u16,Range<usize>)> {
   regex_interpret_law(utils::config_law(book).get("regex_capitulo").unwrap(), text)
 }
\ No newline at end of file
@@ -16,15 +16,30 @@ mod laws;
 mod files;
 mod figures;
 mod catalogue;
+mod search;
+mod explain;
+mod reranker;
+mod cache;
+mod batching;
+mod whitening;
+mod calibration;
+mod matrix;
+mod quantization;
+mod snapshot;
+mod reload;
+mod fsck;
+mod journal;
+mod store;
+mod archive;
+#[cfg(feature = "onnx")]
+mod onnx;
+mod cli;
 
 #[launch]
 fn tsahdu() -> _ {
-  // // Interpet Law
-  // let aux = laws::LawBook {
-  //   pais:String::from("colombia"),
-  //   instrumento:String::from("constitucion"),
-  // };
-  // laws::interpret_law(&aux);
-
+  // Command line tools
+  if cli::dispatch() {
+    std::process::exit(0);
+  }
   rocket::build().attach(server::stage())
 }
\ No newline at end of file
@@ -5,9 +5,9 @@ const LANES: usize = 16;
 
 pub fn euclidean_magnitude<T>(vec_a: &[T]) -> T 
   where T: std::ops::Mul + std::ops::Mul<Output = T> + From<f32> + std::ops::AddAssign + num_traits::Float {
-  let mut norm: T = 0.0f32.try_into().unwrap();
-  for i in 0..vec_a.len() {
-    norm += vec_a[i] * vec_a[i];
+  let mut norm: T = 0.0f32.into();
+  for &x in vec_a {
+    norm += x * x;
   }
   norm.sqrt()
 }
@@ -23,10 +23,10 @@ pub fn vector_euclidean_distance<T>(vec_a: &[T], vec_b: &[T]) -> T
 }
 pub fn minkowski_magnitude<T>(vec_a: &[T], p: T) -> T 
   where T: std::convert::From<f32> + num_traits::Float + std::ops::AddAssign {
-  let mut norm: T = 0.0f32.try_into().unwrap();
-  let numerator: T = 1.0f32.try_into().unwrap();
-  for i in 0..vec_a.len() {
-    norm += vec_a[i].powf(p);
+  let mut norm: T = 0.0f32.into();
+  let numerator: T = 1.0f32.into();
+  for x in vec_a {
+    norm += x.abs().powf(p);
   }
   norm.powf(numerator/p)
 }
@@ -39,10 +39,19 @@ pub fn vector_minkowski_distance<T>(vec_a: &[T], vec_b: &[T], p: T) -> T
   }
   minkowski_magnitude(&diff, p)
 }
+pub fn vector_manhattan_distance<T>(vec_a: &[T], vec_b: &[T]) -> T 
+  where T: std::ops::Sub + std::convert::From<f32> + num_traits::Float + std::ops::AddAssign {
+  assert!(vec_a.len() == vec_b.len(), "Vector lenghts must be equal for arguments in vector_manhattan_distance");
+  let mut norm: T = 0.0f32.into();
+  for i in 0..vec_a.len() {
+    norm += (vec_a[i] - vec_b[i]).abs();
+  }
+  norm
+}
 pub fn vector_cosine_distance<T>(vec_a: &[T], vec_b: &[T]) -> T 
   where T: std::ops::Mul + std::ops::Mul<Output = T> + From<f32> + std::ops::AddAssign + num_traits::Float {
   assert!(vec_a.len() == vec_b.len(), "Vector lenghts must be equal for arguments in vector_cosine_distance");
-  let mut numerator: T = 0.0f32.try_into().unwrap();
+  let mut numerator: T = 0.0f32.into();
   for i in 0..vec_a.len() {
     numerator+=vec_a[i] * vec_b[i];
   }
@@ -65,7 +74,7 @@ pub fn nonsimd_sum<T>(values: &[T]) -> T
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> {
   let chunks = values.chunks_exact(LANES);
   let remainder = chunks.remainder();
-  let sum: [T; 16] = chunks.fold([0.0f32.try_into().unwrap(); LANES], |mut acc, chunk| {
+  let sum: [T; 16] = chunks.fold([0.0f32.into(); LANES], |mut acc, chunk| {
     let chunk: [T; LANES] = chunk.try_into().unwrap();
     for i in 0..LANES {
       acc[i] += chunk[i];
@@ -73,9 +82,9 @@ pub fn nonsimd_sum<T>(values: &[T]) -> T
     acc
   });
   let remainder: T = remainder.iter().copied().sum();
-  let mut reduced : T = 0.0f32.try_into().unwrap();
-  for i in 0..LANES {
-    reduced += sum[i];
+  let mut reduced : T = 0.0f32.into();
+  for x in sum {
+    reduced += x;
   }
   reduced + remainder
 }
@@ -83,55 +92,71 @@ pub fn vec1d_sum<T>(input: &Vec<T>) -> T
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> {
     nonsimd_sum::<T>(input.as_slice())
 }
-pub fn vec2d_axis_sum<T>(input: &Vec<Vec<T>>, dimension: usize) -> Vec<T>
+pub fn vec2d_axis_sum<T>(input: &[Vec<T>], dimension: usize) -> Vec<T>
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> {
   assert!(dimension==0 || dimension==1);
   if dimension==0 {
-    transpose_vec2d::<T>(input.clone()).iter().map(|v| nonsimd_sum(v.as_slice())).collect::<Vec<T>>()
+    transpose_vec2d::<T>(input.to_owned()).iter().map(|v| nonsimd_sum(v.as_slice())).collect::<Vec<T>>()
   } else {
     input.iter().map(|v| nonsimd_sum(v.as_slice())).collect::<Vec<T>>()
   }
 }
-pub fn vec2d_axis_average<T>(input: &Vec<Vec<T>>, dimension: usize) -> Vec<T>
+pub fn vec2d_axis_average<T>(input: &[Vec<T>], dimension: usize) -> Vec<T>
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> + From<i16> {
     assert!(dimension==0 || dimension==1);
     if dimension==0 {
-      let n: T = (input.len() as i16).try_into().unwrap();
+      let n: T = (input.len() as i16).into();
       vec2d_axis_sum::<T>(input, dimension).iter().map(|&x| x / n).collect()
     } else {
-      let n: T = (input.get(0).as_ref().unwrap().len() as i16).try_into().unwrap();
+      let n: T = (input.first().as_ref().unwrap().len() as i16).into();
       vec2d_axis_sum::<T>(input, dimension).iter().map(|&x| x / n).collect()
     }
 }
+pub fn vec2d_weighted_average<T>(input: &[Vec<T>], weights: &Vec<T>) -> Vec<T>
+  where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> {
+    assert!(!input.is_empty() && input.len() == weights.len(), "Weights must match the rows for arguments in vec2d_weighted_average");
+    let total: T = nonsimd_sum::<T>(weights.as_slice());
+    let weighted = input.iter().zip(weights.iter())
+      .map(|(v,&w)| v.iter().map(|&x| x * w).collect::<Vec<T>>()).collect::<Vec<Vec<T>>>();
+    vec2d_axis_sum::<T>(&weighted, 0).iter().map(|&x| x / total).collect()
+}
+pub fn vec2d_axis_max<T>(input: &[Vec<T>]) -> Vec<T> 
+  where T: num_traits::Float {
+    assert!(!input.is_empty());
+    transpose_vec2d::<T>(input.to_owned()).iter()
+      .map(|v| v.iter().fold(T::neg_infinity(), |acc, &x| acc.max(x))).collect::<Vec<T>>()
+}
+#[allow(dead_code)]
 pub fn vec1d_normalize_mu1<T>(input: &Vec<T>) -> Vec<T> 
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32> {
-    let magnitude: T = vec1d_sum::<T>(&input);
+    let magnitude: T = vec1d_sum::<T>(input);
     input.iter().map(|x| *x/magnitude).collect::<Vec<T>>()
 }
-pub fn vec1d_normalize_mu2<T>(input: &Vec<T>) -> Vec<T> 
+#[allow(dead_code)]
+pub fn vec1d_normalize_mu2<T>(input: &[T]) -> Vec<T> 
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign 
     + num_traits::Zero + From<f32> + num_traits::Float {
     let magnitude: T = vec1d_sum::<T>(&input.iter().map(|&x|x.abs()).collect::<Vec<T>>());
     input.iter().map(|x| *x/magnitude).collect::<Vec<T>>()
 }
-pub fn vec1d_normalize_mu3<T>(input: &Vec<T>) -> Vec<T> 
+pub fn vec1d_normalize_mu3<T>(input: &[T]) -> Vec<T> 
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign 
     + num_traits::Zero + From<f32> + num_traits::Float {
     let magnitude: T = (vec1d_sum::<T>(&input.iter().map(|&x|x * x).collect::<Vec<T>>())).sqrt();
     input.iter().map(|x| *x/magnitude).collect::<Vec<T>>()
 }
-pub fn vec1d_binary_entropy<T>(input: &Vec<T>) -> T 
+pub fn vec1d_binary_entropy<T>(input: &[T]) -> T 
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32>  + Real {
-    <f32 as TryInto<T>>::try_into(1.0f32).unwrap() * nonsimd_sum::<T>(input.iter().map(|x| (*x)*x.log2()).collect::<Vec<T>>().as_slice())
+    <f32 as Into<T>>::into(1.0f32) * nonsimd_sum::<T>(input.iter().map(|x| (*x)*x.log2()).collect::<Vec<T>>().as_slice())
 }
-pub fn vec1d_normalize_binary_entropy<T>(input: &Vec<T>) -> T 
+pub fn vec1d_normalize_binary_entropy<T>(input: &[T]) -> T 
   where T: 'static + num_traits::Num + Copy + std::iter::Sum + std::ops::AddAssign + num_traits::Zero + From<f32>  + Real +  num_traits::Float {
     vec1d_binary_entropy::<T>(&vec1d_normalize_mu3::<T>(input))
 }
-pub fn embeddings_entropy(embeddings: &Vec<Vec<f32>>) -> Vec<f32> {
-  return embeddings.iter().map(|v| 
+pub fn embeddings_entropy(embeddings: &[Vec<f32>]) -> Vec<f32> {
+  embeddings.iter().map(|v| 
     vec1d_normalize_binary_entropy(&v.iter().map(|x| x.abs())
-    .collect::<Vec<f32>>())).collect::<Vec<f32>>();
+    .collect::<Vec<f32>>())).collect::<Vec<f32>>()
   // let negative_entropy = embeddings.iter().map(|v| 
   //   v.iter().filter(|&&vsplit| vsplit<0.0f32).collect::<Vec<&f32>>()).collect::<Vec<Vec<&f32>>>()
   //   .iter().map(|x| vec1d_normalize_binary_entropy::<f32>(&x.iter().map(|x| (-1.0f32)*(**x)).collect::<Vec<f32>>())).collect::<Vec<f32>>();
@@ -141,4 +166,183 @@ pub fn embeddings_entropy(embeddings: &Vec<Vec<f32>>) -> Vec<f32> {
   // println!("negative_entropy: {:?}",negative_entropy);
   // println!("positive_entropy: {:?}",positive_entropy);
   // (negative_entropy,positive_entropy)
-}
\ No newline at end of file
+}
+#[allow(clippy::needless_range_loop)]
+pub fn vec2d_covariance(input: &Vec<Vec<f64>>, mean: &[f64]) -> Vec<Vec<f64>> {
+  assert!(!input.is_empty());
+  let dlen = mean.len();
+  let mut covariance = vec![vec![0.0f64; dlen]; dlen];
+  for v in input {
+    let centered = v.iter().zip(mean.iter()).map(|(&x,&m)| x - m).collect::<Vec<f64>>();
+    for i in 0..dlen {
+      for j in i..dlen {
+        covariance[i][j] += centered[i] * centered[j];
+      }
+    }
+  }
+  let n = input.len() as f64;
+  for i in 0..dlen {
+    for j in i..dlen {
+      covariance[i][j] /= n;
+      covariance[j][i] = covariance[i][j];
+    }
+  }
+  covariance
+}
+// Eigenvalues and eigenvectors (one per row) of a symmetric matrix by cyclic Jacobi rotations, largest eigenvalue first
+#[allow(clippy::needless_range_loop)]
+pub fn symmetric_eigen(input: &[Vec<f64>]) -> (Vec<f64>, Vec<Vec<f64>>) {
+  const MAX_SWEEPS: usize = 50;
+  let n = input.len();
+  let mut a = input.to_owned();
+  let mut vt = (0..n).map(|i| (0..n).map(|j| if i==j {1.0f64} else {0.0f64}).collect::<Vec<f64>>()).collect::<Vec<Vec<f64>>>();
+  let scale = (0..n).map(|i| a[i][i].abs()).sum::<f64>().max(f64::MIN_POSITIVE);
+  for _ in 0..MAX_SWEEPS {
+    let off = (0..n).map(|p| ((p+1)..n).map(|q| a[p][q] * a[p][q]).sum::<f64>()).sum::<f64>();
+    if off.sqrt() <= 1e-12 * scale {
+      break;
+    }
+    for p in 0..n {
+      for q in (p+1)..n {
+        let apq = a[p][q];
+        if apq.abs() <= 1e-300 {
+          continue;
+        }
+        let theta = (a[q][q] - a[p][p]) / (2.0 * apq);
+        let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+        let c = 1.0 / (t * t + 1.0).sqrt();
+        let s = t * c;
+        for k in 0..n {
+          let (akp, akq) = (a[k][p], a[k][q]);
+          a[k][p] = c * akp - s * akq;
+          a[k][q] = s * akp + c * akq;
+        }
+        for k in 0..n {
+          let (apk, aqk) = (a[p][k], a[q][k]);
+          a[p][k] = c * apk - s * aqk;
+          a[q][k] = s * apk + c * aqk;
+        }
+        for k in 0..n {
+          let (vpk, vqk) = (vt[p][k], vt[q][k]);
+          vt[p][k] = c * vpk - s * vqk;
+          vt[q][k] = s * vpk + c * vqk;
+        }
+      }
+    }
+  }
+  let mut order = (0..n).collect::<Vec<usize>>();
+  order.sort_by(|&x,&y| a[y][y].total_cmp(&a[x][x]));
+  (order.iter().map(|&x| a[x][x]).collect(), order.iter().map(|&x| vt[x].clone()).collect())
+}
+
+// Explicit SIMD kernels of the f32 scoring paths, AVX2/FMA when the cpu has them, 8 lane accumulators otherwise
+#[cfg(target_arch = "x86_64")]
+mod avx {
+  use std::arch::x86_64::*;
+
+  unsafe fn horizontal_sum(v: __m256) -> f32 {
+    let mut lanes = [0.0f32; 8];
+    _mm256_storeu_ps(lanes.as_mut_ptr(), v);
+    lanes.iter().sum()
+  }
+  #[target_feature(enable = "avx2,fma")]
+  pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let (pa, pb) = (a.as_ptr(), b.as_ptr());
+    let mut acc0 = _mm256_setzero_ps();
+    let mut acc1 = _mm256_setzero_ps();
+    let mut i = 0;
+    while i + 16 <= n {
+      acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), acc0);
+      acc1 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i+8)), _mm256_loadu_ps(pb.add(i+8)), acc1);
+      i += 16;
+    }
+    if i + 8 <= n {
+      acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), acc0);
+      i += 8;
+    }
+    let mut sum = horizontal_sum(_mm256_add_ps(acc0, acc1));
+    while i < n {
+      sum += a[i] * b[i];
+      i += 1;
+    }
+    sum
+  }
+  #[target_feature(enable = "avx2,fma")]
+  pub unsafe fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let (pa, pb) = (a.as_ptr(), b.as_ptr());
+    let mut acc = _mm256_setzero_ps();
+    let mut i = 0;
+    while i + 8 <= n {
+      let diff = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+      acc = _mm256_fmadd_ps(diff, diff, acc);
+      i += 8;
+    }
+    let mut sum = horizontal_sum(acc);
+    while i < n {
+      sum += (a[i] - b[i]) * (a[i] - b[i]);
+      i += 1;
+    }
+    sum
+  }
+  #[target_feature(enable = "avx2,fma")]
+  pub unsafe fn l1(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let (pa, pb) = (a.as_ptr(), b.as_ptr());
+    let sign = _mm256_set1_ps(-0.0);
+    let mut acc = _mm256_setzero_ps();
+    let mut i = 0;
+    while i + 8 <= n {
+      let diff = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+      acc = _mm256_add_ps(_mm256_andnot_ps(sign, diff), acc);
+      i += 8;
+    }
+    let mut sum = horizontal_sum(acc);
+    while i < n {
+      sum += (a[i] - b[i]).abs();
+      i += 1;
+    }
+    sum
+  }
+}
+#[cfg(target_arch = "x86_64")]
+fn has_avx() -> bool {
+  is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+}
+fn lanes_reduce(a: &[f32], b: &[f32], op: impl Fn(f32,f32) -> f32) -> f32 {
+  let mut acc = [0.0f32; 8];
+  let chunks_a = a.chunks_exact(8);
+  let chunks_b = b.chunks_exact(8);
+  let remainder = chunks_a.remainder().iter().zip(chunks_b.remainder().iter()).map(|(&x,&y)| op(x,y)).sum::<f32>();
+  for (ca, cb) in chunks_a.zip(chunks_b) {
+    for i in 0..8 {
+      acc[i] += op(ca[i], cb[i]);
+    }
+  }
+  acc.iter().sum::<f32>() + remainder
+}
+pub fn simd_dot(a: &[f32], b: &[f32]) -> f32 {
+  assert!(a.len() == b.len(), "Vector lenghts must be equal for arguments in simd_dot");
+  #[cfg(target_arch = "x86_64")]
+  if has_avx() {
+    return unsafe { avx::dot(a, b) };
+  }
+  lanes_reduce(a, b, |x,y| x * y)
+}
+pub fn simd_squared_l2(a: &[f32], b: &[f32]) -> f32 {
+  assert!(a.len() == b.len(), "Vector lenghts must be equal for arguments in simd_squared_l2");
+  #[cfg(target_arch = "x86_64")]
+  if has_avx() {
+    return unsafe { avx::squared_l2(a, b) };
+  }
+  lanes_reduce(a, b, |x,y| (x - y) * (x - y))
+}
+pub fn simd_l1(a: &[f32], b: &[f32]) -> f32 {
+  assert!(a.len() == b.len(), "Vector lenghts must be equal for arguments in simd_l1");
+  #[cfg(target_arch = "x86_64")]
+  if has_avx() {
+    return unsafe { avx::l1(a, b) };
+  }
+  lanes_reduce(a, b, |x,y| (x - y).abs())
+}
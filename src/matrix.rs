@@ -0,0 +1,204 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use ndarray::Array2;
+
+use crate::catalogue;
+use crate::laws;
+use crate::mathematics;
+use crate::transformer::DistanceMetric;
+
+// Rows scored together, a block of the matrix stays in cache while every query of a batch goes over it
+const ROW_BLOCK: usize = 64;
+
+// Catalogue of a Book (for one model) as a contiguous row-major matrix, one row per catalogue
+pub struct BookMatrix {
+  pub indexes: Vec<laws::LawIndex>,
+  pub vectors: Array2<f32>,
+  pub norms: Vec<f32>,
+  // generation of the shard the matrix was built from, a newer shard makes it stale
+  pub generation: u64
+}
+
+lazy_static! {
+  static ref MATRICES_MEMORY: Mutex<HashMap<(laws::LawBook,String),Arc<BookMatrix>>> = Mutex::new(
+    HashMap::new()
+  );
+}
+
+// Cosine distance from a dot product, a zero vector (a blank text, a vector lost in whitening) is
+// orthogonal to everything instead of NaN, NaN would poison the sort of every rank
+pub fn cosine_distance(product: f32, norm_a: f32, norm_b: f32) -> f32 {
+  if norm_a == 0.0 || norm_b == 0.0 {
+    return 1.0;
+  }
+  1.0 - product / norm_a / norm_b
+}
+
+impl BookMatrix {
+  pub fn fabric(book: &laws::LawBook, model: &str) -> Result<BookMatrix, String> {
+    let generation = catalogue::book_generation(book);
+    let rows = catalogue::book_vectors(book, model)?;
+    let dims = rows.first().map(|x| x.1.len()).unwrap_or(0);
+    let rows = rows.into_iter().filter(|x| x.1.len() == dims).collect::<Vec<(laws::LawIndex,Vec<f32>)>>();
+    let data = rows.iter().flat_map(|x| x.1.iter().copied()).collect::<Vec<f32>>();
+    Ok(BookMatrix {
+      vectors: Array2::from_shape_vec((rows.len(), dims), data).unwrap(),
+      norms: rows.iter().map(|x| mathematics::simd_dot(&x.1, &x.1).sqrt()).collect(),
+      indexes: rows.into_iter().map(|x| x.0).collect(),
+      generation
+    })
+  }
+  pub fn len(&self) -> usize {
+    self.indexes.len()
+  }
+  fn row(&self, idx: usize) -> &[f32] {
+    let dims = self.vectors.ncols();
+    &self.vectors.as_slice().unwrap()[idx * dims..(idx + 1) * dims]
+  }
+  // Distance of one row to a query, lower is closer (same values as transformer::embeddings_vectors_distance)
+  fn distance(&self, metric: &DistanceMetric, idx: usize, query: &[f32], query_norm: f32) -> f32 {
+    let row = self.row(idx);
+    match metric {
+      DistanceMetric::Cosine       => cosine_distance(mathematics::simd_dot(row, query), self.norms[idx], query_norm),
+      DistanceMetric::Dot          => -mathematics::simd_dot(row, query),
+      DistanceMetric::Euclidean    => mathematics::simd_squared_l2(row, query).sqrt(),
+      DistanceMetric::Manhattan    => mathematics::simd_l1(row, query),
+      DistanceMetric::Minkowski(p) => mathematics::vector_minkowski_distance::<f32>(row, query, *p)
+    }
+  }
+  // Distances of every row to a query, in the order of indexes
+  pub fn score(&self, metric: &DistanceMetric, query: &[f32]) -> Vec<f32> {
+    let query_norm = mathematics::simd_dot(query, query).sqrt();
+    (0..self.len()).map(|x| self.distance(metric, x, query, query_norm)).collect()
+  }
+  // Distances of every row to each query; dot based metrics go through one matrix-matrix product,
+  // the others score the queries block by block of rows
+  pub fn score_batch(&self, metric: &DistanceMetric, queries: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    if queries.is_empty() || self.len() == 0 {
+      return queries.iter().map(|_| Vec::new()).collect();
+    }
+    let query_norms = queries.iter().map(|x| mathematics::simd_dot(x, x).sqrt()).collect::<Vec<f32>>();
+    match metric {
+      DistanceMetric::Cosine | DistanceMetric::Dot | DistanceMetric::Euclidean => {
+        let dims = self.vectors.ncols();
+        let flat = queries.iter().flat_map(|x| x.iter().copied()).collect::<Vec<f32>>();
+        let products = self.vectors.dot(&Array2::from_shape_vec((queries.len(), dims), flat).unwrap().t());
+        (0..queries.len()).map(|q| (0..self.len()).map(|r| {
+          let product = products[[r, q]];
+          match metric {
+            DistanceMetric::Cosine => cosine_distance(product, self.norms[r], query_norms[q]),
+            DistanceMetric::Dot    => -product,
+            // |a-b|^2 = |a|^2 + |b|^2 - 2 a.b
+            _ => (self.norms[r] * self.norms[r] + query_norms[q] * query_norms[q] - 2.0 * product).max(0.0).sqrt()
+          }
+        }).collect::<Vec<f32>>()).collect()
+      }
+      _ => {
+        let mut ret = queries.iter().map(|_| vec![0.0f32; self.len()]).collect::<Vec<Vec<f32>>>();
+        for start in (0..self.len()).step_by(ROW_BLOCK) {
+          let end = (start + ROW_BLOCK).min(self.len());
+          for ((query, query_norm), row) in queries.iter().zip(query_norms.iter()).zip(ret.iter_mut()) {
+            for (r, distance) in row.iter_mut().enumerate().take(end).skip(start) {
+              *distance = self.distance(metric, r, query, *query_norm);
+            }
+          }
+        }
+        ret
+      }
+    }
+  }
+  // Every row ranked against a query, closest first
+  pub fn rank(&self, metric: &DistanceMetric, query: &[f32]) -> Vec<(laws::LawIndex,f32)> {
+    let mut ranked = self.indexes.iter().cloned().zip(self.score(metric, query))
+      .collect::<Vec<(laws::LawIndex,f32)>>();
+    ranked.sort_by(|a,b| a.1.total_cmp(&b.1));
+    ranked
+  }
+}
+
+// Matrix of a Book for a model, built once per load
+pub fn book_matrix(book: &laws::LawBook, model: &str) -> Result<Arc<BookMatrix>, String> {
+  let key = (book.clone(), model.to_string());
+  if let Some(matrix) = MATRICES_MEMORY.lock().unwrap().get(&key).filter(|x| x.generation == catalogue::book_generation(book)) {
+    return Ok(matrix.clone());
+  }
+  let matrix = Arc::new(BookMatrix::fabric(book, model)?);
+  MATRICES_MEMORY.lock().unwrap().insert(key, matrix.clone());
+  Ok(matrix)
+}
+// Drops the matrices of a Book, they are built again on the next search
+pub fn forget_matrix(book: &laws::LawBook) {
+  MATRICES_MEMORY.lock().unwrap().retain(|(dbook,_),_| dbook != book);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transformer;
+
+  fn law_index(articulo: u16) -> laws::LawIndex {
+    laws::LawIndex {
+      book: laws::LawBook { pais: "test".to_string(), instrumento: "matrix".to_string() },
+      titulo: Some(1),
+      capitulo: Some(1),
+      articulo: Some(articulo),
+      parte: None
+    }
+  }
+  fn test_matrix(rows: &[Vec<f32>]) -> BookMatrix {
+    let dims = rows[0].len();
+    BookMatrix {
+      indexes: (0..rows.len()).map(|x| law_index(x as u16)).collect(),
+      vectors: Array2::from_shape_vec((rows.len(), dims), rows.iter().flatten().copied().collect()).unwrap(),
+      norms: rows.iter().map(|x| mathematics::simd_dot(x, x).sqrt()).collect(),
+      generation: 0
+    }
+  }
+  // deterministic vectors, some of them negative, of a length that is not a multiple of the simd lanes
+  fn vectors(count: usize, dims: usize, seed: usize) -> Vec<Vec<f32>> {
+    (0..count).map(|x| (0..dims).map(|y| (((x * 31 + y * 17 + seed) % 23) as f32 - 11.0) / 7.0).collect()).collect()
+  }
+
+  // the matrix kernels must give the distances of the per-item loop (what the bench command times)
+  #[test]
+  fn single_and_batch_scores_match_the_per_item_loop() {
+    let rows = vectors(150, 37, 3);
+    let queries = vectors(5, 37, 11);
+    let dmatrix = test_matrix(&rows);
+    for metric in [DistanceMetric::Cosine, DistanceMetric::Dot, DistanceMetric::Euclidean, DistanceMetric::Manhattan, DistanceMetric::Minkowski(3.0)] {
+      let batch = dmatrix.score_batch(&metric, &queries);
+      for (query, batch_scores) in queries.iter().zip(batch.iter()) {
+        let single = dmatrix.score(&metric, query);
+        for (idx, row) in rows.iter().enumerate() {
+          let expected = transformer::embeddings_vectors_distance(&metric, row, query);
+          let tolerance = 1e-3 * expected.abs().max(1.0);
+          assert!((single[idx] - expected).abs() <= tolerance, "{} single: {} != {}",metric.name(),single[idx],expected);
+          // the batch euclidean goes through |a|^2 + |b|^2 - 2 a.b, exact in its square only
+          let (batch_score, expected) = match metric {
+            DistanceMetric::Euclidean => (batch_scores[idx] * batch_scores[idx], expected * expected),
+            _ => (batch_scores[idx], expected)
+          };
+          let tolerance = 1e-3 * expected.abs().max(1.0);
+          assert!((batch_score - expected).abs() <= tolerance, "{} batch: {} != {}",metric.name(),batch_score,expected);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn zero_norms_rank_without_nan() {
+    let mut rows = vectors(4, 8, 5);
+    rows[2] = vec![0.0; 8];
+    let dmatrix = test_matrix(&rows);
+    let ranked = dmatrix.rank(&DistanceMetric::Cosine, &rows[0]);
+    assert_eq!(ranked[0].0, law_index(0));
+    assert!(ranked.iter().all(|x| !x.1.is_nan()));
+    // a zero query is as far from every row
+    let ranked = dmatrix.rank(&DistanceMetric::Cosine, &[0.0; 8]);
+    assert!(ranked.iter().all(|x| x.1 == 1.0));
+    let batch = dmatrix.score_batch(&DistanceMetric::Cosine, &Vec::from([rows[1].clone(), vec![0.0; 8]]));
+    assert_eq!(batch[0][2], 1.0);
+    assert!(batch[1].iter().all(|&x| x == 1.0));
+  }
+}
@@ -0,0 +1,124 @@
+use lazy_static::lazy_static;
+use std::fs;
+use std::sync::Mutex;
+use std::collections::HashMap;
+use rocket::serde::Deserialize;
+use rocket::serde::json;
+use tract_onnx::prelude::*;
+
+use crate::language;
+use crate::utils;
+
+// Sentence-transformers export input_ids, attention_mask and (BERT models) token_type_ids
+const MAX_SEQUENCE_LENGTH: usize = 512;
+
+// Pooling of the last hidden state, as set by the Pooling module of the sentence-transformers folder
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+enum Pooling {
+  Cls,
+  Mean,
+  Max,
+  MeanSqrtLen
+}
+// modules.json, the modules applied after the transformer
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SentenceModule {
+  path: String,
+  #[serde(rename = "type")]
+  module: String
+}
+// <pooling module>/config.json
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PoolingConfig {
+  #[serde(default)]
+  pooling_mode_cls_token: bool,
+  #[serde(default)]
+  pooling_mode_mean_tokens: bool,
+  #[serde(default)]
+  pooling_mode_max_tokens: bool,
+  #[serde(default)]
+  pooling_mode_mean_sqrt_len_tokens: bool
+}
+struct OnnxModel {
+  runnable: TypedRunnableModel<TypedModel>,
+  pooling: Pooling
+}
+
+lazy_static! {
+  // Model of each served language, by model
+  static ref ONNX_TRANSFORMERS: HashMap<String,Mutex<OnnxModel>> = utils::config_languages().iter()
+    .map(|x| utils::config_model_for_language(x))
+    .map(|x| (x.clone(), Mutex::new(load_onnx_model(&x)
+      .unwrap_or_else(|error| panic!("{} : {} : {}",utils::error_message("E0006"),x,error)))))
+    .collect::<HashMap<String,Mutex<OnnxModel>>>();
+}
+
+fn load_onnx_model(model: &str) -> TractResult<OnnxModel> {
+  let pooling = sentence_pooling(model)?;
+  let runnable = tract_onnx::onnx()
+    .model_for_path(utils::config_onnx_model_file(model))?
+    .into_optimized()?
+    .into_runnable()?;
+  Ok(OnnxModel { runnable, pooling })
+}
+
+// Pooling of the sentence-transformers modules of the model, mean pooling if it has no modules.json
+// Normalize is not needed, every backend normalizes its embeddings; Dense layers are not in the onnx graph
+// and are not applied here, such models are refused instead of producing vectors unlike the torch ones
+fn sentence_pooling(model: &str) -> TractResult<Pooling> {
+  let folder = utils::config_model_path_for(model);
+  let modules: Vec<SentenceModule> = match fs::read_to_string(format!("{}modules.json",folder)) {
+    Ok(content) => json::from_str(&content)?,
+    Err(_) => return Ok(Pooling::Mean)
+  };
+  if let Some(dense) = modules.iter().find(|x| x.module.ends_with(".Dense")) {
+    anyhow::bail!("the onnx backend does not apply the Dense module <{}>, export it within the onnx graph or use torch",dense.path);
+  }
+  let pooling = match modules.iter().find(|x| x.module.ends_with(".Pooling")) {
+    Some(pooling) => pooling,
+    None => return Ok(Pooling::Mean)
+  };
+  let config: PoolingConfig = json::from_str(&fs::read_to_string(format!("{}{}/config.json",folder,pooling.path))?)?;
+  match (config.pooling_mode_cls_token, config.pooling_mode_mean_tokens, config.pooling_mode_max_tokens, config.pooling_mode_mean_sqrt_len_tokens) {
+    (true, false, false, false) => Ok(Pooling::Cls),
+    (false, true, false, false) => Ok(Pooling::Mean),
+    (false, false, true, false) => Ok(Pooling::Max),
+    (false, false, false, true) => Ok(Pooling::MeanSqrtLen),
+    _ => anyhow::bail!("the onnx backend applies a single pooling mode, <{}/config.json> sets none or several",pooling.path)
+  }
+}
+
+// Last hidden state of one sentence, pooled over its tokens (no padding, every token counts)
+fn encode_sentence(model_id: &str, model: &OnnxModel, sentence: &str) -> TractResult<Vec<f32>> {
+  let ids = language::encode_ids(model_id, sentence, MAX_SEQUENCE_LENGTH);
+  let length = ids.len();
+  let input_ids: Tensor = tract_ndarray::Array2::from_shape_vec((1, length), ids)?.into();
+  let attention_mask: Tensor = tract_ndarray::Array2::<i64>::ones((1, length)).into();
+  let token_type_ids: Tensor = tract_ndarray::Array2::<i64>::zeros((1, length)).into();
+  let inputs = if model.runnable.model().inputs.len() > 2 {
+    tvec!(input_ids.into(), attention_mask.into(), token_type_ids.into())
+  } else {
+    tvec!(input_ids.into(), attention_mask.into())
+  };
+  let outputs = model.runnable.run(inputs)?;
+  let hidden = outputs[0].to_array_view::<f32>()?;
+  let hidden = hidden.into_dimensionality::<tract_ndarray::Ix3>()?;
+  let tokens = hidden.index_axis(tract_ndarray::Axis(0), 0);
+  Ok(match model.pooling {
+    Pooling::Cls => tokens.row(0).to_vec(),
+    Pooling::Mean => tokens.mean_axis(tract_ndarray::Axis(0)).unwrap().to_vec(),
+    Pooling::Max => tokens.fold_axis(tract_ndarray::Axis(0), f32::NEG_INFINITY, |a, b| a.max(*b)).to_vec(),
+    Pooling::MeanSqrtLen => tokens.sum_axis(tract_ndarray::Axis(0)).mapv(|x| x / (tokens.nrows() as f32).sqrt()).to_vec()
+  })
+}
+
+// Embeddings (not normalized) of the sentences, pooled as the sentence-transformers model says
+pub fn encode(model_id: &str, sentences: &[String]) -> TractResult<Vec<Vec<f32>>> {
+  let model = ONNX_TRANSFORMERS.get(model_id)
+    .ok_or(anyhow::anyhow!("model not served: {}",model_id))?
+    .lock().unwrap();
+  sentences.iter().map(|x| encode_sentence(model_id, &model, x)).collect()
+}
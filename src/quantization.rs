@@ -0,0 +1,253 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::catalogue;
+use crate::laws;
+use crate::mathematics;
+use crate::matrix;
+use crate::store;
+use crate::transformer;
+use crate::transformer::DistanceMetric;
+use crate::utils;
+use crate::whitening;
+
+// Compression of the catalogue vectors scored by the first stage of a search:
+// None (f32 matrix), Int8 (one byte per dimension, scaled per dimension),
+// Product (one byte per subspace, the nearest centroid of a codebook trained on the book)
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum Quantization {
+  None,
+  Int8,
+  Product
+}
+pub struct ScalarQuantizer {
+  pub minimum: Vec<f32>,
+  pub scale: Vec<f32>
+}
+pub struct ProductQuantizer {
+  pub subspaces: Vec<Range<usize>>,
+  pub codebooks: Vec<Vec<Vec<f32>>>
+}
+// Codes of every catalogue of a Book (for one model), row-major, width bytes per row
+pub struct QuantizedBook {
+  pub indexes: Vec<laws::LawIndex>,
+  pub codes: Vec<u8>,
+  pub width: usize,
+  pub norms: Vec<f32>,
+  pub scalar: Option<ScalarQuantizer>,
+  pub product: Option<ProductQuantizer>,
+  // generation of the shard the codes were built from, a newer shard makes them stale
+  pub generation: u64
+}
+
+lazy_static! {
+  static ref QUANTIZED_MEMORY: Mutex<HashMap<(laws::LawBook,String,String),Arc<QuantizedBook>>> = Mutex::new(
+    HashMap::new()
+  );
+}
+
+impl Quantization {
+  pub fn from_name(name: &str) -> Option<Quantization> {
+    match name.to_lowercase().as_str() {
+      "none"    => Some(Quantization::None),
+      "int8"    => Some(Quantization::Int8),
+      "product" => Some(Quantization::Product),
+      _ => None
+    }
+  }
+}
+
+// Every metric adds up over the dimensions, so it adds up over the subspaces of a vector:
+// partial is the contribution of a slice, combine turns the sum into the distance
+fn partial(metric: &DistanceMetric, a: &[f32], b: &[f32]) -> f32 {
+  match metric {
+    DistanceMetric::Cosine | DistanceMetric::Dot => mathematics::simd_dot(a, b),
+    DistanceMetric::Euclidean    => mathematics::simd_squared_l2(a, b),
+    DistanceMetric::Manhattan    => mathematics::simd_l1(a, b),
+    DistanceMetric::Minkowski(p) => a.iter().zip(b.iter()).map(|(&x,&y)| (x - y).abs().powf(*p)).sum()
+  }
+}
+fn combine(metric: &DistanceMetric, sum: f32, row_norm: f32, query_norm: f32) -> f32 {
+  match metric {
+    DistanceMetric::Cosine       => matrix::cosine_distance(sum, row_norm, query_norm),
+    DistanceMetric::Dot          => -sum,
+    DistanceMetric::Euclidean    => sum.max(0.0).sqrt(),
+    DistanceMetric::Manhattan    => sum,
+    DistanceMetric::Minkowski(p) => sum.max(0.0).powf(1.0 / p)
+  }
+}
+
+impl ScalarQuantizer {
+  pub fn fit(rows: &[Vec<f32>]) -> ScalarQuantizer {
+    let dims = rows[0].len();
+    let minimum = (0..dims).map(|d| rows.iter().map(|x| x[d]).fold(f32::INFINITY, f32::min)).collect::<Vec<f32>>();
+    let maximum = (0..dims).map(|d| rows.iter().map(|x| x[d]).fold(f32::NEG_INFINITY, f32::max)).collect::<Vec<f32>>();
+    ScalarQuantizer {
+      scale: minimum.iter().zip(maximum.iter()).map(|(&a,&b)| if b > a { (b - a) / 255.0 } else { 1.0 }).collect(),
+      minimum
+    }
+  }
+  pub fn encode(&self, row: &[f32]) -> Vec<u8> {
+    row.iter().enumerate().map(|(d,&x)| ((x - self.minimum[d]) / self.scale[d]).round().clamp(0.0, 255.0) as u8).collect()
+  }
+  pub fn decode_into(&self, codes: &[u8], out: &mut Vec<f32>) {
+    out.clear();
+    out.extend(codes.iter().enumerate().map(|(d,&c)| self.minimum[d] + self.scale[d] * c as f32));
+  }
+}
+
+impl ProductQuantizer {
+  // Lloyd iterations per subspace, the centroids start at evenly spaced rows
+  pub fn fit(rows: &[Vec<f32>], subspaces: usize, centroids: usize, iterations: usize) -> ProductQuantizer {
+    let dims = rows[0].len();
+    let subspaces = subspaces.clamp(1, dims);
+    let centroids = centroids.clamp(1, 256).min(rows.len());
+    let ranges = (0..subspaces).map(|j| (j * dims / subspaces)..((j + 1) * dims / subspaces)).collect::<Vec<Range<usize>>>();
+    let codebooks = ranges.iter().map(|range| {
+      let points = rows.iter().map(|x| &x[range.clone()]).collect::<Vec<&[f32]>>();
+      let mut codebook = (0..centroids).map(|c| points[c * points.len() / centroids].to_vec()).collect::<Vec<Vec<f32>>>();
+      for _ in 0..iterations {
+        let mut sums = vec![vec![0.0f32; range.len()]; centroids];
+        let mut counts = vec![0usize; centroids];
+        for point in points.iter() {
+          let nearest = nearest_centroid(&codebook, point);
+          counts[nearest] += 1;
+          sums[nearest].iter_mut().zip(point.iter()).for_each(|(s,&x)| *s += x);
+        }
+        // an empty cluster keeps its centroid
+        for c in 0..centroids {
+          if counts[c] > 0 {
+            codebook[c] = sums[c].iter().map(|&x| x / counts[c] as f32).collect();
+          }
+        }
+      }
+      codebook
+    }).collect::<Vec<Vec<Vec<f32>>>>();
+    ProductQuantizer {
+      subspaces: ranges,
+      codebooks
+    }
+  }
+  pub fn encode(&self, row: &[f32]) -> Vec<u8> {
+    self.subspaces.iter().zip(self.codebooks.iter())
+      .map(|(range, codebook)| nearest_centroid(codebook, &row[range.clone()]) as u8).collect()
+  }
+  // Asymmetric distance tables, the contribution of each centroid of each subspace to the query
+  pub fn tables(&self, metric: &DistanceMetric, query: &[f32]) -> Vec<Vec<f32>> {
+    self.subspaces.iter().zip(self.codebooks.iter())
+      .map(|(range, codebook)| codebook.iter().map(|x| partial(metric, x, &query[range.clone()])).collect()).collect()
+  }
+}
+fn nearest_centroid(codebook: &[Vec<f32>], point: &[f32]) -> usize {
+  codebook.iter().enumerate()
+    .map(|(c,x)| (c, mathematics::simd_squared_l2(x, point)))
+    .min_by(|a,b| a.1.total_cmp(&b.1)).map(|x| x.0).unwrap_or(0)
+}
+
+impl QuantizedBook {
+  pub fn fabric(book: &laws::LawBook, model: &str, quantization: &Quantization) -> Result<QuantizedBook, String> {
+    // the catalogues of a quantized book hold no vector, the full precision ones are read once to build the codes
+    let generation = catalogue::book_generation(book);
+    let (indexes, rows) = catalogue::book_vectors(book, model)?.into_iter()
+      .unzip::<laws::LawIndex,Vec<f32>,Vec<laws::LawIndex>,Vec<Vec<f32>>>();
+    let mut quantized = QuantizedBook {
+      norms: rows.iter().map(|x| mathematics::simd_dot(x, x).sqrt()).collect(),
+      indexes,
+      codes: Vec::new(),
+      width: 0,
+      scalar: None,
+      product: None,
+      generation
+    };
+    if rows.is_empty() {
+      return Ok(quantized);
+    }
+    match quantization {
+      Quantization::Product => {
+        let product = ProductQuantizer::fit(&rows, utils::config_pq_subspaces(), utils::config_pq_centroids(), utils::config_pq_iterations());
+        quantized.width = product.subspaces.len();
+        quantized.codes = rows.iter().flat_map(|x| product.encode(x)).collect();
+        quantized.product = Some(product);
+      }
+      _ => {
+        let scalar = ScalarQuantizer::fit(&rows);
+        quantized.width = rows[0].len();
+        quantized.codes = rows.iter().flat_map(|x| scalar.encode(x)).collect();
+        quantized.scalar = Some(scalar);
+      }
+    }
+    Ok(quantized)
+  }
+  // Bytes held per catalogue, codes and norm
+  pub fn bytes_per_vector(&self) -> usize {
+    self.width + std::mem::size_of::<f32>()
+  }
+  // Asymmetric distances, the query stays in full precision and only the catalogues are quantized
+  pub fn score(&self, metric: &DistanceMetric, query: &[f32]) -> Vec<f32> {
+    let query_norm = mathematics::simd_dot(query, query).sqrt();
+    let rows = self.codes.chunks_exact(self.width.max(1));
+    if let Some(product) = &self.product {
+      let tables = product.tables(metric, query);
+      return rows.zip(self.norms.iter()).map(|(codes, &norm)| {
+        let sum = codes.iter().zip(tables.iter()).map(|(&c,table)| table[c as usize]).sum::<f32>();
+        combine(metric, sum, norm, query_norm)
+      }).collect();
+    }
+    let scalar = self.scalar.as_ref().unwrap();
+    let mut decoded: Vec<f32> = Vec::with_capacity(self.width);
+    rows.zip(self.norms.iter()).map(|(codes, &norm)| {
+      scalar.decode_into(codes, &mut decoded);
+      combine(metric, partial(metric, &decoded, query), norm, query_norm)
+    }).collect()
+  }
+  pub fn rank(&self, metric: &DistanceMetric, query: &[f32]) -> Vec<(laws::LawIndex,f32)> {
+    let mut ranked = self.indexes.iter().cloned().zip(self.score(metric, query))
+      .collect::<Vec<(laws::LawIndex,f32)>>();
+    ranked.sort_by(|a,b| a.1.total_cmp(&b.1));
+    ranked
+  }
+}
+
+// Vector of a catalogue as written by the transformer, whitened as it is when loaded: from the snapshot
+// of its Book if it has one, else from the store
+pub fn full_precision(dindex: &laws::LawIndex, model: &str) -> Option<Vec<f32>> {
+  if let Some(vector) = catalogue::mapped_vector(dindex, model) {
+    return Some(vector);
+  }
+  let vector = store::store().get(dindex).ok()??.vector;
+  Some(whitening::whiten(&dindex.book, model, &vector))
+}
+// Scores the best candidates of a quantized ranking again with their full precision vectors
+// from the store, the ranking ends with them: approximate distances are not comparable with exact ones,
+// and calibration and grouping must only see exact ones. A candidate missing from the store is dropped
+pub fn rescore(ranked: Vec<(laws::LawIndex,f32)>, metric: &DistanceMetric, query: &[f32], model: &str, candidates: usize) -> Vec<(laws::LawIndex,f32)> {
+  let mut rescored = ranked.into_iter().take(candidates)
+    .filter_map(|(dindex, _)| full_precision(&dindex, model)
+      .map(|vector| {
+        let dscore = transformer::embeddings_vectors_distance(metric, query, &vector);
+        (dindex, dscore)
+      }))
+    .collect::<Vec<(laws::LawIndex,f32)>>();
+  rescored.sort_by(|a,b| a.1.total_cmp(&b.1));
+  rescored
+}
+
+// Quantized codes of a Book for a model, built once per load
+pub fn book_quantized(book: &laws::LawBook, model: &str, quantization: &Quantization) -> Result<Arc<QuantizedBook>, String> {
+  let key = (book.clone(), model.to_string(), format!("{:?}",quantization));
+  if let Some(quantized) = QUANTIZED_MEMORY.lock().unwrap().get(&key).filter(|x| x.generation == catalogue::book_generation(book)) {
+    return Ok(quantized.clone());
+  }
+  let quantized = Arc::new(QuantizedBook::fabric(book, model, quantization)?);
+  QUANTIZED_MEMORY.lock().unwrap().insert(key, quantized.clone());
+  Ok(quantized)
+}
+pub fn forget_quantized(book: &laws::LawBook) {
+  QUANTIZED_MEMORY.lock().unwrap().retain(|(dbook,_,_),_| dbook != book);
+}
@@ -0,0 +1,266 @@
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use rocket::serde::{Serialize, Deserialize};
+use walkdir::WalkDir;
+
+use crate::catalogue;
+use crate::calibration;
+use crate::files;
+use crate::laws;
+use crate::snapshot;
+use crate::store;
+use crate::utils;
+use crate::whitening;
+
+// Changes applied to one Book by a reload
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BookReload {
+  pub pais: String,
+  pub instrumento: String,
+  pub added: usize,
+  pub modified: usize,
+  pub removed: usize,
+  pub config_changed: bool
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReloadEvent {
+  pub trigger: String,
+  pub timestamp: u64,
+  pub duration_ms: f32,
+  pub books: Vec<BookReload>
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReloadStats {
+  pub reloads: u64,
+  pub events: Vec<ReloadEvent>
+}
+
+lazy_static! {
+  // One reload at a time, the watcher and POST /admin/reload may race
+  static ref RELOAD_LOCK: Mutex<()> = Mutex::new(());
+  static ref RELOAD_EVENTS: Mutex<VecDeque<ReloadEvent>> = Mutex::new(
+    VecDeque::new()
+  );
+  // Law configuration of every Book as of the last reload, a change drops what was derived with it
+  static ref LAW_CONFIGS: Mutex<HashMap<laws::LawBook,String>> = Mutex::new(
+    HashMap::new()
+  );
+}
+static RELOADS: AtomicU64 = AtomicU64::new(0);
+
+// Records the law configurations in use, changes are told apart from them
+pub fn remember_law_configs() {
+  let mut configs = LAW_CONFIGS.lock().unwrap();
+  for book in catalogue::memory_books() {
+    configs.insert(book.clone(), fs::read_to_string(files::law_config_filename(&book)).unwrap_or_default());
+  }
+}
+
+// Vector of a catalogue, read from the snapshot it was mapped from if it holds none
+fn mapped_or_resident(dcatalogue: &catalogue::Catalogue, mapped: &Option<Arc<snapshot::MappedSnapshot>>) -> Option<Vec<f32>> {
+  dcatalogue.dmeaning.embedding.vector.clone()
+    .or_else(|| mapped.as_ref()?.vector(&dcatalogue.dindex)
+      .map(|x| whitening::whiten(&dcatalogue.dindex.book, &dcatalogue.dmeaning.embedding.model, &x)))
+}
+// Catalogues added, modified and removed between the shard in memory and the one read from disk
+fn book_changes(book: &laws::LawBook, fresh: Option<&catalogue::BookRead>) -> (usize, usize, usize) {
+  let current = catalogue::book_shard(book);
+  let current_mapped = catalogue::book_snapshot(book);
+  let empty = HashMap::new();
+  let (fresh, fresh_mapped) = fresh.map(|x| (&x.0, x.1.clone())).unwrap_or((&empty, None));
+  // the same snapshot mapped again, none of its catalogues changed
+  if let (Some(x), Some(y)) = (current_mapped.as_ref(), fresh_mapped.as_ref()) {
+    if x.payload_hash() == y.payload_hash() && current.len() == fresh.len() && fresh.keys().all(|x| current.contains_key(x)) {
+      return (0, 0, 0);
+    }
+  }
+  let added = fresh.keys().filter(|x| !current.contains_key(x)).count();
+  let removed = current.keys().filter(|x| !fresh.contains_key(x)).count();
+  let modified = fresh.iter().filter(|(dindex,dcatalogue)| match current.get(dindex) {
+    Some(previous) => previous.dmeaning.phrase.text != dcatalogue.dmeaning.phrase.text
+      // a quantized book holds no vector in memory, a new vector of the same text and model goes unnoticed
+      || mapped_or_resident(previous, &current_mapped).map(|x| Some(x) != mapped_or_resident(dcatalogue, &fresh_mapped)).unwrap_or(false)
+      || previous.dmeaning.embedding.model != dcatalogue.dmeaning.embedding.model
+      || previous.dmeaning.embedding.template != dcatalogue.dmeaning.embedding.template
+      || previous.dmeaning.embedding.metric.name() != dcatalogue.dmeaning.embedding.metric.name(),
+    None => false
+  }).count();
+  (added, modified, removed)
+}
+
+// Reads every Book again (from its snapshot when it is still valid) and swaps all the changed shards at once
+pub fn reload(trigger: &str) -> ReloadEvent {
+  reload_books(trigger, None)
+}
+// Reads the given Books again (every one on None) and swaps all the changed shards at once
+pub fn reload_books(trigger: &str, scope: Option<Vec<laws::LawBook>>) -> ReloadEvent {
+  // a reload that panicked on a broken file leaves nothing half done, the next one may proceed
+  let _guard = RELOAD_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+  let now = Instant::now();
+  let books = match scope {
+    Some(books) => books,
+    None => {
+      let mut books = store::store().books().unwrap_or_else(|x| panic!("{}",x));
+      for book in catalogue::memory_books() {
+        if !books.contains(&book) {
+          books.push(book);
+        }
+      }
+      books
+    }
+  };
+  let mut shards: Vec<(laws::LawBook,Option<catalogue::BookRead>)> = Vec::new();
+  let mut changes: Vec<BookReload> = Vec::new();
+  for book in books {
+    if catalogue::is_staging(&book) {
+      println!("[Warning]: {}.{} is being ingested, it is reloaded once published",book.pais,book.instrumento);
+      continue;
+    }
+    let law_config = fs::read_to_string(files::law_config_filename(&book)).unwrap_or_default();
+    let config_changed = LAW_CONFIGS.lock().unwrap().insert(book.clone(), law_config.clone())
+      .map(|x| x != law_config).unwrap_or(false);
+    // a refitted whitening changes every vector of the book
+    whitening::forget_whitening(&book);
+    let fresh = catalogue::read_book(&book);
+    let (added, modified, removed) = book_changes(&book, fresh.as_ref());
+    if added + modified + removed == 0 && !config_changed {
+      continue;
+    }
+    println!("Reloading {}.{}: {} added, {} modified, {} removed{}",book.pais,book.instrumento,added,modified,removed,
+      if config_changed { ", law configuration changed" } else { "" });
+    if added + modified + removed > 0 {
+      shards.push((book.clone(), fresh));
+    } else {
+      catalogue::forget_book(&book);
+    }
+    changes.push(BookReload {
+      pais: book.pais.clone(),
+      instrumento: book.instrumento.clone(),
+      added,
+      modified,
+      removed,
+      config_changed
+    });
+  }
+  catalogue::publish_shards(shards);
+  // statistics are computed here rather than by the first search of a changed book
+  for change in changes.iter().filter(|x| x.added + x.modified > 0 || x.config_changed) {
+    let book = laws::LawBook {
+      pais: change.pais.clone(),
+      instrumento: change.instrumento.clone()
+    };
+    if let Err(error) = calibration::book_statistics(&book, &utils::config_law_metric(&book)) {
+      println!("[Warning]: no statistics for {}.{}, {}",book.pais,book.instrumento,error);
+    }
+  }
+  let event = ReloadEvent {
+    trigger: trigger.to_string(),
+    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+    duration_ms: now.elapsed().as_secs_f32() * 1000.0,
+    books: changes
+  };
+  println!("Reload ({}) done in {:.1} ms, {} books changed",event.trigger,event.duration_ms,event.books.len());
+  RELOADS.fetch_add(1, Ordering::Relaxed);
+  let mut events = RELOAD_EVENTS.lock().unwrap();
+  events.push_back(event.clone());
+  while events.len() > utils::config_reload_history() {
+    events.pop_front();
+  }
+  event
+}
+
+// Book of a folder or file named pais.instrumento[extension]
+fn book_of_name(name: &str, extension: &str) -> Option<laws::LawBook> {
+  name.strip_suffix(extension)?.split_once('.')
+    .map(|(pais, instrumento)| laws::LawBook {
+      pais: pais.to_string(),
+      instrumento: instrumento.to_string()
+    })
+}
+// Size and modification time of the files of each Book: those of its folder and its law configuration
+// (the law texts are only read by an ingestion). The sqlite store is a single file, a change in it
+// is told under None, as a change of every Book
+fn books_fingerprint() -> HashMap<Option<laws::LawBook>,String> {
+  let mut files: Vec<(Option<laws::LawBook>,walkdir::DirEntry)> = Vec::new();
+  // the snapshots lie beside the folders, a reload may write them
+  for dpath in WalkDir::new(utils::config_reference_folder()).min_depth(2).max_depth(2).into_iter().filter_map(|e| e.ok()) {
+    let book = dpath.path().parent().and_then(|x| x.file_name()).and_then(|x| x.to_str()).and_then(|x| book_of_name(x, ""));
+    if book.is_some() {
+      files.push((book, dpath));
+    }
+  }
+  for dpath in WalkDir::new(utils::config_laws_folder()).into_iter().filter_map(|e| e.ok()) {
+    let book = book_of_name(&utils::name_from_dir_entry(&dpath), &utils::config_law_config_extension());
+    if book.is_some() {
+      files.push((book, dpath));
+    }
+  }
+  if utils::config_store() == store::StoreKind::Sqlite {
+    for filename in [utils::config_sqlite_file(), format!("{}-wal",utils::config_sqlite_file())] {
+      files.extend(WalkDir::new(filename).into_iter().filter_map(|e| e.ok()).map(|x| (None, x)));
+    }
+  }
+  let mut fingerprints: HashMap<Option<laws::LawBook>,Vec<String>> = HashMap::new();
+  for (book, dpath) in files.into_iter().filter(|x| x.1.file_type().is_file()) {
+    let metadata = match dpath.metadata() {
+      Ok(metadata) => metadata,
+      Err(_) => continue
+    };
+    let modified = metadata.modified().ok().and_then(|x| x.duration_since(UNIX_EPOCH).ok()).map(|x| x.as_nanos()).unwrap_or(0);
+    fingerprints.entry(book).or_default().push(format!("{} {} {}",dpath.path().display(),metadata.len(),modified));
+  }
+  fingerprints.into_iter().map(|(book, mut lines)| {
+    lines.sort();
+    (book, lines.join("\n"))
+  }).collect()
+}
+// Polls the folders and reloads the Books whose files changed and then stayed unchanged for a whole interval,
+// so an ingestion still writing its files is not picked up halfway
+pub fn watch() {
+  let interval = match utils::config_reload_interval() {
+    Some(interval) => interval,
+    None => return
+  };
+  thread::Builder::new().name("tsahdu-reload".to_string())
+    .spawn(move || {
+      let mut loaded = books_fingerprint();
+      let mut previous = loaded.clone();
+      loop {
+        thread::sleep(interval);
+        let fingerprint = books_fingerprint();
+        if fingerprint != loaded && fingerprint == previous {
+          let changed = fingerprint.keys().chain(loaded.keys())
+            .filter(|x| fingerprint.get(*x) != loaded.get(*x))
+            .cloned().collect::<HashSet<Option<laws::LawBook>>>();
+          let scope = if changed.contains(&None) { None } else { Some(changed.into_iter().flatten().collect()) };
+          if std::panic::catch_unwind(|| reload_books("watch", scope)).is_err() {
+            println!("[Error]: reload failed, the catalogues in memory are kept");
+          }
+          loaded = fingerprint.clone();
+        }
+        previous = fingerprint;
+      }
+    })
+    .expect("unable to spawn the reload watcher");
+}
+
+pub fn stats() -> ReloadStats {
+  ReloadStats {
+    reloads: RELOADS.load(Ordering::Relaxed),
+    events: RELOAD_EVENTS.lock().unwrap().iter().cloned().collect()
+  }
+}
@@ -0,0 +1,176 @@
+#[cfg(feature = "torch")]
+pub use self::cross_encoder::*;
+#[cfg(not(feature = "torch"))]
+use std::time::Duration;
+#[cfg(not(feature = "torch"))]
+use crate::search;
+
+// The cross-encoder runs on libtorch, builds without it keep the first stage order
+#[cfg(not(feature = "torch"))]
+pub fn load() {
+}
+#[cfg(not(feature = "torch"))]
+pub fn rerank_hits(_query: &str, hits: Vec<search::SearchHit>, _count: usize, _budget: Duration) -> Vec<search::SearchHit> {
+  hits
+}
+
+#[cfg(feature = "torch")]
+mod cross_encoder {
+  use lazy_static::lazy_static;
+  use std::panic::{catch_unwind, AssertUnwindSafe};
+  use std::path::Path;
+  use std::sync::{mpsc, Mutex, PoisonError};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use rust_bert::Config;
+  use rust_bert::bert::{BertConfig, BertForSequenceClassification};
+  use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
+  use rust_tokenizers::vocab::{BertVocab, Vocab};
+  use tch::{nn, Kind, Tensor};
+
+  use crate::catalogue;
+  use crate::search;
+  use crate::utils;
+
+  // Pairs scored per forward pass
+  const RERANK_BATCH: usize = 8;
+  const MAX_PAIR_LENGTH: usize = 512;
+
+  pub struct CrossEncoder {
+    tokenizer: BertTokenizer,
+    model: BertForSequenceClassification,
+    device: tch::Device,
+    _var_store: nn::VarStore
+  }
+
+  lazy_static! {
+    static ref CROSS_ENCODER: Mutex<Option<CrossEncoder>> = Mutex::new(
+      load_cross_encoder()
+    );
+  }
+
+  // Loads the cross-encoder from models_path, None if the model is not installed or cannot be read
+  fn load_cross_encoder() -> Option<CrossEncoder> {
+    let model_path = utils::config_cross_encoder_path();
+    let config_file = format!("{}config.json",model_path);
+    let weights_file = format!("{}rust_model.ot",model_path);
+    let vocab_file = format!("{}{}",model_path,utils::config_vocab_filename());
+    if !(Path::new(&config_file).exists() && Path::new(&weights_file).exists() && Path::new(&vocab_file).exists()) {
+      println!("[Warning]: cross-encoder not found in <{}>, search keeps the first stage order",model_path);
+      return None;
+    }
+    let device = tch::Device::cuda_if_available();
+    let mut var_store = nn::VarStore::new(device);
+    // a corrupt model falls back to the first stage order as a missing one does
+    let model = match catch_unwind(AssertUnwindSafe(|| {
+      let config = BertConfig::from_file(&config_file);
+      BertForSequenceClassification::new(&var_store.root(), &config)
+    })) {
+      Ok(model) => model,
+      Err(_) => {
+        println!("[Warning]: {}: <{}>, search keeps the first stage order",utils::error_message("E0013"),config_file);
+        return None;
+      }
+    };
+    if let Err(reason) = var_store.load(&weights_file) {
+      println!("[Warning]: {}: <{}> {}, search keeps the first stage order",utils::error_message("E0013"),weights_file,reason);
+      return None;
+    }
+    let tokenizer = match BertTokenizer::from_file(&vocab_file, utils::config_cross_encoder_lowercase(), false) {
+      Ok(tokenizer) => tokenizer,
+      Err(reason) => {
+        println!("[Warning]: {}: <{}> {}, search keeps the first stage order",utils::error_message("E0001"),vocab_file,reason);
+        return None;
+      }
+    };
+    Some(CrossEncoder {
+      tokenizer: tokenizer,
+      model: model,
+      device: device,
+      _var_store: var_store
+    })
+  }
+
+  impl CrossEncoder {
+    // Relevance of each (query, passage) pair, higher is more relevant
+    pub fn score(&self, query: &str, passages: &[String]) -> Vec<f32> {
+      let pairs = passages.iter().map(|x| (query, x.as_str())).collect::<Vec<(&str,&str)>>();
+      let tokenized = self.tokenizer.encode_pair_list(&pairs, MAX_PAIR_LENGTH, &TruncationStrategy::LongestFirst, 0);
+      let max_len = tokenized.iter().map(|x| x.token_ids.len()).max().unwrap_or(0);
+      let pad_id = self.tokenizer.vocab().token_to_id(BertVocab::pad_value());
+      let token_ids = tokenized.iter().map(|x| {
+        let mut ids = x.token_ids.clone();
+        ids.resize(max_len, pad_id);
+        Tensor::of_slice(&ids)
+      }).collect::<Vec<Tensor>>();
+      let segment_ids = tokenized.iter().map(|x| {
+        let mut ids = x.segment_ids.iter().map(|&y| y as i64).collect::<Vec<i64>>();
+        ids.resize(max_len, 0);
+        Tensor::of_slice(&ids)
+      }).collect::<Vec<Tensor>>();
+      let input_ids = Tensor::stack(&token_ids, 0).to(self.device);
+      let token_type_ids = Tensor::stack(&segment_ids, 0).to(self.device);
+      let mask = input_ids.ne(pad_id).to_kind(Kind::Int64);
+      let logits = tch::no_grad(|| {
+        self.model.forward_t(Some(&input_ids), Some(&mask), Some(&token_type_ids), None, None, false).logits
+      });
+      // Single label models output a relevance logit, two label models the logit of the relevant class
+      let labels = logits.size()[1];
+      let relevance = if labels == 1 { logits.select(1, 0) } else { logits.log_softmax(-1, Kind::Float).select(1, labels - 1) };
+      Vec::<f32>::from(&relevance.to_kind(Kind::Float).to(tch::Device::Cpu))
+    }
+  }
+
+  // Loads the cross-encoder (if re-ranking is enabled) before the first search needs it
+  pub fn load() {
+    if utils::config_rerank_count() > 0 {
+      lazy_static::initialize(&CROSS_ENCODER);
+    }
+  }
+
+  // Re-scores the first count hits against the query text with the cross-encoder, the rest keep
+  // their place; falls back to the first stage order if the model is missing or the budget runs out
+  pub fn rerank_hits(query: &str, hits: Vec<search::SearchHit>, count: usize, budget: Duration) -> Vec<search::SearchHit> {
+    let now = Instant::now();
+    if count == 0 || hits.is_empty() {
+      return hits;
+    }
+    let mut hits = hits;
+    let rest = if hits.len() > count { hits.split_off(count) } else { Vec::new() };
+    let passages = hits.iter().map(|x| catalogue::memory_catalogue(&x.best)
+      .map(|y| y.dmeaning.phrase.text).unwrap_or_default()).collect::<Vec<String>>();
+    // the scoring runs apart so that the search waits for the budget at most, a late result is dropped
+    let (sender, receiver) = mpsc::channel::<Vec<f32>>();
+    let query = query.to_string();
+    thread::spawn(move || {
+      let guard = CROSS_ENCODER.lock().unwrap_or_else(PoisonError::into_inner);
+      if let Some(encoder) = guard.as_ref() {
+        let scores = passages.chunks(RERANK_BATCH).flat_map(|x| encoder.score(&query, x)).collect::<Vec<f32>>();
+        sender.send(scores).ok();
+      }
+    });
+    let scores = match receiver.recv_timeout(budget.saturating_sub(now.elapsed())) {
+      Ok(scores) => scores,
+      Err(mpsc::RecvTimeoutError::Timeout) => {
+        println!("[Warning]: cross-encoder budget of {:?} exceeded, search keeps the first stage order",budget);
+        hits.extend(rest);
+        return hits;
+      }
+      // no cross-encoder installed
+      Err(mpsc::RecvTimeoutError::Disconnected) => {
+        hits.extend(rest);
+        return hits;
+      }
+    };
+    let mut scored = hits.into_iter().zip(scores.into_iter()).map(|(mut hit, dscore)| {
+      hit.rerank_score = Some(dscore);
+      hit
+    }).collect::<Vec<search::SearchHit>>();
+    // a NaN score goes last
+    let relevance = |x: &search::SearchHit| x.rerank_score.filter(|y| !y.is_nan()).unwrap_or(f32::NEG_INFINITY);
+    scored.sort_by(|a,b| relevance(b).total_cmp(&relevance(a)));
+    scored.extend(rest);
+    return scored;
+  }
+}
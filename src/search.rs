@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::calibration;
+use crate::catalogue;
+use crate::explain;
+use crate::laws;
+use crate::store;
+use crate::transformer;
+use crate::utils;
+
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum GroupLevel {
+  Parte,
+  Articulo,
+  Capitulo
+}
+// Max keeps the score of the best (closest) part, Mean averages the scores of all the parts
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ScoreAggregate {
+  Max,
+  Mean
+}
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum SearchMode {
+  Flat,
+  Hierarchical
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchHit {
+  pub dindex: laws::LawIndex,
+  pub breadcrumb: String,
+  pub best: laws::LawIndex,
+  pub score: f32,
+  pub best_score: f32,
+  pub rerank_score: Option<f32>,
+  pub calibrated: Option<calibration::Calibrated>,
+  pub parts: usize,
+  pub span: Option<(usize,usize)>,
+  pub highlights: Vec<explain::Highlight>
+}
+
+impl GroupLevel {
+  pub fn from_name(name: &str) -> Option<GroupLevel> {
+    match name.to_lowercase().as_str() {
+      "parte"    => Some(GroupLevel::Parte),
+      "articulo" => Some(GroupLevel::Articulo),
+      "capitulo" => Some(GroupLevel::Capitulo),
+      _ => None
+    }
+  }
+}
+impl SearchMode {
+  pub fn from_name(name: &str) -> Option<SearchMode> {
+    match name.to_lowercase().as_str() {
+      "flat"         => Some(SearchMode::Flat),
+      "hierarchical" => Some(SearchMode::Hierarchical),
+      _ => None
+    }
+  }
+}
+impl ScoreAggregate {
+  pub fn from_name(name: &str) -> Option<ScoreAggregate> {
+    match name.to_lowercase().as_str() {
+      "max"  => Some(ScoreAggregate::Max),
+      "mean" => Some(ScoreAggregate::Mean),
+      _ => None
+    }
+  }
+}
+
+// The index a hit collapses into for a given level of grouping
+pub fn group_index(dindex: &laws::LawIndex, level: &GroupLevel) -> laws::LawIndex {
+  let mut gindex = dindex.clone();
+  match level {
+    GroupLevel::Parte    => {}
+    GroupLevel::Articulo => {gindex.parte = None;}
+    GroupLevel::Capitulo => {gindex.parte = None; gindex.articulo = None;}
+  }
+  gindex
+}
+
+// Character offsets of the window of a part inside the full text of its article
+pub fn part_span(dindex: &laws::LawIndex) -> Option<(usize,usize)> {
+  let part_text = catalogue::memory_catalogue(dindex).map(|x| x.dmeaning.phrase.text)?;
+  if dindex.parte.is_none() {
+    return Some((0, part_text.chars().count()));
+  }
+  let article_text = store::store().get_text(&laws::LawIndex { parte: None, ..dindex.clone() }).ok().flatten()?;
+  let start = article_text.find(part_text.as_str())?;
+  let start_chars = article_text[..start].chars().count();
+  Some((start_chars, start_chars + part_text.chars().count()))
+}
+
+// Collapses the ranked parts (closest first) into one hit per group, best groups first
+pub fn group_results(ranked: &Vec<(laws::LawIndex,f32)>, level: &GroupLevel, aggregate: &ScoreAggregate) -> Vec<SearchHit> {
+  let mut order: Vec<laws::LawIndex> = Vec::new();
+  let mut groups: HashMap<laws::LawIndex,Vec<(laws::LawIndex,f32)>> = HashMap::new();
+  for (dindex, dscore) in ranked {
+    let gindex = group_index(dindex, level);
+    if !groups.contains_key(&gindex) {
+      order.push(gindex.clone());
+    }
+    groups.entry(gindex).or_default().push((dindex.clone(), *dscore));
+  }
+  let mut hits = order.iter().map(|gindex| {
+    // ranked is sorted, the first member of a group is its best part
+    let members = &groups[gindex];
+    let (best, best_score) = members[0].clone();
+    let score = match aggregate {
+      ScoreAggregate::Max  => best_score,
+      ScoreAggregate::Mean => members.iter().map(|x| x.1).sum::<f32>() / members.len() as f32
+    };
+    SearchHit {
+      dindex: gindex.clone(),
+      breadcrumb: breadcrumb(&best),
+      best: best.clone(),
+      score,
+      best_score,
+      rerank_score: None,
+      calibrated: None,
+      parts: members.len(),
+      span: None,
+      highlights: Vec::new()
+    }
+  }).collect::<Vec<SearchHit>>();
+  hits.sort_by(|a,b| a.score.total_cmp(&b.score));
+  hits
+}
+
+// Keeps the hits clearing the threshold and fills their calibrated scores against the statistics of the book
+pub fn calibrate_hits(hits: Vec<SearchHit>, statistics: &calibration::BookStatistics, unit: &calibration::ScoreUnit, threshold: f32) -> Vec<SearchHit> {
+  hits.into_iter()
+    .filter(|x| calibration::passes(statistics, x.score, unit, threshold))
+    .map(|mut x| {
+      x.calibrated = Some(statistics.calibrate(x.score));
+      x
+    }).collect()
+}
+
+// Fills the offsets of the best window of each hit, meant for the few hits returned
+pub fn locate_hits(hits: &mut [SearchHit]) {
+  for hit in hits.iter_mut() {
+    hit.span = part_span(&hit.best);
+  }
+}
+
+// Fills the sentences of the article of each hit that best explain the match
+pub fn explain_hits(model: &str, query: &[f32], hits: &mut [SearchHit], count: usize) {
+  for hit in hits.iter_mut() {
+    hit.highlights = explain::explain_hit(model, query, &hit.best, count);
+  }
+}
+
+// Maximal Marginal Relevance, picks hits trading their relevance to the query against
+// their redundancy with the hits already picked, lambda = 1.0 keeps the plain ranking.
+// Both are measured with the metric of the search, a closer vector is a more similar one
+pub fn mmr_rerank(query: &Vec<f32>, hits: Vec<SearchHit>, metric: &transformer::DistanceMetric, lambda: f32, count: usize) -> Vec<SearchHit> {
+  if lambda >= 1.0 || hits.len() <= 1 {
+    return hits.into_iter().take(count).collect();
+  }
+  let similarity = |a: &Vec<f32>, b: &Vec<f32>| Some(-transformer::embeddings_vectors_distance(metric, a, b)).filter(|x| !x.is_nan());
+  let vectors = hits.iter().map(|x| catalogue::catalogue_vector(&x.best)).collect::<Vec<Option<Vec<f32>>>>();
+  // a hit without a vector, or with an undefined distance (a zero vector), is picked last
+  let relevance = vectors.iter().map(|x| x.as_ref().and_then(|v| similarity(query, v)).unwrap_or(f32::MIN)).collect::<Vec<f32>>();
+  let mut picked: Vec<usize> = Vec::new();
+  let mut pending: Vec<usize> = (0..hits.len()).collect();
+  while picked.len() < count && !pending.is_empty() {
+    let (pos, _) = pending.iter().enumerate().map(|(pos, &cand)| {
+      let redundancy = picked.iter().filter_map(|&sel| 
+        match (&vectors[cand], &vectors[sel]) {
+          (Some(a), Some(b)) => similarity(a, b),
+          _ => None
+        }).reduce(f32::max).unwrap_or(0.0);
+      (pos, lambda * relevance[cand] - (1.0 - lambda) * redundancy)
+    }).max_by(|a,b| a.1.total_cmp(&b.1)).unwrap();
+    picked.push(pending.remove(pos));
+  }
+  let mut hits = hits.into_iter().map(Some).collect::<Vec<Option<SearchHit>>>();
+  picked.iter().map(|&x| hits[x].take().unwrap()).collect()
+}
+
+// Human readable location of an index, "Título II › Capítulo 1 › Art. 23"
+pub fn breadcrumb(dindex: &laws::LawIndex) -> String {
+  let mut crumbs: Vec<String> = Vec::new();
+  if let Some(titulo) = dindex.titulo {
+    crumbs.push(format!("Título {}",utils::roman_numeral(titulo)));
+  }
+  if let Some(capitulo) = dindex.capitulo {
+    crumbs.push(format!("Capítulo {}",capitulo));
+  }
+  if let Some(articulo) = dindex.articulo {
+    crumbs.push(format!("Art. {}",articulo));
+  }
+  if let Some(parte) = dindex.parte {
+    crumbs.push(format!("Parte {}",parte));
+  }
+  crumbs.join(" › ")
+}
+
+// Human readable location of a subtree, the articles outside of a Title or Chapter are named so
+pub fn subtree_breadcrumb(book: &laws::LawBook, centroid: &laws::Centroid) -> String {
+  let mut crumbs: Vec<String> = Vec::new();
+  crumbs.push(match centroid.titulo {
+    Some(_) => breadcrumb(&laws::LawIndex { book: book.clone(), titulo: centroid.titulo, capitulo: None, articulo: None, parte: None }),
+    None => "Sin título".to_string()
+  });
+  if !centroid.whole {
+    crumbs.push(match centroid.capitulo {
+      Some(capitulo) => format!("Capítulo {}",capitulo),
+      None => "Sin capítulo".to_string()
+    });
+  }
+  crumbs.join(" › ")
+}
+
+// Ranks the Chapters (or Titles without chapters) of a Book by centroid distance, coarse to fine:
+// first the Titles, then the Chapters inside the best Titles, keeping the best subtrees
+pub fn rank_subtrees(metric: &transformer::DistanceMetric, embedding: &[f32], book: &laws::LawBook, subtrees: usize) -> Result<Vec<(laws::Centroid,f32)>, String> {
+  let centroids = laws::book_centroids(book)?;
+  let mut titles = centroids.iter().filter(|x| x.whole)
+    .map(|x| (x.clone(), transformer::embeddings_vectors_distance(metric, embedding, &x.vector)))
+    .collect::<Vec<(laws::Centroid,f32)>>();
+  titles.sort_by(|a,b| a.1.total_cmp(&b.1));
+  let mut ranked: Vec<(laws::Centroid,f32)> = Vec::new();
+  for (dtitle, dscore) in titles.into_iter().take(subtrees) {
+    let chapters = centroids.iter().filter(|x| x.titulo == dtitle.titulo && !x.whole)
+      .map(|x| (x.clone(), transformer::embeddings_vectors_distance(metric, embedding, &x.vector)))
+      .collect::<Vec<(laws::Centroid,f32)>>();
+    if chapters.is_empty() {
+      ranked.push((dtitle, dscore));
+    } else {
+      ranked.extend(chapters);
+    }
+  }
+  ranked.sort_by(|a,b| a.1.total_cmp(&b.1));
+  Ok(ranked.into_iter().take(subtrees).collect())
+}
+
+// Catalogues ranked inside the subtrees taken, and the breadcrumbs of those subtrees with their distances
+pub type HierarchicalRanking = (Vec<(laws::LawIndex,f32)>, Vec<(String,f32)>);
+// Ranks only the catalogues inside the best subtrees of a Book, returns the subtrees taken
+pub fn rank_hierarchical(embedding: &transformer::Embedding, book: &laws::LawBook, subtrees: usize) -> Result<HierarchicalRanking, String> {
+  let query = embedding.vector.clone().unwrap();
+  let path = rank_subtrees(&embedding.metric, &query, book, subtrees)?;
+  // scoring the whole book is cheaper than gathering the rows of the subtrees
+  let aux = catalogue::rank_embedding_against_law_book_within(embedding, book, |dindex| path.iter().any(|(x,_)| 
+      dindex.titulo==x.titulo && (x.whole || dindex.capitulo==x.capitulo)))?;
+  let path = path.iter().map(|(x,dscore)| (subtree_breadcrumb(book, x), *dscore)).collect::<Vec<(String,f32)>>();
+  Ok((aux, path))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::language;
+
+  fn law_index(instrumento: &str, capitulo: u16, articulo: u16, parte: Option<u16>) -> laws::LawIndex {
+    laws::LawIndex {
+      book: laws::LawBook { pais: "test".to_string(), instrumento: instrumento.to_string() },
+      titulo: Some(1),
+      capitulo: Some(capitulo),
+      articulo: Some(articulo),
+      parte
+    }
+  }
+  fn ranked() -> Vec<(laws::LawIndex,f32)> {
+    Vec::from([
+      (law_index("search", 1, 1, Some(0)), 0.1),
+      (law_index("search", 1, 2, None), 0.2),
+      (law_index("search", 1, 1, Some(2)), 0.3),
+      (law_index("search", 2, 3, None), 0.4),
+      (law_index("search", 1, 1, Some(1)), 0.9)])
+  }
+
+  #[test]
+  fn parts_collapse_into_their_article() {
+    let hits = group_results(&ranked(), &GroupLevel::Articulo, &ScoreAggregate::Max);
+    assert_eq!(hits.iter().map(|x| x.dindex.articulo).collect::<Vec<Option<u16>>>(), Vec::from([Some(1), Some(2), Some(3)]));
+    assert_eq!(hits[0].dindex, law_index("search", 1, 1, None));
+    assert_eq!(hits[0].best, law_index("search", 1, 1, Some(0)));
+    assert_eq!(hits[0].parts, 3);
+    assert_eq!(hits[0].score, 0.1);
+    assert_eq!(hits[0].breadcrumb, "Título I › Capítulo 1 › Art. 1 › Parte 0");
+    // by parte nothing is collapsed
+    assert_eq!(group_results(&ranked(), &GroupLevel::Parte, &ScoreAggregate::Max).len(), 5);
+  }
+
+  #[test]
+  fn mean_aggregate_ranks_by_the_average_of_the_parts() {
+    let hits = group_results(&ranked(), &GroupLevel::Articulo, &ScoreAggregate::Mean);
+    // article 1 averages 0.1, 0.3 and 0.9, it falls behind articles 2 and 3
+    assert_eq!(hits.iter().map(|x| x.dindex.articulo).collect::<Vec<Option<u16>>>(), Vec::from([Some(2), Some(3), Some(1)]));
+    assert!((hits[2].score - 1.3 / 3.0).abs() < 1e-6);
+    assert_eq!(hits[2].best_score, 0.1);
+    let chapters = group_results(&ranked(), &GroupLevel::Capitulo, &ScoreAggregate::Max);
+    assert_eq!(chapters.len(), 2);
+    assert_eq!(chapters[0].parts, 4);
+    assert_eq!(chapters[0].dindex, laws::LawIndex { articulo: None, ..law_index("search", 1, 1, None) });
+  }
+
+  #[test]
+  fn mmr_trades_relevance_for_diversity() {
+    let book = laws::LawBook { pais: "test".to_string(), instrumento: "mmr".to_string() };
+    // the second one is the most relevant, the first one nearly repeats it
+    let vectors = [[1.0, 0.0], [0.995, 0.0998], [0.0, 1.0]];
+    let catalogues = vectors.iter().enumerate().map(|(idx, vector)| {
+      let dindex = law_index("mmr", 1, idx as u16, None);
+      (dindex.clone(), catalogue::catalogue_fabric(dindex.book.pais.clone(), dindex.book.instrumento.clone(),
+        dindex.titulo, dindex.capitulo, dindex.articulo, None, &language::phrase_fabric(format!("articulo {}",idx)),
+        transformer::EmbeddingType::Total, transformer::PoolingStrategy::Mean, "test".to_string(), String::new(),
+        transformer::DistanceMetric::Cosine, &Some(vector.to_vec())))
+    }).collect::<HashMap<laws::LawIndex,catalogue::Catalogue>>();
+    catalogue::publish_shard(&book, catalogues);
+    let hits = group_results(&(0..3).map(|x| (law_index("mmr", 1, x, None), x as f32)).collect(), &GroupLevel::Articulo, &ScoreAggregate::Max);
+    let query = Vec::from([0.8, 0.6]);
+    let articles = |hits: Vec<SearchHit>| hits.iter().map(|x| x.dindex.articulo.unwrap()).collect::<Vec<u16>>();
+    // lambda 1 keeps the ranking, cut to count
+    assert_eq!(articles(mmr_rerank(&query, hits.clone(), &transformer::DistanceMetric::Cosine, 1.0, 2)), Vec::from([0, 1]));
+    // the near duplicate of the most relevant hit gives way to a less relevant one
+    assert_eq!(articles(mmr_rerank(&query, hits.clone(), &transformer::DistanceMetric::Cosine, 0.5, 2)), Vec::from([1, 2]));
+    assert_eq!(articles(mmr_rerank(&query, hits, &transformer::DistanceMetric::Cosine, 0.5, 5)), Vec::from([1, 2, 0]));
+  }
+
+  #[test]
+  fn mmr_picks_a_zero_vector_last() {
+    let book = laws::LawBook { pais: "test".to_string(), instrumento: "mmrzero".to_string() };
+    // the cosine distance to the zero vector is undefined
+    let vectors = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+    let catalogues = vectors.iter().enumerate().map(|(idx, vector)| {
+      let dindex = law_index("mmrzero", 1, idx as u16, None);
+      (dindex.clone(), catalogue::catalogue_fabric(dindex.book.pais.clone(), dindex.book.instrumento.clone(),
+        dindex.titulo, dindex.capitulo, dindex.articulo, None, &language::phrase_fabric(format!("articulo {}",idx)),
+        transformer::EmbeddingType::Total, transformer::PoolingStrategy::Mean, "test".to_string(), String::new(),
+        transformer::DistanceMetric::Cosine, &Some(vector.to_vec())))
+    }).collect::<HashMap<laws::LawIndex,catalogue::Catalogue>>();
+    catalogue::publish_shard(&book, catalogues);
+    let hits = group_results(&(0..3).map(|x| (law_index("mmrzero", 1, x, None), x as f32)).collect(), &GroupLevel::Articulo, &ScoreAggregate::Max);
+    let picked = mmr_rerank(&Vec::from([1.0, 0.0]), hits, &transformer::DistanceMetric::Cosine, 0.5, 3);
+    assert_eq!(picked.iter().map(|x| x.dindex.articulo.unwrap()).collect::<Vec<u16>>(), Vec::from([1, 2, 0]));
+  }
+}
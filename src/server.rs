@@ -1,14 +1,24 @@
 // use rocket::http::{Status, ContentType};
 // use rocket::form::{Form, Contextual, FromForm, FromFormField, Context};
+use std::path::Path;
+use std::thread;
 use rocket::serde::{Serialize, Deserialize};
 use rocket::serde::json::{Json, Value, json};
 
 use crate::utils;
+use crate::files;
 use crate::laws;
 use crate::language;
 use crate::transformer;
 use crate::catalogue;
 use crate::mathematics;
+use crate::search;
+use crate::reranker;
+use crate::cache;
+use crate::batching;
+use crate::whitening;
+use crate::calibration;
+use crate::reload;
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -20,11 +30,25 @@ struct CompareRequest {
 #[serde(crate = "rocket::serde")]
 struct SearchRequest {
   phrase: language::Phrase,
+  pais: String,
+  instrumento: String,
+  group: Option<String>,
+  score: Option<String>,
+  mmr_lambda: Option<f32>,
+  mode: Option<String>,
+  explain: Option<bool>,
+  language: Option<String>,
+  metric: Option<String>
+}
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct IngestRequest {
   pais: String,
   instrumento: String
 }
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
+#[allow(dead_code)]
 struct InformRequest {
   phrase: language::Phrase,
   pais: String,
@@ -77,46 +101,176 @@ fn ping() -> String {
   // println!("average: 0 : {:?}",mathematics::vec2d_axis_average::<f32>(encds.clone(),0));
   // println!("sum: 1 : {:?}",mathematics::vec2d_axis_sum::<f32>(encds.clone(),1));
   // println!("average: 1 : {:?}",mathematics::vec2d_axis_average::<f32>(encds.clone(),1));
-  return String::from("pong");
+  String::from("pong")
 }
 
 #[post("/search", format="json", data = "<payload>")]
-fn phrase_search_post(payload: Json<SearchRequest>) -> String {//Json<Vec<(laws::LawIndex,f32)>> {
-  // Generate Embeddings
-  let embedding = transformer::transform_phrase(&payload.phrase);
-  if embedding.is_some() {
-    // Compare against LawBook
-    dbg!(catalogue::compare_embedding_against_law_book(
-      &transformer::Embedding {
-        vector:embedding.clone(),
-        etype: transformer::EmbeddingType::Total
-      }, 
-      &laws::LawBook {
-        pais: payload.pais.clone(),
-        instrumento: payload.instrumento.clone()
-      }));
-    "ok".to_string()
-  } else {
-    "phrase is too short, not undersootd".to_string()
+async fn phrase_search_post(payload: Json<SearchRequest>) -> Value {
+  let level = match &payload.group {
+    None => utils::config_group_level(),
+    Some(x) => match search::GroupLevel::from_name(x) {
+      Some(level) => level,
+      None => return json!({"status": "error", "reason": format!("unknown group: {}",x)})
+    }
+  };
+  let aggregate = match &payload.score {
+    None => utils::config_group_score(),
+    Some(x) => match search::ScoreAggregate::from_name(x) {
+      Some(aggregate) => aggregate,
+      None => return json!({"status": "error", "reason": format!("unknown score: {}",x)})
+    }
+  };
+  let mode = match &payload.mode {
+    None => utils::config_search_mode(),
+    Some(x) => match search::SearchMode::from_name(x) {
+      Some(mode) => mode,
+      None => return json!({"status": "error", "reason": format!("unknown mode: {}",x)})
+    }
+  };
+  // lambda trades relevance against novelty, outside of [0, 1] it rewards redundancy
+  let mmr_lambda = match payload.mmr_lambda {
+    None => utils::config_mmr_lambda(),
+    Some(x) if (0.0..=1.0).contains(&x) => x,
+    Some(x) => return json!({"status": "error", "reason": format!("mmr_lambda must be between 0 and 1: {}",x)})
+  };
+  let book = laws::LawBook {
+    pais: payload.pais.clone(),
+    instrumento: payload.instrumento.clone()
+  };
+  // Vectors of different models are not comparable, the query is encoded by the model of the book;
+  // the language of the query only picks the model of a book not loaded yet
+  let book_model = catalogue::book_model(&book);
+  let language = match &payload.language {
+    None => match &book_model {
+      Some(book_model) => utils::config_languages().into_iter()
+        .find(|x| utils::config_model_for_language(x) == *book_model)
+        .unwrap_or_else(|| language::detect_language(&payload.phrase.text)),
+      None => language::detect_language(&payload.phrase.text)
+    },
+    Some(x) if utils::config_languages().contains(x) => x.clone(),
+    Some(x) => return json!({"status": "error", "reason": format!("language not served: {}",x)})
+  };
+  let model = match (&payload.language, book_model) {
+    (_, None) => utils::config_model_for_language(&language),
+    (None, Some(book_model)) => book_model,
+    (Some(_), Some(book_model)) if book_model == utils::config_model_for_language(&language) => book_model,
+    (Some(_), Some(book_model)) => return json!({"status": "error", "reason": format!("{}.{} is embedded by {}, the query ({}) by {}",
+      book.pais,book.instrumento,book_model,language,utils::config_model_for_language(&language))})
+  };
+  // The metric the book was indexed with, unless the request asks for another one
+  let metric = match &payload.metric {
+    None => catalogue::book_metric(&book).unwrap_or(utils::config_law_metric(&book)),
+    Some(x) => match transformer::DistanceMetric::from_name(x) {
+      Some(metric) => metric,
+      None => return json!({"status": "error", "reason": format!("unknown metric: {}",x)})
+    }
+  };
+  // Generate Embeddings, the handler waits for the batching worker without holding an executor thread
+  let template = utils::config_template(&model, &transformer::EncodingRole::Query);
+  let embedding = match transformer::transform_phrase_async(&model, &template, &payload.phrase).await {
+    Ok(Some(embedding)) => embedding,
+    Ok(None) => return json!({"status": "error", "reason": "phrase is too short, not undersootd"}),
+    Err(error) => return json!({"status": "error", "reason": error})
+  };
+  // Ranking, re-ranking and explanations are CPU bound, they run on the blocking threads
+  let payload = payload.into_inner();
+  rocket::tokio::task::spawn_blocking(move || 
+    search_embedding(payload, book, level, aggregate, mode, mmr_lambda, language, model, template, metric, embedding))
+    .await.unwrap_or_else(|x| json!({"status": "error", "reason": format!("{}: {}",utils::error_message("E0007"),x)}))
+}
+// Ranks a Book against the embedding of the query, from the first stage to the explanations
+#[allow(clippy::too_many_arguments)]
+fn search_embedding(
+  payload: SearchRequest, 
+  book: laws::LawBook, 
+  level: search::GroupLevel, 
+  aggregate: search::ScoreAggregate, 
+  mode: search::SearchMode, 
+  mmr_lambda: f32, 
+  language: String, 
+  model: String, 
+  template: String, 
+  metric: transformer::DistanceMetric, 
+  embedding: Vec<f32>) -> Value {
+  let book = &book;
+  // The query lives in the whitened space of the book, explanations compare it as encoded
+  let query = whitening::whiten(book, &model, &embedding);
+  let embd = &transformer::Embedding {
+    vector:Some(query.clone()),
+    etype: transformer::EmbeddingType::Total,
+    pooling: utils::config_pooling(),
+    model: model.clone(),
+    template,
+    metric: metric.clone()
+  };
+  // Compare against LawBook
+  let ranking = match mode {
+    search::SearchMode::Flat => catalogue::rank_embedding_against_law_book(embd, book).map(|x| (x, Vec::new())),
+    search::SearchMode::Hierarchical => search::rank_hierarchical(embd, book, utils::config_search_subtrees())
+  };
+  // A store that cannot be read fails the search, not the server
+  let ((ranked, path), statistics) = match ranking.and_then(|x| Ok((x, calibration::book_statistics(book, &metric)?))) {
+    Ok(ranked) => ranked,
+    Err(error) => return json!({"status": "error", "reason": error})
+  };
+  // Group parts of the same article (or chapter) into one hit
+  let hits = search::group_results(&ranked, &level, &aggregate).into_iter()
+    .take(utils::config_mmr_candidates().max(utils::config_return_count()))
+    .collect::<Vec<search::SearchHit>>();
+  // Keep the confident hits only, in units comparable across models and books
+  let hits = search::calibrate_hits(
+    hits, 
+    &statistics, 
+    &utils::config_return_min_unit(), 
+    utils::config_return_min_value());
+  if hits.is_empty() {
+    return json!({"status": "ok", "outcome": "no_confident_match", "language": language, "model": model, "metric": metric.name(), "path": path, "results": hits});
+  }
+  // Diversify near duplicated articles among the first stage candidates
+  let hits = search::mmr_rerank(
+    &query, 
+    hits, 
+    &metric, 
+    mmr_lambda, 
+    utils::config_return_count().max(utils::config_rerank_count()));
+  // Re-score the diversified candidates with the cross-encoder, its order is the final one
+  let mut hits = reranker::rerank_hits(
+    &payload.phrase.text, 
+    hits, 
+    utils::config_rerank_count(), 
+    utils::config_rerank_timeout()).into_iter()
+    .take(utils::config_return_count())
+    .collect::<Vec<search::SearchHit>>();
+  search::locate_hits(&mut hits);
+  // Highlight the sentences explaining each hit
+  if payload.explain.unwrap_or(utils::config_explain()) {
+    search::explain_hits(&model, &embedding, &mut hits, utils::config_explain_count());
   }
-    // Json::try_from().unwrap();
+  json!({"status": "ok", "outcome": "match", "language": language, "model": model, "metric": metric.name(), "path": path, "results": hits})
 }
 
 #[get("/norm/<phrase>")]
-fn phrase_norm_get(phrase: String) -> String {
+async fn phrase_norm_get(phrase: String) -> String {
   // Sentences
   let sentences = Vec::from([phrase.clone()]);
   // Generate Embeddings
-  let embeddings = transformer::transform_sentences(&sentences);
+  let embeddings = match transformer::transform_sentences_async(&utils::config_model(), &sentences).await {
+    Ok(embeddings) => embeddings,
+    Err(error) => return error
+  };
   // Return
   format!("Phrase: {:?}, norm: {:?}, entropy: {:?}", 
     phrase, mathematics::euclidean_magnitude(&embeddings[0]), mathematics::embeddings_entropy(&embeddings))
 }
 
 #[post("/norm", format="json", data = "<payload>")]
-fn phrase_norm_post(payload: Json<language::Phrase>) -> String {
+async fn phrase_norm_post(payload: Json<language::Phrase>) -> String {
   // Generate Embeddings
-  let embeddings = transformer::transform_phrase(&payload);
+  let template = utils::config_template(&utils::config_model(), &transformer::EncodingRole::Query);
+  let embeddings = match transformer::transform_phrase_async(&utils::config_model(), &template, &payload).await {
+    Ok(embeddings) => embeddings,
+    Err(error) => return error
+  };
   // Compare against LawBook
   let book = &laws::LawBook {
     pais: "colombia".to_string(),
@@ -124,33 +278,43 @@ fn phrase_norm_post(payload: Json<language::Phrase>) -> String {
   };
   let embd = &transformer::Embedding {
     vector:embeddings.clone(),
-    etype: transformer::EmbeddingType::Total
+    etype: transformer::EmbeddingType::Total,
+    pooling: utils::config_pooling(),
+    model: utils::config_model(),
+    template,
+    metric: utils::config_law_metric(book)
   };
-  dbg!(catalogue::compare_embedding_against_law_book(embd, book));
+  let _ = dbg!(catalogue::compare_embedding_against_law_book(embd, book));
   // Return
   format!("Phrase: {:?}, norm: {:?}", 
     payload.text.clone(), mathematics::euclidean_magnitude(&embeddings.unwrap()))
 }
 
 #[post("/compare", format="json", data = "<payload>")]
-fn phrases_distance_post(payload: Json<CompareRequest>) -> String {
+async fn phrases_distance_post(payload: Json<CompareRequest>) -> String {
   // Sentences
   let sentences = Vec::from([payload.phrase1.text.clone(),payload.phrase2.text.clone()]);
   // Generate Embeddings
-  let embeddings = transformer::transform_sentences(&sentences);
+  let embeddings = match transformer::transform_sentences_async(&utils::config_model(), &sentences).await {
+    Ok(embeddings) => embeddings,
+    Err(error) => return error
+  };
   format!("Phrase1: {:?}, Phrase2: {:?}, Distance: {:?}", 
-    payload.phrase1, payload.phrase2, mathematics::vector_euclidean_distance(&embeddings[0],&embeddings[1]))
+    payload.phrase1, payload.phrase2, transformer::embeddings_vectors_distance(&utils::config_metric(), &embeddings[0], &embeddings[1]))
 }
 
 #[get("/compare/<phrase1>/<phrase2>")]
-fn phrases_distance_get(phrase1: String, phrase2: String) -> String {
+async fn phrases_distance_get(phrase1: String, phrase2: String) -> String {
   // Sentences
   let sentences = Vec::from([phrase1.clone(),phrase2.clone()]);
   // Generate Embeddings
-  let embeddings = transformer::transform_sentences(&sentences);
+  let embeddings = match transformer::transform_sentences_async(&utils::config_model(), &sentences).await {
+    Ok(embeddings) => embeddings,
+    Err(error) => return error
+  };
   // Return
   format!("Phrase1: {:?}, Phrase2: {:?}, Distance: {:?}", 
-    phrase1, phrase2, mathematics::vector_euclidean_distance(&embeddings[0],&embeddings[1]))
+    phrase1, phrase2, transformer::embeddings_vectors_distance(&utils::config_metric(), &embeddings[0], &embeddings[1]))
 }
 
 // #[post("/inform", format="json", data = "<payload>")]
@@ -174,6 +338,51 @@ fn phrases_distance_get(phrase1: String, phrase2: String) -> String {
 // }
 
 
+#[get("/metrics")]
+fn metrics_get() -> Value {
+  json!({
+    "embedding_cache": cache::stats(),
+    "batching": batching::stats(),
+    "reload": reload::stats()
+  })
+}
+
+// Applies the catalogues written since the last load (new, modified or deleted) without a restart
+// Reading the changed books again and their calibration statistics is CPU bound, it runs on the blocking threads
+#[post("/admin/reload")]
+async fn admin_reload_post() -> Value {
+  match rocket::tokio::task::spawn_blocking(|| reload::reload("admin")).await {
+    Ok(event) => json!({
+      "status": "ok",
+      "reload": event
+    }),
+    Err(x) => json!({"status": "error", "reason": format!("{}: {}",utils::error_message("E0021"),x)})
+  }
+}
+
+// Ingests the text of law of a Book in the background, searches see its catalogues once it is complete
+#[post("/admin/ingest", format="json", data = "<payload>")]
+fn admin_ingest_post(payload: Json<IngestRequest>) -> Value {
+  let book = laws::LawBook {
+    pais: payload.pais.to_lowercase(),
+    instrumento: payload.instrumento.to_lowercase()
+  };
+  for filename in [files::book_of_law_filename(&book), files::law_config_filename(&book)] {
+    if !Path::new(&filename).exists() {
+      return json!({"status": "error", "reason": format!("{}: {}",utils::error_message("E0011"),filename)});
+    }
+  }
+  if laws::is_ingesting(&book) {
+    return json!({"status": "error", "reason": format!("{}.{} is being ingested already",book.pais,book.instrumento)});
+  }
+  let ingesting = format!("{}.{}",book.pais,book.instrumento);
+  thread::spawn(move || laws::ingest(&book));
+  json!({
+    "status": "ok",
+    "ingesting": ingesting
+  })
+}
+
 #[catch(404)]
 fn not_found() -> Value {
   json!({
@@ -184,6 +393,9 @@ fn not_found() -> Value {
 
 pub fn stage() -> rocket::fairing::AdHoc {
   catalogue::load_catalogues_memory(true);
+  reload::remember_law_configs();
+  reload::watch();
+  reranker::load();
   rocket::fairing::AdHoc::on_ignite("TSAHDU_server", |rocket| async {
     rocket.mount("/", routes![
       ping,
@@ -192,6 +404,9 @@ pub fn stage() -> rocket::fairing::AdHoc {
       phrase_norm_post,
       phrases_distance_post,
       phrase_search_post,
+      metrics_get,
+      admin_reload_post,
+      admin_ingest_post,
       // inform_post
       ])
     .register("/", catchers![not_found])
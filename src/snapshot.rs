@@ -0,0 +1,267 @@
+use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use memmap2::Mmap;
+use rocket::serde::{Serialize, Deserialize};
+use rocket::serde::json;
+use walkdir::WalkDir;
+
+use crate::catalogue;
+use crate::cryptography;
+use crate::files;
+use crate::language;
+use crate::laws;
+use crate::transformer;
+use crate::utils;
+
+// Single file per Book, next to its folder: MAGIC, header length (u64 le), json header, then the payload:
+// texts, padding to 4 bytes, vector matrix (f32 le, row-major, one row per entry)
+const MAGIC: &[u8; 8] = b"TSAHDUS2";
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SnapshotEntry {
+  pub dindex: laws::LawIndex,
+  pub etype: transformer::EmbeddingType,
+  pub pooling: transformer::PoolingStrategy,
+  pub model: String,
+  pub template: String,
+  pub metric: transformer::DistanceMetric,
+  pub text_offset: usize,
+  pub text_length: usize
+}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SnapshotHeader {
+  pub book: laws::LawBook,
+  pub manifest_hash: String,
+  // sha256 of the payload, a corrupted text or vector is caught before anything is served
+  pub payload_hash: String,
+  pub dims: usize,
+  pub texts_length: usize,
+  pub entries: Vec<SnapshotEntry>
+}
+
+// A snapshot kept mapped while its Book is published from it, the catalogues read from it hold no vector:
+// rows are read (not whitened) from the map when a matrix, codes or centroids are built
+pub struct MappedSnapshot {
+  mmap: Arc<Mmap>,
+  payload_hash: String,
+  dims: usize,
+  vectors_start: usize,
+  rows: HashMap<laws::LawIndex,usize>
+}
+
+impl MappedSnapshot {
+  // Vector of a catalogue as written by the transformer, None if the snapshot has no such catalogue
+  pub fn vector(&self, dindex: &laws::LawIndex) -> Option<Vec<f32>> {
+    let offset = self.vectors_start + 4 * self.dims * self.rows.get(dindex)?;
+    Some(self.mmap[offset..offset + 4 * self.dims].chunks_exact(4)
+      .map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect())
+  }
+  pub fn payload_hash(&self) -> &str {
+    &self.payload_hash
+  }
+  pub fn contains(&self, dindex: &laws::LawIndex) -> bool {
+    self.rows.contains_key(dindex)
+  }
+  // The same map without the catalogues replaced in memory since it was read
+  pub fn without(&self, replaced: &HashSet<laws::LawIndex>) -> MappedSnapshot {
+    MappedSnapshot {
+      mmap: self.mmap.clone(),
+      payload_hash: self.payload_hash.clone(),
+      dims: self.dims,
+      vectors_start: self.vectors_start,
+      rows: self.rows.iter().filter(|(dindex,_)| !replaced.contains(*dindex))
+        .map(|(dindex,row)| (dindex.clone(), *row)).collect()
+    }
+  }
+}
+
+// Modification time and entry count of the folder of a Book: the stores write by renaming (files::write_atomic)
+// and remove files, both change the folder, so no file of the book is stat-ed on each load
+pub fn book_manifest(book: &laws::LawBook) -> String {
+  let folder = files::book_foldername(book);
+  let modified = fs::metadata(&folder).and_then(|x| x.modified()).ok()
+    .and_then(|x| x.duration_since(UNIX_EPOCH).ok()).map(|x| x.as_nanos()).unwrap_or(0);
+  let entries = fs::read_dir(&folder).map(|x| x.count()).unwrap_or(0);
+  format!("{} {} {}",folder,modified,entries)
+}
+
+// Writes the snapshot of a Book from its files (not from memory, vectors may be whitened there)
+pub fn build_snapshot(book: &laws::LawBook) -> usize {
+  // taken before the files are read, a write meanwhile leaves the snapshot stale rather than wrong
+  let manifest = book_manifest(book);
+  let mut entries: Vec<SnapshotEntry> = Vec::new();
+  let mut texts: Vec<u8> = Vec::new();
+  let mut vectors: Vec<f32> = Vec::new();
+  let mut dims: usize = 0;
+  for dpath in WalkDir::new(files::book_foldername(book)).into_iter().filter_map(|e| e.ok()) {
+    if !utils::name_from_dir_entry(&dpath).ends_with(&utils::config_reference_extension()) {
+      continue;
+    }
+    let (dindex, etype, pooling, model, template, metric) = match files::read_reference_file(&dpath) {
+      Ok(reference) => reference,
+      Err(reason) => {
+        println!("[Warning]: snapshot of {}.{} skips it, {}",book.pais,book.instrumento,reason);
+        continue;
+      }
+    };
+    let vector = match utils::lines_from_file(files::embeddings_filename(&dindex)).ok()
+      .and_then(|x| x.iter().map(|y| y.parse::<f32>().ok()).collect::<Option<Vec<f32>>>()) {
+      Some(vector) => vector,
+      None => continue
+    };
+    if dims == 0 {
+      dims = vector.len();
+    }
+    if vector.len() != dims {
+      println!("[Warning]: snapshot of {}.{} skips <{}>, {} dimensions instead of {}",
+        book.pais,book.instrumento,files::embeddings_filename(&dindex),vector.len(),dims);
+      continue;
+    }
+    let text = files::read_phrase_of_law(&dindex);
+    entries.push(SnapshotEntry {
+      dindex,
+      etype,
+      pooling,
+      model,
+      template,
+      metric,
+      text_offset: texts.len(),
+      text_length: text.len()
+    });
+    texts.extend_from_slice(text.as_bytes());
+    vectors.extend(vector);
+  }
+  // the vectors start 4 bytes aligned in the file, the header length decides the padding
+  let mut payload: Vec<u8> = Vec::with_capacity(texts.len() + 4 * vectors.len() + 3);
+  payload.extend_from_slice(&texts);
+  let mut header = SnapshotHeader {
+    book: book.clone(),
+    manifest_hash: cryptography::sha256_digest(manifest.as_str()),
+    payload_hash: String::new(),
+    dims,
+    texts_length: texts.len(),
+    entries
+  };
+  let header_length = json::to_string(&header).expect("unable to serialize the snapshot header").len()
+    + cryptography::sha256_bytes(&[]).len();
+  while !(16 + header_length + payload.len()).is_multiple_of(4) {
+    payload.push(0);
+  }
+  for x in vectors.iter() {
+    payload.extend_from_slice(&x.to_le_bytes());
+  }
+  header.payload_hash = cryptography::sha256_bytes(&payload);
+  let header_bytes = json::to_string(&header).expect("unable to serialize the snapshot header").into_bytes();
+  let mut bytes: Vec<u8> = Vec::with_capacity(16 + header_bytes.len() + payload.len());
+  bytes.extend_from_slice(MAGIC);
+  bytes.extend_from_slice(&(header_bytes.len() as u64).to_le_bytes());
+  bytes.extend_from_slice(&header_bytes);
+  bytes.extend_from_slice(&payload);
+  files::write_atomic(&files::snapshot_filename(book), &bytes)
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0015"),files::snapshot_filename(book)));
+  files::write_atomic(&files::manifest_filename(book), manifest.as_bytes())
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0015"),files::manifest_filename(book)));
+  header.entries.len()
+}
+
+// Maps the snapshot of a Book into a new shard of CATALOGUES_MEMORY, false (nothing loaded) if it is missing,
+// corrupted or its files changed since it was built
+pub fn load_snapshot(book: &laws::LawBook) -> bool {
+  match read_snapshot(book) {
+    Some((catalogues, mapped)) => {
+      catalogue::publish_shard_mapped(book, catalogues, Some(mapped));
+      true
+    }
+    None => false
+  }
+}
+// Catalogues of the snapshot of a Book, without their vectors, and the map they are read from;
+// None if it cannot be trusted. Texts are copied, they are a small part of a snapshot
+pub fn read_snapshot(book: &laws::LawBook) -> Option<(HashMap<laws::LawIndex,catalogue::Catalogue>, Arc<MappedSnapshot>)> {
+  let file = match File::open(files::snapshot_filename(book)) {
+    Ok(file) => file,
+    Err(_) => return None
+  };
+  let mmap = match unsafe { Mmap::map(&file) } {
+    Ok(mmap) => mmap,
+    Err(_) => return None
+  };
+  if mmap.len() < 16 || &mmap[..8] != MAGIC {
+    println!("[Warning]: <{}> is not a snapshot",files::snapshot_filename(book));
+    return None;
+  }
+  let header_length = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+  let header: SnapshotHeader = match mmap.get(16..16usize.saturating_add(header_length))
+    .and_then(|x| std::str::from_utf8(x).ok()).and_then(|x| json::from_str(x).ok()) {
+    Some(header) => header,
+    None => {
+      println!("[Warning]: <{}> has a corrupted header",files::snapshot_filename(book));
+      return None;
+    }
+  };
+  // both the manifest on disk and the folder it describes must be the ones the snapshot was built from
+  let manifest = fs::read_to_string(files::manifest_filename(book)).unwrap_or_default();
+  if cryptography::sha256_digest(manifest.as_str()) != header.manifest_hash || book_manifest(book) != manifest {
+    println!("[Warning]: snapshot of {}.{} is stale, loading its files",book.pais,book.instrumento);
+    return None;
+  }
+  let texts_start = 16 + header_length;
+  let vectors_start = texts_start.checked_add(header.texts_length).and_then(|x| x.checked_add(3)).map(|x| x / 4 * 4);
+  let vectors_end = header.dims.checked_mul(header.entries.len()).and_then(|x| x.checked_mul(4))
+    .zip(vectors_start).and_then(|(x, y)| x.checked_add(y));
+  if vectors_end != Some(mmap.len()) {
+    println!("[Warning]: <{}> is truncated",files::snapshot_filename(book));
+    return None;
+  }
+  if cryptography::sha256_bytes(&mmap[texts_start..]) != header.payload_hash {
+    println!("[Warning]: <{}> has a corrupted payload",files::snapshot_filename(book));
+    return None;
+  }
+  let mut catalogues: HashMap<laws::LawIndex,catalogue::Catalogue> = HashMap::new();
+  let mut rows: HashMap<laws::LawIndex,usize> = HashMap::new();
+  for (row, entry) in header.entries.into_iter().enumerate() {
+    // a header that passed the hash may still have been written wrong, no offset is trusted
+    let text = match entry.text_offset.checked_add(entry.text_length)
+      .filter(|&x| x <= header.texts_length)
+      .and_then(|x| std::str::from_utf8(&mmap[texts_start + entry.text_offset..texts_start + x]).ok()) {
+      Some(text) => text.to_string(),
+      None => {
+        println!("[Warning]: <{}> has a text out of bounds",files::snapshot_filename(book));
+        return None;
+      }
+    };
+    rows.insert(entry.dindex.clone(), row);
+    // built as is, the fabric would embed the text again for a catalogue without its vector
+    catalogues.insert(entry.dindex.clone(), catalogue::Catalogue {
+      dindex: entry.dindex,
+      dmeaning: transformer::Meaning {
+        phrase: language::phrase_fabric(text),
+        embedding: transformer::Embedding {
+          etype: entry.etype,
+          pooling: entry.pooling,
+          model: entry.model,
+          template: entry.template,
+          metric: entry.metric,
+          vector: None
+        }
+      }
+    });
+  }
+  let mapped = MappedSnapshot {
+    mmap: Arc::new(mmap),
+    payload_hash: header.payload_hash,
+    dims: header.dims,
+    vectors_start: vectors_start.unwrap(),
+    rows
+  };
+  Some((catalogues, Arc::new(mapped)))
+}
@@ -0,0 +1,485 @@
+use lazy_static::lazy_static;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+use crate::catalogue;
+use crate::files;
+use crate::journal;
+use crate::language;
+use crate::laws;
+use crate::transformer;
+use crate::utils;
+
+// A catalogue as persisted: its text and its vector as written by the transformer (not whitened)
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct StoredCatalogue {
+  pub dindex: laws::LawIndex,
+  pub etype: transformer::EmbeddingType,
+  pub pooling: transformer::PoolingStrategy,
+  pub model: String,
+  pub template: String,
+  pub metric: transformer::DistanceMetric,
+  pub text: String,
+  pub vector: Vec<f32>
+}
+// Where the catalogues are persisted: Folder (reference, embedding and text files per catalogue),
+// Sqlite (one transactional file)
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+pub enum StoreKind {
+  Folder,
+  Sqlite
+}
+
+impl StoreKind {
+  pub fn from_name(name: &str) -> Option<StoreKind> {
+    match name.to_lowercase().as_str() {
+      "folder" => Some(StoreKind::Folder),
+      "sqlite" => Some(StoreKind::Sqlite),
+      _ => None
+    }
+  }
+}
+
+impl StoredCatalogue {
+  pub fn from_catalogue(doc: &catalogue::Catalogue) -> StoredCatalogue {
+    StoredCatalogue {
+      dindex: doc.dindex.clone(),
+      etype: doc.dmeaning.embedding.etype.clone(),
+      pooling: doc.dmeaning.embedding.pooling.clone(),
+      model: doc.dmeaning.embedding.model.clone(),
+      template: doc.dmeaning.embedding.template.clone(),
+      metric: doc.dmeaning.embedding.metric.clone(),
+      text: doc.dmeaning.phrase.text.clone(),
+      vector: doc.dmeaning.embedding.vector.clone().unwrap_or_default()
+    }
+  }
+  // Catalogue with the stored vector as it is, whitening is applied when loading to memory
+  pub fn to_catalogue(&self) -> catalogue::Catalogue {
+    catalogue::catalogue_fabric(
+      self.dindex.book.pais.clone(),
+      self.dindex.book.instrumento.clone(),
+      self.dindex.titulo,
+      self.dindex.capitulo,
+      self.dindex.articulo,
+      self.dindex.parte,
+      &language::phrase_fabric(self.text.clone()),
+      self.etype.clone(),
+      self.pooling.clone(),
+      self.model.clone(),
+      self.template.clone(),
+      self.metric.clone(),
+      &Some(self.vector.clone()))
+  }
+}
+
+// Persistence of the catalogues by LawIndex and by LawBook
+pub trait CatalogueStore: Send + Sync {
+  fn name(&self) -> &'static str;
+  // Saves catalogues and texts (of articles split in parts) as one unit, all or none of them
+  fn put_unit(&self, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String>;
+  fn get(&self, dindex: &laws::LawIndex) -> Result<Option<StoredCatalogue>, String>;
+  // Text of a catalogue or of an article split in parts
+  fn get_text(&self, dindex: &laws::LawIndex) -> Result<Option<String>, String>;
+  fn list(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String>;
+  fn books(&self) -> Result<Vec<laws::LawBook>, String>;
+  fn delete(&self, dindex: &laws::LawIndex) -> Result<(), String>;
+  // no command removes a whole Book yet
+  #[allow(dead_code)]
+  fn delete_book(&self, book: &laws::LawBook) -> Result<usize, String>;
+  // Replaces every catalogue and text of a Book at once, the old ones stay until the new ones are all written
+  fn replace_book(&self, book: &laws::LawBook, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String>;
+  // Texts of the articles split in parts, they have no catalogue of their own
+  fn list_texts(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String>;
+
+  fn put(&self, catalogue: &StoredCatalogue) -> Result<(), String> {
+    self.put_unit(&Vec::from([catalogue.clone()]), &Vec::new())
+  }
+  fn get_book(&self, book: &laws::LawBook) -> Result<Vec<StoredCatalogue>, String> {
+    let mut catalogues: Vec<StoredCatalogue> = Vec::new();
+    for dindex in self.list(book)? {
+      catalogues.extend(self.get(&dindex)?);
+    }
+    Ok(catalogues)
+  }
+}
+
+// The folder layout: <reference_folder>/<pais>.<instrumento>/ with a reference, an embedding and a text file per catalogue
+pub struct FolderStore;
+
+// Files of some catalogues and texts in the folder layout, with their content
+fn folder_writes(catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Vec<(String,String)> {
+  let mut writes = texts.iter().map(|x| (files::file_of_law_filename(&x.0), x.1.clone())).collect::<Vec<(String,String)>>();
+  for stored in catalogues.iter() {
+    let doc = stored.to_catalogue();
+    writes.extend(catalogue::catalogue_writes(&doc));
+    // the text of an article is kept over the one of its single window
+    if !writes.iter().any(|x| x.0 == files::file_of_law_filename(&doc.dindex)) {
+      writes.push((files::file_of_law_filename(&doc.dindex), stored.text.clone()));
+    }
+  }
+  writes
+}
+
+impl CatalogueStore for FolderStore {
+  fn name(&self) -> &'static str {
+    "folder"
+  }
+  fn put_unit(&self, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String> {
+    let book = match catalogues.first().map(|x| &x.dindex).or(texts.first().map(|x| &x.0)) {
+      Some(dindex) => dindex.book.clone(),
+      None => return Ok(())
+    };
+    let unit = texts.first().map(|x| &x.0).or(catalogues.first().map(|x| &x.dindex)).map(files::law_index_to_filename);
+    journal::commit_unit(&book, &unit.unwrap(), folder_writes(catalogues, texts));
+    Ok(())
+  }
+  fn get(&self, dindex: &laws::LawIndex) -> Result<Option<StoredCatalogue>, String> {
+    if !Path::new(&files::reference_filename(dindex)).exists() {
+      return Ok(None);
+    }
+    let (dindex, etype, pooling, model, template, metric) = files::read_reference_path(&files::reference_filename(dindex))?;
+    let vector = utils::lines_from_file(files::embeddings_filename(&dindex)).map_err(|x| format!("{}: {}",utils::error_message("E0003"),x))?
+      .iter().map(|x| x.parse::<f32>().map_err(|x| format!("{}: {}",utils::error_message("E0003"),x))).collect::<Result<Vec<f32>,String>>()?;
+    let text = fs::read_to_string(files::file_of_law_filename(&dindex)).map_err(|x| format!("{}: {}",utils::error_message("E0002"),x))?;
+    Ok(Some(StoredCatalogue {
+      dindex,
+      etype,
+      pooling,
+      model,
+      template,
+      metric,
+      text,
+      vector
+    }))
+  }
+  fn get_text(&self, dindex: &laws::LawIndex) -> Result<Option<String>, String> {
+    Ok(fs::read_to_string(files::file_of_law_filename(dindex)).ok())
+  }
+  fn list(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String> {
+    Ok(WalkDir::new(files::book_foldername(book)).into_iter().filter_map(|e| e.ok())
+      .filter(|x| utils::name_from_dir_entry(x).ends_with(&utils::config_reference_extension()))
+      // unreadable references are left to the fsck command
+      .filter_map(|x| files::read_reference_file(&x).ok().map(|y| y.0))
+      .collect())
+  }
+  fn books(&self) -> Result<Vec<laws::LawBook>, String> {
+    Ok(catalogue::reference_books())
+  }
+  fn delete(&self, dindex: &laws::LawIndex) -> Result<(), String> {
+    for filename in [files::reference_filename(dindex), files::embeddings_filename(dindex), files::file_of_law_filename(dindex)] {
+      if Path::new(&filename).exists() {
+        fs::remove_file(&filename).map_err(|x| format!("{}: {}",filename,x))?;
+      }
+    }
+    Ok(())
+  }
+  fn delete_book(&self, book: &laws::LawBook) -> Result<usize, String> {
+    let count = self.list(book)?.len();
+    if Path::new(&files::book_foldername(book)).is_dir() {
+      fs::remove_dir_all(files::book_foldername(book)).map_err(|x| format!("{}: {}",files::book_foldername(book),x))?;
+    }
+    // the snapshot lives next to the folder, a Book made again in it must not find the old one
+    for filename in [files::snapshot_filename(book), files::manifest_filename(book)] {
+      if Path::new(&filename).exists() {
+        fs::remove_file(&filename).map_err(|x| format!("{}: {}",filename,x))?;
+      }
+    }
+    Ok(count)
+  }
+  // The new folder is written whole beside reference_folder, then renamed over the old one
+  fn replace_book(&self, book: &laws::LawBook, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String> {
+    let _lock = journal::lock_book(book);
+    let folder = files::book_foldername(book);
+    let folder = folder.trim_end_matches('/');
+    let staging = files::staging_foldername(book);
+    let staging = staging.trim_end_matches('/');
+    let retired = format!("{}.retired",staging);
+    for path in [staging, retired.as_str()].into_iter().filter(|x| Path::new(x).exists()) {
+      fs::remove_dir_all(path).map_err(|x| format!("{}: {}",path,x))?;
+    }
+    fs::create_dir_all(staging).map_err(|x| format!("{}: {}",staging,x))?;
+    for (filename, content) in folder_writes(catalogues, texts) {
+      let target = Path::new(staging).join(Path::new(&filename).file_name().unwrap());
+      fs::File::create(&target).and_then(|mut x| x.write_all(content.as_bytes()).and_then(|_| x.sync_all()))
+        .map_err(|x| format!("{}: {}",target.display(),x))?;
+    }
+    files::sync_folder(staging).map_err(|x| format!("{}: {}",staging,x))?;
+    // the old folder is kept aside until the new one is in place
+    if Path::new(folder).is_dir() {
+      fs::rename(folder, &retired).map_err(|x| format!("{}: {}",folder,x))?;
+    }
+    fs::rename(staging, folder).map_err(|x| format!("{}: {}",folder,x))?;
+    files::sync_folder(&utils::config_reference_folder()).map_err(|x| format!("{}: {}",folder,x))?;
+    for filename in [files::snapshot_filename(book), files::manifest_filename(book), retired] {
+      if Path::new(&filename).is_dir() {
+        fs::remove_dir_all(&filename).map_err(|x| format!("{}: {}",filename,x))?;
+      } else if Path::new(&filename).exists() {
+        fs::remove_file(&filename).map_err(|x| format!("{}: {}",filename,x))?;
+      }
+    }
+    Ok(())
+  }
+  fn list_texts(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String> {
+    // texts of a parte are saved along with its catalogue, only the article ones are listed
+    let catalogues = self.list(book)?;
+    let mut articles = catalogues.iter().filter(|x| x.parte.is_some())
+      .map(|x| laws::LawIndex { parte: None, ..x.clone() })
+      .filter(|x| !catalogues.contains(x) && Path::new(&files::file_of_law_filename(x)).exists())
+      .collect::<Vec<laws::LawIndex>>();
+    articles.sort_by_key(files::law_index_to_filename);
+    articles.dedup();
+    Ok(articles)
+  }
+}
+
+// Every catalogue, text and vector in one SQLite file, each unit is written in one transaction
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+  connection: Mutex<rusqlite::Connection>
+}
+
+#[cfg(feature = "sqlite")]
+const SQLITE_SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS catalogues (
+  key TEXT PRIMARY KEY,
+  pais TEXT NOT NULL,
+  instrumento TEXT NOT NULL,
+  titulo INTEGER,
+  capitulo INTEGER,
+  articulo INTEGER,
+  parte INTEGER,
+  etype TEXT NOT NULL,
+  pooling TEXT NOT NULL,
+  model TEXT NOT NULL,
+  template TEXT NOT NULL,
+  metric TEXT NOT NULL,
+  dims INTEGER NOT NULL,
+  vector BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS catalogues_book ON catalogues (pais, instrumento);
+CREATE TABLE IF NOT EXISTS texts (
+  key TEXT PRIMARY KEY,
+  pais TEXT NOT NULL,
+  instrumento TEXT NOT NULL,
+  titulo INTEGER,
+  capitulo INTEGER,
+  articulo INTEGER,
+  parte INTEGER,
+  text TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS texts_book ON texts (pais, instrumento);
+"#;
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(error: rusqlite::Error) -> String {
+  format!("sqlite: {}",error)
+}
+#[cfg(feature = "sqlite")]
+fn index_from_row(row: &rusqlite::Row) -> rusqlite::Result<laws::LawIndex> {
+  Ok(laws::LawIndex {
+    book: laws::LawBook {
+      pais: row.get("pais")?,
+      instrumento: row.get("instrumento")?
+    },
+    titulo: row.get("titulo")?,
+    capitulo: row.get("capitulo")?,
+    articulo: row.get("articulo")?,
+    parte: row.get("parte")?
+  })
+}
+
+// Inserts (or replaces) catalogues and texts within a transaction
+#[cfg(feature = "sqlite")]
+fn sqlite_put(transaction: &rusqlite::Transaction, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String> {
+  // the text of an article is kept over the one of its single window
+  let texts = catalogues.iter().map(|x| (x.dindex.clone(), x.text.clone())).chain(texts.iter().cloned());
+  for (dindex, text) in texts {
+    transaction.execute(
+      "INSERT OR REPLACE INTO texts (key, pais, instrumento, titulo, capitulo, articulo, parte, text) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+      rusqlite::params![files::law_index_to_filename(&dindex), dindex.book.pais, dindex.book.instrumento,
+        dindex.titulo, dindex.capitulo, dindex.articulo, dindex.parte, text]).map_err(sqlite_error)?;
+  }
+  for stored in catalogues.iter() {
+    let dindex = &stored.dindex;
+    transaction.execute(
+      "INSERT OR REPLACE INTO catalogues (key, pais, instrumento, titulo, capitulo, articulo, parte, etype, pooling, model, template, metric, dims, vector) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+      rusqlite::params![files::law_index_to_filename(dindex), dindex.book.pais, dindex.book.instrumento,
+        dindex.titulo, dindex.capitulo, dindex.articulo, dindex.parte,
+        format!("{:?}",stored.etype), format!("{:?}",stored.pooling), stored.model, stored.template, stored.metric.name(),
+        stored.vector.len() as i64, stored.vector.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>()]).map_err(sqlite_error)?;
+  }
+  Ok(())
+}
+// Deletes every catalogue and text of a Book within a transaction, returns the catalogues deleted
+#[cfg(feature = "sqlite")]
+fn sqlite_delete_book(transaction: &rusqlite::Transaction, book: &laws::LawBook) -> Result<usize, String> {
+  let count = transaction.execute("DELETE FROM catalogues WHERE pais = ?1 AND instrumento = ?2",
+    rusqlite::params![book.pais, book.instrumento]).map_err(sqlite_error)?;
+  transaction.execute("DELETE FROM texts WHERE pais = ?1 AND instrumento = ?2",
+    rusqlite::params![book.pais, book.instrumento]).map_err(sqlite_error)?;
+  Ok(count)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+  pub fn open(filename: &str) -> Result<SqliteStore, String> {
+    if let Some(folder) = Path::new(filename).parent().filter(|x| !x.as_os_str().is_empty()) {
+      fs::create_dir_all(folder).map_err(|x| format!("{}: {}",filename,x))?;
+    }
+    let connection = rusqlite::Connection::open(filename).map_err(sqlite_error)?;
+    connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;").map_err(sqlite_error)?;
+    connection.execute_batch(SQLITE_SCHEMA).map_err(sqlite_error)?;
+    Ok(SqliteStore {
+      connection: Mutex::new(connection)
+    })
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl CatalogueStore for SqliteStore {
+  fn name(&self) -> &'static str {
+    "sqlite"
+  }
+  fn put_unit(&self, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    sqlite_put(&transaction, catalogues, texts)?;
+    transaction.commit().map_err(sqlite_error)
+  }
+  fn replace_book(&self, book: &laws::LawBook, catalogues: &[StoredCatalogue], texts: &[(laws::LawIndex,String)]) -> Result<(), String> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    sqlite_delete_book(&transaction, book)?;
+    sqlite_put(&transaction, catalogues, texts)?;
+    transaction.commit().map_err(sqlite_error)
+  }
+  fn get(&self, dindex: &laws::LawIndex) -> Result<Option<StoredCatalogue>, String> {
+    use rusqlite::OptionalExtension;
+    let connection = self.connection.lock().unwrap();
+    let row = connection.query_row(
+      "SELECT c.*, t.text FROM catalogues c JOIN texts t ON t.key = c.key WHERE c.key = ?1",
+      rusqlite::params![files::law_index_to_filename(dindex)],
+      |row| Ok((index_from_row(row)?, row.get::<_,String>("etype")?, row.get::<_,String>("pooling")?,
+        row.get::<_,String>("model")?, row.get::<_,String>("template")?, row.get::<_,String>("metric")?,
+        row.get::<_,String>("text")?, row.get::<_,Vec<u8>>("vector")?)))
+      .optional().map_err(sqlite_error)?;
+    let (dindex, etype, pooling, model, template, metric, text, vector) = match row {
+      Some(row) => row,
+      None => return Ok(None)
+    };
+    // a row written by another version is an error, as an unreadable reference file is
+    let key = files::law_index_to_filename(&dindex);
+    Ok(Some(StoredCatalogue {
+      etype: match etype.as_str() {
+        "Total" => transformer::EmbeddingType::Total,
+        "Average" => transformer::EmbeddingType::Average,
+        x => return Err(format!("{}: unknown etype: {}",key,x))
+      },
+      pooling: transformer::PoolingStrategy::from_name(&pooling).ok_or(format!("{}: unknown pooling: {}",key,pooling))?,
+      model,
+      template,
+      metric: transformer::DistanceMetric::from_name(&metric).ok_or(format!("{}: unknown metric: {}",key,metric))?,
+      text,
+      vector: vector.chunks_exact(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect(),
+      dindex
+    }))
+  }
+  fn get_text(&self, dindex: &laws::LawIndex) -> Result<Option<String>, String> {
+    use rusqlite::OptionalExtension;
+    let connection = self.connection.lock().unwrap();
+    connection.query_row("SELECT text FROM texts WHERE key = ?1",
+      rusqlite::params![files::law_index_to_filename(dindex)], |row| row.get(0))
+      .optional().map_err(sqlite_error)
+  }
+  fn list(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare("SELECT * FROM catalogues WHERE pais = ?1 AND instrumento = ?2").map_err(sqlite_error)?;
+    let indexes = statement.query_map(rusqlite::params![book.pais, book.instrumento], index_from_row)
+      .map_err(sqlite_error)?
+      .collect::<rusqlite::Result<Vec<laws::LawIndex>>>().map_err(sqlite_error);
+    indexes
+  }
+  fn books(&self) -> Result<Vec<laws::LawBook>, String> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare("SELECT DISTINCT pais, instrumento FROM catalogues").map_err(sqlite_error)?;
+    let books = statement.query_map([], |row| Ok(laws::LawBook { pais: row.get(0)?, instrumento: row.get(1)? }))
+      .map_err(sqlite_error)?
+      .collect::<rusqlite::Result<Vec<laws::LawBook>>>().map_err(sqlite_error);
+    books
+  }
+  fn delete(&self, dindex: &laws::LawIndex) -> Result<(), String> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    for table in ["catalogues", "texts"] {
+      transaction.execute(format!("DELETE FROM {} WHERE key = ?1",table).as_str(),
+        rusqlite::params![files::law_index_to_filename(dindex)]).map_err(sqlite_error)?;
+    }
+    transaction.commit().map_err(sqlite_error)
+  }
+  fn delete_book(&self, book: &laws::LawBook) -> Result<usize, String> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    let count = sqlite_delete_book(&transaction, book)?;
+    transaction.commit().map_err(sqlite_error)?;
+    Ok(count)
+  }
+  fn list_texts(&self, book: &laws::LawBook) -> Result<Vec<laws::LawIndex>, String> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare(
+      "SELECT * FROM texts WHERE pais = ?1 AND instrumento = ?2 AND key NOT IN (SELECT key FROM catalogues)").map_err(sqlite_error)?;
+    let indexes = statement.query_map(rusqlite::params![book.pais, book.instrumento], index_from_row)
+      .map_err(sqlite_error)?
+      .collect::<rusqlite::Result<Vec<laws::LawIndex>>>().map_err(sqlite_error);
+    indexes
+  }
+}
+
+// Opens a store of a kind, the sqlite one at sqlite_file
+pub fn open_store(kind: &StoreKind) -> Box<dyn CatalogueStore> {
+  match kind {
+    StoreKind::Folder => Box::new(FolderStore),
+    #[cfg(feature = "sqlite")]
+    StoreKind::Sqlite => Box::new(SqliteStore::open(&utils::config_sqlite_file())
+      .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0017"),utils::config_sqlite_file()))),
+    #[cfg(not(feature = "sqlite"))]
+    StoreKind::Sqlite => panic!("{}: built without the sqlite feature",utils::error_message("E0017"))
+  }
+}
+
+lazy_static! {
+  static ref STORE: Box<dyn CatalogueStore> = open_store(&utils::config_store());
+}
+
+// The configured store, opened once
+pub fn store() -> &'static dyn CatalogueStore {
+  &**STORE
+}
+
+// Copies every catalogue and text of some Books (of every Book if none is given) from one store to another,
+// one unit per Book; returns the catalogues copied
+pub fn migrate(from: &dyn CatalogueStore, to: &dyn CatalogueStore, books: &[laws::LawBook]) -> Result<usize, String> {
+  let books = if books.is_empty() { from.books()? } else { books.to_owned() };
+  let mut count = 0;
+  for book in books.iter() {
+    let catalogues = from.get_book(book)?;
+    let mut texts: Vec<(laws::LawIndex,String)> = Vec::new();
+    for dindex in from.list_texts(book)? {
+      texts.extend(from.get_text(&dindex)?.map(|x| (dindex, x)));
+    }
+    to.put_unit(&catalogues, &texts)?;
+    let copied = to.list(book)?.len();
+    if copied < catalogues.len() {
+      return Err(format!("{}.{}: {} of {} catalogues copied",book.pais,book.instrumento,copied,catalogues.len()));
+    }
+    println!("Migrated {}.{}: {} catalogues and {} article texts from {} to {}",
+      book.pais,book.instrumento,catalogues.len(),texts.len(),from.name(),to.name());
+    count += catalogues.len();
+  }
+  Ok(count)
+}
@@ -1,11 +1,21 @@
+#[cfg(feature = "torch")]
 use lazy_static::lazy_static;
+#[cfg(feature = "torch")]
 use std::sync::Mutex;
+#[cfg(feature = "torch")]
+use std::collections::HashMap;
 use rocket::serde::{Serialize, Deserialize};
 
 use crate::mathematics;
+use crate::cache;
+use crate::batching;
 use crate::utils;
 use crate::language;
 
+#[cfg(feature = "onnx")]
+use crate::onnx;
+
+#[cfg(feature = "torch")]
 use rust_bert::pipelines::sentence_embeddings::{
   SentenceEmbeddingsModel,
   SentenceEmbeddingsBuilder, 
@@ -20,12 +30,53 @@ pub enum EmbeddingType {
   Total,
   Average
 }
+// How the embeddings of the windows of a long phrase are combined:
+// Mean (plain average), TokenWeighted (average weighted by the tokens of each window),
+// OverlapCorrected (TokenWeighted, counting the overlapped tokens only once),
+// Max (element wise maximum), Parts (no pooling, each window is kept as a parte)
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum PoolingStrategy {
+  Mean,
+  TokenWeighted,
+  OverlapCorrected,
+  Max,
+  Parts
+}
+// Distance between a query and the catalogues, lower is always closer:
+// Cosine (1 - cosine similarity), Dot (negative dot product), Euclidean, Manhattan, Minkowski of order p
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum DistanceMetric {
+  Cosine,
+  Dot,
+  Euclidean,
+  Manhattan,
+  Minkowski(f32)
+}
+// Retrieval models encode questions and passages differently, Query (searching) or Document (ingesting)
+#[derive(Debug)]
+#[derive(Clone,PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum EncodingRole {
+  Query,
+  Document
+}
 #[derive(Debug)]
 #[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Embedding {
   pub etype: EmbeddingType,
+  pub pooling: PoolingStrategy,
+  pub model: String,
+  pub template: String,
+  pub metric: DistanceMetric,
   pub vector: Option<Vec<f32>>
 }
 #[derive(Debug)]
@@ -37,52 +88,233 @@ pub struct Meaning {
   pub embedding: Embedding
 }
 
+impl PoolingStrategy {
+  pub fn from_name(name: &str) -> Option<PoolingStrategy> {
+    match name.to_lowercase().as_str() {
+      "mean"             => Some(PoolingStrategy::Mean),
+      "tokenweighted"    => Some(PoolingStrategy::TokenWeighted),
+      "overlapcorrected" => Some(PoolingStrategy::OverlapCorrected),
+      "max"              => Some(PoolingStrategy::Max),
+      "parts"            => Some(PoolingStrategy::Parts),
+      _ => None
+    }
+  }
+}
+impl DistanceMetric {
+  // {cosine, dot, euclidean, manhattan, minkowski-p}, minkowski-3 is the Minkowski distance of order 3
+  pub fn from_name(name: &str) -> Option<DistanceMetric> {
+    match name.to_lowercase().as_str() {
+      "cosine"    => Some(DistanceMetric::Cosine),
+      "dot"       => Some(DistanceMetric::Dot),
+      "euclidean" => Some(DistanceMetric::Euclidean),
+      "manhattan" => Some(DistanceMetric::Manhattan),
+      x => x.strip_prefix("minkowski-")
+        .and_then(|p| p.parse::<f32>().ok())
+        .filter(|&p| p >= 1.0)
+        .map(DistanceMetric::Minkowski)
+    }
+  }
+  pub fn name(&self) -> String {
+    match self {
+      DistanceMetric::Cosine       => "cosine".to_string(),
+      DistanceMetric::Dot          => "dot".to_string(),
+      DistanceMetric::Euclidean    => "euclidean".to_string(),
+      DistanceMetric::Manhattan    => "manhattan".to_string(),
+      DistanceMetric::Minkowski(p) => format!("minkowski-{}",p)
+    }
+  }
+}
+
+#[cfg(feature = "torch")]
 lazy_static! {
-  static ref TRANSFORMER: Mutex<SentenceEmbeddingsModel> = Mutex::new(
-    SentenceEmbeddingsBuilder::local(
-      utils::config_model_path()
-  ).with_device(tch::Device::cuda_if_available()).create_model().expect(utils::error_message("E0006").as_str()));
+  // Model of each served language, by model
+  static ref TRANSFORMERS: HashMap<String,Mutex<SentenceEmbeddingsModel>> = utils::config_languages().iter()
+    .map(|x| utils::config_model_for_language(x))
+    .map(|x| (x.clone(), Mutex::new(SentenceEmbeddingsBuilder::local(
+      utils::config_model_path_for(&x)
+    ).with_device(tch::Device::cuda_if_available()).create_model().expect(format!("{} : {}",utils::error_message("E0006"),x).as_str()))))
+    .collect::<HashMap<String,Mutex<SentenceEmbeddingsModel>>>();
 }
-pub fn embeddings_vectors_distance(vec_a: &Vec<f32>, vec_b: &Vec<f32>) -> f32 {
-  mathematics::vector_euclidean_distance::<f32>(vec_a, vec_b)
+pub fn embeddings_vectors_distance(metric: &DistanceMetric, vec_a: &[f32], vec_b: &[f32]) -> f32 {
+  match metric {
+    DistanceMetric::Cosine       => 1.0 - mathematics::vector_cosine_distance::<f32>(vec_a, vec_b),
+    DistanceMetric::Dot          => -mathematics::dot_product::<f32>(vec_a, vec_b),
+    DistanceMetric::Euclidean    => mathematics::vector_euclidean_distance::<f32>(vec_a, vec_b),
+    DistanceMetric::Manhattan    => mathematics::vector_manhattan_distance::<f32>(vec_a, vec_b),
+    DistanceMetric::Minkowski(p) => mathematics::vector_minkowski_distance::<f32>(vec_a, vec_b, *p)
+  }
 }
-pub fn transform_sentences(sentences: &Vec<String>) -> Vec<Vec<f32>> {
+// Normalization applied to every embedding, part of the embedding cache key
+pub const NORMALIZATION: &str = "mu3";
+
+// Runs the model on the given backend {torch: rust-bert on libtorch, onnx: tract on CPU}
+pub fn model_encode_with(backend: &str, model: &str, sentences: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
   // Generate Embeddings
-  TRANSFORMER.lock().unwrap().encode(sentences).expect(utils::error_message("E0007").as_str())
-    .iter().map(|x| mathematics::vec1d_normalize_mu3::<f32>(&x.to_vec())).collect()
+  let encds: Vec<Vec<f32>> = match backend {
+    #[cfg(feature = "torch")]
+    "torch" => TRANSFORMERS.get(model)
+      .ok_or(anyhow::anyhow!("model not served: {}",model))?
+      .lock().unwrap().encode(sentences)?,
+    #[cfg(feature = "onnx")]
+    "onnx" => onnx::encode(model, sentences)?,
+    _ => anyhow::bail!("backend not available in this build: {}",backend)
+  };
+  Ok(encds.iter().map(|x| mathematics::vec1d_normalize_mu3::<f32>(&x.to_vec())).collect())
+}
+// Largest element wise difference between the torch and onnx embeddings of each sentence,
+// infinite when their dimensions differ, both backends must be built
+pub fn backend_parity(model: &str, sentences: &[String]) -> anyhow::Result<Vec<f32>> {
+  let torch = model_encode_with("torch", model, sentences)?;
+  let onnx = model_encode_with("onnx", model, sentences)?;
+  Ok(torch.iter().zip(onnx.iter()).map(|(vec_a, vec_b)| {
+    if vec_a.len() != vec_b.len() {
+      return f32::INFINITY;
+    }
+    vec_a.iter().zip(vec_b.iter()).map(|(a,b)| (a-b).abs()).fold(0.0f32, f32::max)
+  }).collect())
+}
+// Runs the configured backend, only the batching worker calls it
+pub fn model_encode(model: &str, sentences: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+  model_encode_with(utils::config_backend().as_str(), model, sentences)
+}
+// Encodes the sentences batched together with the ones of concurrent requests
+pub fn encode_sentences(model: &str, sentences: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  batching::submit(model, sentences)
+}
+// Cache keys of the sentences, their cached embeddings and the positions of the missing ones
+fn cached_sentences(model: &str, sentences: &[String]) -> (Vec<String>, Vec<Option<Vec<f32>>>, Vec<usize>) {
+  let disk = utils::config_embedding_cache_disk();
+  let keys = sentences.iter().map(|x| cache::embedding_key(x, model, NORMALIZATION)).collect::<Vec<String>>();
+  let ret = keys.iter().map(|x| cache::lookup(x, disk)).collect::<Vec<Option<Vec<f32>>>>();
+  let missing = (0..sentences.len()).filter(|&x| ret[x].is_none()).collect::<Vec<usize>>();
+  (keys, ret, missing)
+}
+// Fills the missing embeddings with the encoded ones and caches them
+fn cache_encoded(keys: &[String], mut ret: Vec<Option<Vec<f32>>>, missing: &[usize], encds: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+  let disk = utils::config_embedding_cache_disk();
+  for (&idx, encd) in missing.iter().zip(encds) {
+    cache::store(&keys[idx], &encd, disk);
+    ret[idx] = Some(encd);
+  }
+  ret.into_iter().map(|x| x.unwrap()).collect()
+}
+// Embeddings of the sentences, only the ones missing in the embedding cache are encoded
+pub fn transform_sentences(model: &str, sentences: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  let (keys, ret, missing) = cached_sentences(model, sentences);
+  let encds = encode_sentences(model, &missing.iter().map(|&x| sentences[x].clone()).collect::<Vec<String>>())?;
+  Ok(cache_encoded(&keys, ret, &missing, encds))
+}
+// Same as transform_sentences, awaiting the batching worker instead of blocking the thread
+pub async fn transform_sentences_async(model: &str, sentences: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  let (keys, ret, missing) = cached_sentences(model, sentences);
+  let encds = batching::submit_async(model, &missing.iter().map(|&x| sentences[x].clone()).collect::<Vec<String>>()).await?;
+  Ok(cache_encoded(&keys, ret, &missing, encds))
+}
+
+// Places a text in a template, "query: {}" -> "query: text"; a template without {} is a prefix
+pub fn apply_template(template: &str, text: &str) -> String {
+  if template.contains("{}") {
+    return template.replacen("{}", text, 1);
+  }
+  format!("{}{}",template,text)
+}
+// Embeddings of the sentences placed in a template, the cache keeps them apart from the plain ones
+pub fn transform_templated(model: &str, template: &str, sentences: &[String]) -> Result<Vec<Vec<f32>>, String> {
+  transform_sentences(model, &sentences.iter().map(|x| apply_template(template, x)).collect::<Vec<String>>())
 }
 
 // Transforms a sentence
 // sentence length cannot be more than 512 words
-pub fn transform_sentence(sentence: &String) -> Vec<f32> {
-  transform_sentences(&Vec::from([sentence.clone()])).get(0).unwrap().to_vec()
+pub fn transform_sentence(model: &str, sentence: &str) -> Vec<f32> {
+  transform_sentences(model, &Vec::from([sentence.to_owned()])).unwrap_or_else(|_| panic!("{}", utils::error_message("E0007"))).first().unwrap().to_vec()
 }
 
 // Transforms a Phrases
 // Requires a sentence, of any length
-pub fn transform_phrases(phrases_of_law: &Vec<language::Phrase>) -> Vec<Option<Vec<f32>>> {
+#[allow(dead_code)]
+pub fn transform_phrases(model: &str, role: &EncodingRole, phrases_of_law: &Vec<language::Phrase>) -> Result<Vec<Option<Vec<f32>>>, String> {
   let mut ret:Vec<Option<Vec<f32>>>  = Vec::new();
   for phrase in phrases_of_law {
-    ret.push(transform_phrase(phrase));
+    ret.push(transform_phrase(model, role, phrase)?);
   }
-  return ret;
+  Ok(ret)
 }
 
-// Transforms a Phrase of any Length to a Embedding Vector
-pub fn transform_phrase(phrase_of_law: &language::Phrase) -> Option<Vec<f32>> {
-  let segments = language::segment_phrase(phrase_of_law);
-  if segments.is_empty() {return None;}
-  let texts: Vec<String> = segments.iter().map(|x| x.text.clone()).collect::<Vec<String>>();
-  let encds = transform_sentences(&texts);
-  return Some(mathematics::vec2d_axis_average::<f32>(&encds,0));
+// Combines the embeddings of the windows of a phrase, Parts has nothing to combine and averages
+pub fn pool_windows(windows: &[language::Window], encds: &[Vec<f32>], pooling: &PoolingStrategy) -> Vec<f32> {
+  if encds.len() == 1 {
+    return encds[0].clone();
+  }
+  // every window is unit length but their combination is not, the metrics expect unit vectors
+  let pooled = match pooling {
+    PoolingStrategy::Mean | PoolingStrategy::Parts => mathematics::vec2d_axis_average::<f32>(encds,0),
+    PoolingStrategy::TokenWeighted => mathematics::vec2d_weighted_average::<f32>(encds, 
+      &windows.iter().map(|x| x.tokens as f32).collect::<Vec<f32>>()),
+    PoolingStrategy::OverlapCorrected => mathematics::vec2d_weighted_average::<f32>(encds, 
+      &windows.iter().enumerate().map(|(idx, x)| {
+        // half of each overlapped region belongs to each of the two windows sharing it
+        let next_overlap = windows.get(idx+1).map(|y| y.overlap).unwrap_or(0);
+        (x.tokens as f32 - 0.5 * x.overlap as f32 - 0.5 * next_overlap as f32).max(1.0)
+      }).collect::<Vec<f32>>()),
+    PoolingStrategy::Max => mathematics::vec2d_axis_max::<f32>(encds)
+  };
+  if pooled.iter().all(|x| *x == 0.0) {
+    return pooled;
+  }
+  mathematics::vec1d_normalize_mu3(&pooled)
+}
+
+// Transforms a Phrase of any Length to a Embedding Vector, each window placed in the template of the role
+// None if the phrase is too short to be understood
+pub fn transform_phrase(model: &str, role: &EncodingRole, phrase_of_law: &language::Phrase) -> Result<Option<Vec<f32>>, String> {
+  transform_phrase_with(model, &utils::config_template(model, role), phrase_of_law)
+}
+pub fn transform_phrase_with(model: &str, template: &str, phrase_of_law: &language::Phrase) -> Result<Option<Vec<f32>>, String> {
+  let windows = language::segment_phrase_windows(model, template, phrase_of_law);
+  if windows.is_empty() {return Ok(None);}
+  let texts: Vec<String> = windows.iter().map(|x| apply_template(template, &x.phrase.text)).collect::<Vec<String>>();
+  let encds = transform_sentences(model, &texts)?;
+  Ok(Some(pool_windows(&windows, &encds, &utils::config_pooling())))
+}
+// Same as transform_phrase_with, for the async handlers of the server
+pub async fn transform_phrase_async(model: &str, template: &str, phrase_of_law: &language::Phrase) -> Result<Option<Vec<f32>>, String> {
+  let windows = language::segment_phrase_windows(model, template, phrase_of_law);
+  if windows.is_empty() {return Ok(None);}
+  let texts: Vec<String> = windows.iter().map(|x| apply_template(template, &x.phrase.text)).collect::<Vec<String>>();
+  let encds = transform_sentences_async(model, &texts).await?;
+  Ok(Some(pool_windows(&windows, &encds, &utils::config_pooling())))
 }
 
-pub fn meaning_fabric(phrase_of_law: &language::Phrase, dembedding: &Option<Vec<f32>>, etype: EmbeddingType) -> Meaning {
+pub fn meaning_fabric(phrase_of_law: &language::Phrase, dembedding: &Option<Vec<f32>>, etype: EmbeddingType, pooling: PoolingStrategy, model: String, template: String, metric: DistanceMetric) -> Meaning {
   Meaning {
     phrase: phrase_of_law.clone(),
     embedding: Embedding {
-      etype: etype, 
-      vector: if dembedding.is_none() { transform_phrase(&phrase_of_law.clone()) } else { dembedding.clone() }
+      etype, 
+      pooling,
+      vector: if dembedding.is_none() {
+        transform_phrase_with(&model, &template, &phrase_of_law.clone()).unwrap_or_else(|_| panic!("{}", utils::error_message("E0007")))
+      } else { dembedding.clone() },
+      model,
+      template,
+      metric
     }
   }
-}
\ No newline at end of file
+}
+
+// Both backends are needed to compare them
+#[cfg(all(test, feature = "torch", feature = "onnx"))]
+mod tests {
+  use super::*;
+
+  // Needs the onnx export next to the torch model, fails on models the onnx backend refuses (Dense modules)
+  #[test]
+  fn onnx_embeddings_match_torch_within_tolerance() {
+    let sentences = utils::lines_from_file("resources/fixtures/parity.txt").unwrap()
+      .into_iter().filter(|x| !x.trim().is_empty()).collect::<Vec<String>>();
+    let deviations = backend_parity(&utils::config_model(), &sentences).unwrap();
+    let tolerance = utils::config_onnx_parity_tolerance();
+    for (sentence, deviation) in sentences.iter().zip(deviations.iter()) {
+      assert!(*deviation <= tolerance, "max deviation {} above {} : <{}>",deviation,tolerance,sentence);
+    }
+  }
+}
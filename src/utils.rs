@@ -9,6 +9,11 @@ use std::collections::HashMap;
 use std::str::FromStr;
 
 use crate::laws;
+use crate::calibration;
+use crate::quantization;
+use crate::search;
+use crate::transformer;
+use crate::store;
 
 // use std::time::Instant;
 // let now = Instant::now();
@@ -22,11 +27,12 @@ use crate::laws;
 
 // Returns the number expresion of a string "789waka123" -> 789123
 pub fn atoi<F: FromStr>(input: &str) -> Result<F, <F as FromStr>::Err> {
-  input.chars().filter(|c| c.is_digit(10)).collect::<String>().parse::<F>()
+  input.chars().filter(|c| c.is_ascii_digit()).collect::<String>().parse::<F>()
 }
 
 // Returns overlaping chunks f([5,4,3,2,1],4,2) -> [[5,4,3,2],[3,2,1]]
-pub fn overlaping_chunks<T: Clone>(input: &Vec<T>, chunk_size: usize, overlap_size: usize) -> Vec<Vec<T>> {
+#[allow(dead_code)]
+pub fn overlaping_chunks<T: Clone>(input: &[T], chunk_size: usize, overlap_size: usize) -> Vec<Vec<T>> {
   let mut ret : Vec<Vec<T>> = Vec::new();
   let mut partial : Vec<T> = Vec::new();
   let mut c_idx : usize = 0;
@@ -43,11 +49,29 @@ pub fn overlaping_chunks<T: Clone>(input: &Vec<T>, chunk_size: usize, overlap_si
   }
   ret.push(partial.clone());
   partial.clear();
-  return ret;
+  ret
+}
+
+// Returns the roman numeral of a number 14 -> "XIV", 0 -> "0"
+pub fn roman_numeral(input: u16) -> String {
+  let symbols = [(1000,"M"),(900,"CM"),(500,"D"),(400,"CD"),(100,"C"),(90,"XC"),
+    (50,"L"),(40,"XL"),(10,"X"),(9,"IX"),(5,"V"),(4,"IV"),(1,"I")];
+  if input == 0 {
+    return "0".to_string();
+  }
+  let mut rest = input;
+  let mut ret = String::new();
+  for (value, symbol) in symbols {
+    while rest >= value {
+      ret.push_str(symbol);
+      rest -= value;
+    }
+  }
+  ret
 }
 
 // Extracts a substring
-pub fn substring(text: &String, start: usize, end: usize) -> String {
+pub fn substring(text: &str, start: usize, end: usize) -> String {
   text[start..end].to_string()
 }
 // Reads a File, returns a Vec of all Lines
@@ -56,7 +80,7 @@ pub fn lines_from_file(filename: impl AsRef<Path>) -> io::Result<Vec<String>> {
 }
 // extracts the name of a file from a entry dir
 pub fn name_from_dir_entry(filepath: &DirEntry) -> String {
-  return filepath.file_name().to_str().unwrap().to_string();
+  filepath.file_name().to_str().unwrap().to_string()
 }
 // Reads a configuration file
 pub fn read_config_file(filepath: &str) -> HashMap<String, String> {
@@ -65,76 +89,148 @@ pub fn read_config_file(filepath: &str) -> HashMap<String, String> {
     .build()
     .unwrap()
     .try_deserialize::<HashMap<String, String>>()
-    .expect(format!("Unable to access file: {}",filepath).as_str())
+    .unwrap_or_else(|_| panic!("Unable to access file: {}",filepath))
 }
 
 // Defined Errors configuration
 pub fn tsahdu_errors() -> HashMap<String, String> {
-  return read_config_file("Errors");
+  read_config_file("Errors")
 }
 // Defined Errors messages
 pub fn error_message(code : &str) -> String {
-  return tsahdu_errors()[code].clone();
+  tsahdu_errors()[code].clone()
 }
 // Defined File of configuration
 pub fn tsahdu_config() -> HashMap<String, String> {
-  return read_config_file("Config");
+  read_config_file("Config")
 }
-// Get the configured Language 
+// Get the configured Language, the default one when a request does not tell
 pub fn config_language() -> String {
-  return tsahdu_config().get("language").expect(format!("Key not found in Config: language").as_str()).clone();
+  tsahdu_config().get("language").unwrap_or_else(|| panic!("{}", "Key not found in Config: language".to_string())).clone()
+}
+// Get the served Languages, a model is loaded for each one
+pub fn config_languages() -> Vec<String> {
+  tsahdu_config().get("languages").unwrap_or_else(|| panic!("{}", "Key not found in Config: languages".to_string()))
+    .split(",").map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect::<Vec<String>>()
 }
 // Get the configured path for models 
 pub fn config_models_path() -> String {
-  return tsahdu_config().get("models_path").expect(format!("Key not found in Config: models_path").as_str()).clone();
+  tsahdu_config().get("models_path").unwrap_or_else(|| panic!("{}", "Key not found in Config: models_path".to_string())).clone()
 }
 // Get the configured path for models 
 pub fn config_vocab_filename() -> String {
-  return tsahdu_config().get("vocab_filename").expect(format!("Key not found in Config: vocab_filename").as_str()).clone();
+  tsahdu_config().get("vocab_filename").unwrap_or_else(|| panic!("{}", "Key not found in Config: vocab_filename".to_string())).clone()
+}
+// Get the Transformer Model of a Language
+pub fn config_model_for_language(language: &str) -> String {
+  tsahdu_config().get(format!("{}_model",language).as_str()).unwrap_or_else(|| panic!("{}", error_message("E0000"))).clone()
+}
+// Get the Language served by a Transformer Model
+pub fn config_language_of_model(model: &str) -> Option<String> {
+  config_languages().into_iter().find(|x| config_model_for_language(x) == model)
 }
 // Get the configured Transformer Model
 pub fn config_model() -> String {
-  if config_language() == "es" {
-    return tsahdu_config().get("es_model").expect(format!("Key not found in Config: es_model").as_str()).clone();
-  } else if config_language() == "en" {
-    return tsahdu_config().get("en_model").expect(format!("Key not found in Config: en_model").as_str()).clone();
-  } else {
-    panic!("{}",error_message("E0000"));
-  }
+  config_model_for_language(config_language().as_str())
 }
 
 // Get the Transformer Model Path
+pub fn config_model_path_for(model: &str) -> String {
+  format!("{}{}/",config_models_path().as_str().to_owned(),model)
+}
+#[allow(dead_code)]
 pub fn config_model_path() -> String {
-  return format!("{}{}/",config_models_path().as_str().to_owned(),config_model().as_str().to_owned());
+  config_model_path_for(config_model().as_str())
+}
+
+// Get the configured embedding backend {torch, onnx}
+pub fn config_backend() -> String {
+  tsahdu_config().get("backend").unwrap_or_else(|| panic!("{}", "Key not found in Config: backend".to_string())).clone()
+}
+// Get the ONNX export of a Transformer Model
+pub fn config_onnx_model_file(model: &str) -> String {
+  format!("{}{}",config_model_path_for(model),tsahdu_config().get("onnx_model_filename").unwrap_or_else(|| panic!("{}", "Key not found in Config: onnx_model_filename".to_string())))
+}
+// Get the onnx_parity_tolerance
+pub fn config_onnx_parity_tolerance() -> f32 {
+  tsahdu_config().get("onnx_parity_tolerance").unwrap_or_else(|| panic!("{}", "Key not found in Config: onnx_parity_tolerance".to_string())).parse::<f32>().expect("wrong configuration, onnx_parity_tolerance must be a numeric string")
 }
 
-// Get the Transformer Vocab file
-pub fn config_vocab_file() -> String {
-  return format!("{}{}",config_model_path().as_str().to_owned(),config_vocab_filename().as_str().to_owned());
+// Get the Cross-Encoder Model Path
+#[cfg(feature = "torch")]
+pub fn config_cross_encoder_path() -> String {
+  format!("{}{}/",config_models_path(),tsahdu_config().get("cross_encoder_model").unwrap_or_else(|| panic!("{}", "Key not found in Config: cross_encoder_model".to_string())))
+}
+// Get the Cross-Encoder lowercase
+#[cfg(feature = "torch")]
+pub fn config_cross_encoder_lowercase() -> bool {
+  tsahdu_config().get("cross_encoder_lowercase").unwrap_or_else(|| panic!("{}", "Key not found in Config: cross_encoder_lowercase".to_string())).parse::<bool>().expect("wrong configuration, cross_encoder_lowercase must be true or false")
+}
+
+// Get the Vocab file of a Transformer Model
+pub fn config_vocab_file_for(model: &str) -> String {
+  format!("{}{}",config_model_path_for(model).as_str().to_owned(),config_vocab_filename().as_str().to_owned())
 }
 // Get the reference folder
 pub fn config_reference_folder() -> String {
-  return tsahdu_config().get("reference_folder").expect(format!("Key not found in Config: reference_folder").as_str()).clone();
+  tsahdu_config().get("reference_folder").unwrap_or_else(|| panic!("{}", "Key not found in Config: reference_folder".to_string())).clone()
+}
+// Get the store
+pub fn config_store() -> store::StoreKind {
+  let name = tsahdu_config().get("store").unwrap_or_else(|| panic!("{}", "Key not found in Config: store".to_string())).clone();
+  store::StoreKind::from_name(&name).unwrap_or_else(|| panic!("wrong configuration, unknown store: {}",name))
+}
+// Get the sqlite file
+pub fn config_sqlite_file() -> String {
+  tsahdu_config().get("sqlite_file").unwrap_or_else(|| panic!("{}", "Key not found in Config: sqlite_file".to_string())).clone()
+}
+// Get the quarantine folder
+pub fn config_quarantine_folder() -> String {
+  tsahdu_config().get("quarantine_folder").unwrap_or_else(|| panic!("{}", "Key not found in Config: quarantine_folder".to_string())).clone()
 }
 // Get the laws folder
 pub fn config_laws_folder() -> String {
-  return tsahdu_config().get("laws_folder").expect(format!("Key not found in Config: laws_folder").as_str()).clone();
+  tsahdu_config().get("laws_folder").unwrap_or_else(|| panic!("{}", "Key not found in Config: laws_folder".to_string())).clone()
 }
 // Get the embeddings extension
 pub fn config_embeddings_extension() -> String {
-  return tsahdu_config().get("embeddings_extension").expect(format!("Key not found in Config: embeddings_extension").as_str()).clone();
+  tsahdu_config().get("embeddings_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: embeddings_extension".to_string())).clone()
 }
 // Get the reference extension
 pub fn config_reference_extension() -> String {
-  return tsahdu_config().get("reference_extension").expect(format!("Key not found in Config: reference_extension").as_str()).clone();
+  tsahdu_config().get("reference_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: reference_extension".to_string())).clone()
 }
 // Get the law extension
 pub fn config_law_extension() -> String {
-  return tsahdu_config().get("laws_extension").expect(format!("Key not found in Config: laws_extension").as_str()).clone();
+  tsahdu_config().get("laws_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: laws_extension".to_string())).clone()
+}
+// Get the whitening extension
+pub fn config_whitening_extension() -> String {
+  tsahdu_config().get("whitening_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: whitening_extension".to_string())).clone()
+}
+// Get the snapshot extension
+pub fn config_snapshot_extension() -> String {
+  tsahdu_config().get("snapshot_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: snapshot_extension".to_string())).clone()
+}
+// Get the manifest extension
+pub fn config_manifest_extension() -> String {
+  tsahdu_config().get("manifest_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: manifest_extension".to_string())).clone()
+}
+// Get the journal extension
+pub fn config_journal_extension() -> String {
+  tsahdu_config().get("journal_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: journal_extension".to_string())).clone()
+}
+// Get the partial extension
+pub fn config_partial_extension() -> String {
+  tsahdu_config().get("partial_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: partial_extension".to_string())).clone()
+}
+// Get the lock extension
+pub fn config_lock_extension() -> String {
+  tsahdu_config().get("lock_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: lock_extension".to_string())).clone()
 }
 // Get the law configuration extension
 pub fn config_law_config_extension() -> String {
-  return tsahdu_config().get("laws_config_extension").expect(format!("Key not found in Config: laws_config_extension").as_str()).clone();
+  tsahdu_config().get("laws_config_extension").unwrap_or_else(|| panic!("{}", "Key not found in Config: laws_config_extension".to_string())).clone()
 }
 // Get the law configuration extension
 pub fn config_law(book: &laws::LawBook) -> HashMap<String,String> {
@@ -144,25 +240,169 @@ pub fn config_law(book: &laws::LawBook) -> HashMap<String,String> {
       book.pais,
       book.instrumento,
       config_law_config_extension());
-  return read_config_file(search_for.as_str());
+  read_config_file(search_for.as_str())
+}
+// Get the Transformer Model of a Book, from the language of its configuration (the configured one if not set)
+pub fn config_law_model(book: &laws::LawBook) -> String {
+  match config_law(book).get("language") {
+    Some(language) => config_model_for_language(language),
+    None => config_model()
+  }
+}
+// Get the configured distance metric
+pub fn config_metric() -> transformer::DistanceMetric {
+  transformer::DistanceMetric::from_name(tsahdu_config().get("metric").unwrap_or_else(|| panic!("{}", "Key not found in Config: metric".to_string()))).expect("wrong configuration, metric must be one of {cosine, dot, euclidean, manhattan, minkowski-p}")
+}
+// Get the distance metric of a Book, from its configuration (the configured one if not set)
+pub fn config_law_metric(book: &laws::LawBook) -> transformer::DistanceMetric {
+  match config_law(book).get("metric") {
+    Some(metric) => transformer::DistanceMetric::from_name(metric).unwrap_or_else(|| panic!("wrong law configuration, unknown metric: {}",metric)),
+    None => config_metric()
+  }
 }
 // Get the minimum_window_size
 pub fn config_minimum_window_size() -> String {
-  return tsahdu_config().get("minimum_window_size").expect(format!("Key not found in Config: minimum_window_size").as_str()).clone();
+  tsahdu_config().get("minimum_window_size").unwrap_or_else(|| panic!("{}", "Key not found in Config: minimum_window_size".to_string())).clone()
 }
-// Get the maximum_window_size
-pub fn config_maximum_window_size() -> String {
-  return tsahdu_config().get("maximum_window_size").expect(format!("Key not found in Config: maximum_window_size").as_str()).clone();
+// Get the maximum_window_tokens
+pub fn config_maximum_window_tokens() -> String {
+  tsahdu_config().get("maximum_window_tokens").unwrap_or_else(|| panic!("{}", "Key not found in Config: maximum_window_tokens".to_string())).clone()
 }
-// Get the window_retrocede
-pub fn config_window_retrocede() -> String {
-  return tsahdu_config().get("window_retrocede").expect(format!("Key not found in Config: window_retrocede").as_str()).clone();
+// Get the window_retrocede_tokens
+pub fn config_window_retrocede_tokens() -> String {
+  tsahdu_config().get("window_retrocede_tokens").unwrap_or_else(|| panic!("{}", "Key not found in Config: window_retrocede_tokens".to_string())).clone()
+}
+// Get the tokenizer_lowercase of a Language
+pub fn config_tokenizer_lowercase(language: &str) -> bool {
+  let key = format!("{}_tokenizer_lowercase",language);
+  tsahdu_config().get(key.as_str()).unwrap_or_else(|| panic!("Key not found in Config: {}",key)).parse::<bool>().unwrap_or_else(|_| panic!("wrong configuration, {} must be true or false",key))
 }
 // Get the return_count
 pub fn config_return_count() -> usize {
-  return atoi::<usize>(tsahdu_config().get("return_count").expect(format!("Key not found in Config: return_count").as_str())).expect("wrong configuration, return_count must be a numeric string");
+  atoi::<usize>(tsahdu_config().get("return_count").unwrap_or_else(|| panic!("{}", "Key not found in Config: return_count".to_string()))).expect("wrong configuration, return_count must be a numeric string")
 }
 // Get the return_min_value
 pub fn config_return_min_value() -> f32 {
-  return tsahdu_config().get("return_min_value").expect(format!("Key not found in Config: return_min_value").as_str()).parse::<f32>().expect("wrong configuration, return_min_value must be a numeric string");
-}
\ No newline at end of file
+  tsahdu_config().get("return_min_value").unwrap_or_else(|| panic!("{}", "Key not found in Config: return_min_value".to_string())).parse::<f32>().expect("wrong configuration, return_min_value must be a numeric string")
+}
+// Get the return_min_unit
+pub fn config_return_min_unit() -> calibration::ScoreUnit {
+  calibration::ScoreUnit::from_name(tsahdu_config().get("return_min_unit").unwrap_or_else(|| panic!("{}", "Key not found in Config: return_min_unit".to_string()))).expect("wrong configuration, return_min_unit must be one of {distance, zscore, percentile, confidence}")
+}
+// Get the group_level
+pub fn config_group_level() -> search::GroupLevel {
+  search::GroupLevel::from_name(tsahdu_config().get("group_level").unwrap_or_else(|| panic!("{}", "Key not found in Config: group_level".to_string()))).expect("wrong configuration, group_level must be one of {parte, articulo, capitulo}")
+}
+// Get the group_score
+pub fn config_group_score() -> search::ScoreAggregate {
+  search::ScoreAggregate::from_name(tsahdu_config().get("group_score").unwrap_or_else(|| panic!("{}", "Key not found in Config: group_score".to_string()))).expect("wrong configuration, group_score must be one of {max, mean}")
+}
+// Get the mmr_lambda
+pub fn config_mmr_lambda() -> f32 {
+  tsahdu_config().get("mmr_lambda").unwrap_or_else(|| panic!("{}", "Key not found in Config: mmr_lambda".to_string())).parse::<f32>().ok().filter(|x| (0.0..=1.0).contains(x)).expect("wrong configuration, mmr_lambda must be a number between 0 and 1")
+}
+// Get the mmr_candidates
+pub fn config_mmr_candidates() -> usize {
+  atoi::<usize>(tsahdu_config().get("mmr_candidates").unwrap_or_else(|| panic!("{}", "Key not found in Config: mmr_candidates".to_string()))).expect("wrong configuration, mmr_candidates must be a numeric string")
+}
+// Get the search_mode
+pub fn config_search_mode() -> search::SearchMode {
+  search::SearchMode::from_name(tsahdu_config().get("search_mode").unwrap_or_else(|| panic!("{}", "Key not found in Config: search_mode".to_string()))).expect("wrong configuration, search_mode must be one of {flat, hierarchical}")
+}
+// Get the search_subtrees
+pub fn config_search_subtrees() -> usize {
+  atoi::<usize>(tsahdu_config().get("search_subtrees").unwrap_or_else(|| panic!("{}", "Key not found in Config: search_subtrees".to_string()))).expect("wrong configuration, search_subtrees must be a numeric string")
+}
+// Get the explain
+pub fn config_explain() -> bool {
+  tsahdu_config().get("explain").unwrap_or_else(|| panic!("{}", "Key not found in Config: explain".to_string())).parse::<bool>().expect("wrong configuration, explain must be true or false")
+}
+// Get the explain_count
+pub fn config_explain_count() -> usize {
+  atoi::<usize>(tsahdu_config().get("explain_count").unwrap_or_else(|| panic!("{}", "Key not found in Config: explain_count".to_string()))).expect("wrong configuration, explain_count must be a numeric string")
+}
+// Get the rerank_count
+pub fn config_rerank_count() -> usize {
+  atoi::<usize>(tsahdu_config().get("rerank_count").unwrap_or_else(|| panic!("{}", "Key not found in Config: rerank_count".to_string()))).expect("wrong configuration, rerank_count must be a numeric string")
+}
+// Get the rerank_timeout_ms
+pub fn config_rerank_timeout() -> std::time::Duration {
+  std::time::Duration::from_millis(atoi::<u64>(tsahdu_config().get("rerank_timeout_ms").unwrap_or_else(|| panic!("{}", "Key not found in Config: rerank_timeout_ms".to_string()))).expect("wrong configuration, rerank_timeout_ms must be a numeric string"))
+}
+// Get the pooling
+pub fn config_pooling() -> transformer::PoolingStrategy {
+  transformer::PoolingStrategy::from_name(tsahdu_config().get("pooling").unwrap_or_else(|| panic!("{}", "Key not found in Config: pooling".to_string()))).expect("wrong configuration, pooling must be one of {mean, tokenweighted, overlapcorrected, max, parts}")
+}
+// Get the query or document template of a Transformer Model ({lang}_query_template, {lang}_document_template), "{}" if not set
+pub fn config_template(model: &str, role: &transformer::EncodingRole) -> String {
+  let language = match config_language_of_model(model) {
+    Some(language) => language,
+    None => return "{}".to_string()
+  };
+  let key = match role {
+    transformer::EncodingRole::Query => format!("{}_query_template",language),
+    transformer::EncodingRole::Document => format!("{}_document_template",language)
+  };
+  tsahdu_config().get(key.as_str()).cloned().unwrap_or("{}".to_string())
+}
+// Get the whitening
+pub fn config_whitening() -> bool {
+  tsahdu_config().get("whitening").unwrap_or_else(|| panic!("{}", "Key not found in Config: whitening".to_string())).parse::<bool>().expect("wrong configuration, whitening must be true or false")
+}
+// Get the whitening_components
+pub fn config_whitening_components() -> usize {
+  atoi::<usize>(tsahdu_config().get("whitening_components").unwrap_or_else(|| panic!("{}", "Key not found in Config: whitening_components".to_string()))).expect("wrong configuration, whitening_components must be a numeric string")
+}
+// Get the whitening_epsilon
+pub fn config_whitening_epsilon() -> f64 {
+  tsahdu_config().get("whitening_epsilon").unwrap_or_else(|| panic!("{}", "Key not found in Config: whitening_epsilon".to_string())).parse::<f64>().expect("wrong configuration, whitening_epsilon must be a number")
+}
+// Get the quantization
+pub fn config_quantization() -> quantization::Quantization {
+  quantization::Quantization::from_name(tsahdu_config().get("quantization").unwrap_or_else(|| panic!("{}", "Key not found in Config: quantization".to_string()))).expect("wrong configuration, quantization must be one of {none, int8, product}")
+}
+// Get the pq_subspaces
+pub fn config_pq_subspaces() -> usize {
+  atoi::<usize>(tsahdu_config().get("pq_subspaces").unwrap_or_else(|| panic!("{}", "Key not found in Config: pq_subspaces".to_string()))).expect("wrong configuration, pq_subspaces must be a numeric string")
+}
+// Get the pq_centroids
+pub fn config_pq_centroids() -> usize {
+  atoi::<usize>(tsahdu_config().get("pq_centroids").unwrap_or_else(|| panic!("{}", "Key not found in Config: pq_centroids".to_string()))).expect("wrong configuration, pq_centroids must be a numeric string")
+}
+// Get the pq_iterations
+pub fn config_pq_iterations() -> usize {
+  atoi::<usize>(tsahdu_config().get("pq_iterations").unwrap_or_else(|| panic!("{}", "Key not found in Config: pq_iterations".to_string()))).expect("wrong configuration, pq_iterations must be a numeric string")
+}
+// Get the rescore_candidates
+pub fn config_rescore_candidates() -> usize {
+  atoi::<usize>(tsahdu_config().get("rescore_candidates").unwrap_or_else(|| panic!("{}", "Key not found in Config: rescore_candidates".to_string()))).expect("wrong configuration, rescore_candidates must be a numeric string")
+}
+// Get the embedding_cache_capacity
+pub fn config_embedding_cache_capacity() -> usize {
+  atoi::<usize>(tsahdu_config().get("embedding_cache_capacity").unwrap_or_else(|| panic!("{}", "Key not found in Config: embedding_cache_capacity".to_string()))).expect("wrong configuration, embedding_cache_capacity must be a numeric string")
+}
+// Get the embedding_cache_disk
+pub fn config_embedding_cache_disk() -> bool {
+  tsahdu_config().get("embedding_cache_disk").unwrap_or_else(|| panic!("{}", "Key not found in Config: embedding_cache_disk".to_string())).parse::<bool>().expect("wrong configuration, embedding_cache_disk must be true or false")
+}
+// Get the embedding_cache_folder
+pub fn config_embedding_cache_folder() -> String {
+  tsahdu_config().get("embedding_cache_folder").unwrap_or_else(|| panic!("{}", "Key not found in Config: embedding_cache_folder".to_string())).clone()
+}
+// Get the batch_max_size
+pub fn config_batch_max_size() -> usize {
+  atoi::<usize>(tsahdu_config().get("batch_max_size").unwrap_or_else(|| panic!("{}", "Key not found in Config: batch_max_size".to_string()))).expect("wrong configuration, batch_max_size must be a numeric string")
+}
+// Get the batch_max_wait_ms
+pub fn config_batch_max_wait() -> std::time::Duration {
+  std::time::Duration::from_millis(atoi::<u64>(tsahdu_config().get("batch_max_wait_ms").unwrap_or_else(|| panic!("{}", "Key not found in Config: batch_max_wait_ms".to_string()))).expect("wrong configuration, batch_max_wait_ms must be a numeric string"))
+}
+// Get the reload_interval_s, None when the folders are not watched
+pub fn config_reload_interval() -> Option<std::time::Duration> {
+  let seconds = atoi::<u64>(tsahdu_config().get("reload_interval_s").unwrap_or_else(|| panic!("{}", "Key not found in Config: reload_interval_s".to_string()))).expect("wrong configuration, reload_interval_s must be a numeric string");
+  if seconds == 0 { None } else { Some(std::time::Duration::from_secs(seconds)) }
+}
+// Get the reload_history
+pub fn config_reload_history() -> usize {
+  atoi::<usize>(tsahdu_config().get("reload_history").unwrap_or_else(|| panic!("{}", "Key not found in Config: reload_history".to_string()))).expect("wrong configuration, reload_history must be a numeric string")
+}
@@ -0,0 +1,133 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::files;
+use crate::laws;
+use crate::mathematics;
+use crate::store;
+use crate::utils;
+
+// Mean centering plus PCA whitening of a Book, components are the principal directions
+// already scaled by 1/sqrt(eigenvalue + epsilon), largest eigenvalue first
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Whitening {
+  pub model: String,
+  pub mean: Vec<f32>,
+  pub components: Vec<Vec<f32>>
+}
+
+lazy_static! {
+  // None when the Book has no fitted transform, so the file is only looked for once
+  static ref WHITENINGS_MEMORY: Mutex<HashMap<laws::LawBook,Option<Arc<Whitening>>>> = Mutex::new(
+    HashMap::new()
+  );
+  // Read once, every loaded vector and every query asks for it
+  static ref WHITENING_ENABLED: bool = utils::config_whitening();
+}
+
+impl Whitening {
+  // Fits the transform on the (not whitened) vectors of a catalogue
+  pub fn fit(model: &str, vectors: &[Vec<f32>], components: usize, epsilon: f64) -> Whitening {
+    assert!(!vectors.is_empty(), "whitening requires at least one vector");
+    let vectors = vectors.iter().map(|x| x.iter().map(|&y| y as f64).collect::<Vec<f64>>()).collect::<Vec<Vec<f64>>>();
+    let n = vectors.len() as f64;
+    let mean = mathematics::transpose_vec2d::<f64>(vectors.clone()).iter()
+      .map(|x| x.iter().sum::<f64>() / n).collect::<Vec<f64>>();
+    let covariance = mathematics::vec2d_covariance(&vectors, &mean);
+    let (eigenvalues, eigenvectors) = mathematics::symmetric_eigen(&covariance);
+    Whitening {
+      model: model.to_string(),
+      mean: mean.iter().map(|&x| x as f32).collect(),
+      components: eigenvalues.iter().zip(eigenvectors.iter()).take(components.min(mean.len()))
+        .map(|(&x, v)| {
+          let scale = 1.0 / (x.max(0.0) + epsilon).sqrt();
+          v.iter().map(|&y| (y * scale) as f32).collect::<Vec<f32>>()
+        }).collect()
+    }
+  }
+  // Centers, projects on the components and normalizes a vector, distances stay euclidean
+  pub fn apply(&self, vector: &[f32]) -> Vec<f32> {
+    let centered = vector.iter().zip(self.mean.iter()).map(|(&x,&m)| x - m).collect::<Vec<f32>>();
+    let projected = self.components.iter()
+      .map(|x| mathematics::dot_product::<f32>(x, &centered)).collect::<Vec<f32>>();
+    mathematics::vec1d_normalize_mu3::<f32>(&projected)
+  }
+}
+
+fn format_row(row: &[f32]) -> String {
+  row.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join(" ")
+}
+fn parse_row(line: &str) -> Option<Vec<f32>> {
+  line.split_whitespace().map(|x| x.parse::<f32>().ok()).collect()
+}
+// model in the first line, the mean in the second, then one component per line
+pub fn write_whitening_file(book: &laws::LawBook, whitening: &Whitening) {
+  let mut lines = Vec::from([whitening.model.clone(), format_row(&whitening.mean)]);
+  lines.extend(whitening.components.iter().map(|x| format_row(x)));
+  files::write_atomic(&files::whitening_filename(book), lines.join("\n").as_bytes())
+    .unwrap_or_else(|_| panic!("{}: {}",utils::error_message("E0014"),files::whitening_filename(book)));
+}
+pub fn read_whitening_file(book: &laws::LawBook) -> Option<Whitening> {
+  let lines = utils::lines_from_file(files::whitening_filename(book)).ok()?;
+  if lines.len() < 3 {
+    return None;
+  }
+  Some(Whitening {
+    model: lines[0].clone(),
+    mean: parse_row(&lines[1])?,
+    components: lines[2..].iter().map(|x| parse_row(x)).collect::<Option<Vec<Vec<f32>>>>()?
+  })
+}
+
+// Stored vectors of a Book embedded by a model, read from the store since memory may hold them whitened
+pub fn book_vectors(book: &laws::LawBook, model: &str) -> Result<Vec<Vec<f32>>, String> {
+  Ok(store::store().get_book(book)?
+    .into_iter().filter(|x| x.model == model).map(|x| x.vector).filter(|x| !x.is_empty())
+    .collect())
+}
+// Fits the whitening of a Book on its stored vectors and persists it next to the catalogue,
+// catalogues left by a previous model are not in the space of the transform
+pub fn fit_book(book: &laws::LawBook) -> Result<Option<Whitening>, String> {
+  let model = utils::config_law_model(book);
+  let vectors = book_vectors(book, &model)?;
+  if vectors.is_empty() {
+    return Ok(None);
+  }
+  let whitening = Whitening::fit(
+    &model, 
+    &vectors, 
+    utils::config_whitening_components(), 
+    utils::config_whitening_epsilon());
+  write_whitening_file(book, &whitening);
+  forget_whitening(book);
+  Ok(Some(whitening))
+}
+
+// Transform of a Book if whitening is enabled and it was fitted, cached per Book
+pub fn book_whitening(book: &laws::LawBook) -> Option<Arc<Whitening>> {
+  if !*WHITENING_ENABLED {
+    return None;
+  }
+  if let Some(whitening) = WHITENINGS_MEMORY.lock().unwrap().get(book) {
+    return whitening.clone();
+  }
+  // the file is read without holding the memory, two first readers may both read it
+  let whitening = read_whitening_file(book).map(Arc::new);
+  if whitening.is_none() {
+    println!("[Warning]: whitening is enabled but <{}> was not fitted, vectors are kept as they are",files::whitening_filename(book));
+  }
+  WHITENINGS_MEMORY.lock().unwrap().insert(book.clone(), whitening.clone());
+  whitening
+}
+pub fn forget_whitening(book: &laws::LawBook) {
+  WHITENINGS_MEMORY.lock().unwrap().remove(book);
+}
+// Whitens a stored or query vector of a Book (of the same model), unchanged if the Book has no transform
+pub fn whiten(book: &laws::LawBook, model: &str, vector: &[f32]) -> Vec<f32> {
+  match book_whitening(book) {
+    Some(whitening) if whitening.model == model && whitening.mean.len() == vector.len() => whitening.apply(vector),
+    _ => vector.to_owned()
+  }
+}